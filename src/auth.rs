@@ -10,7 +10,7 @@ use tracing::{event, Level};
 
 use crate::state::AppState;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Claims {
     pub sub: String,
     pub aud: Value,
@@ -18,10 +18,17 @@ pub struct Claims {
     pub exp: usize,
     pub iat: usize,
     pub azp: String,
-    pub scope: String
+    pub scope: String,
+    // Only present when the tenant adds them to access tokens
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
-pub async fn authentication_middleware(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Result<Response, StatusCode>{
+pub async fn authentication_middleware(State(state): State<Arc<AppState>>, mut request: Request, next: Next) -> Result<Response, StatusCode>{
     // Get the Authorization header
     match request.headers().get("Authorization"){
         Some(auth_header) => {
@@ -59,9 +66,9 @@ pub async fn authentication_middleware(State(state): State<Arc<AppState>>, reque
                                             // Decode the token body
                                             match decode::<Claims>(token, &jwk.decoding_key, &validation){
                                                 Ok(token_data) => {
-                                                    match token_data.claims.aud {
+                                                    match &token_data.claims.aud {
                                                         Value::String(single_aud) => {
-                                                            if state.auth0_audience != single_aud{
+                                                            if state.auth0_audience != *single_aud{
                                                                 event!(Level::WARN, "Invalid audience: {}!", single_aud);
                                                                 return Err(StatusCode::UNAUTHORIZED);
                                                             }
@@ -72,7 +79,7 @@ pub async fn authentication_middleware(State(state): State<Arc<AppState>>, reque
                                                             for entry in multiple_aud{
                                                                 match entry {
                                                                     Value::String(s) => {
-                                                                        if state.auth0_audience == s{
+                                                                        if state.auth0_audience == *s{
                                                                             aud_found = true;
                                                                         }
                                                                     },
@@ -89,6 +96,9 @@ pub async fn authentication_middleware(State(state): State<Arc<AppState>>, reque
                                                     }
 
                                                     event!(Level::TRACE, "Auth middleware successful!");
+
+                                                    // Make the validated claims available to downstream middleware and handlers
+                                                    request.extensions_mut().insert(token_data.claims);
                                                     return Ok(next.run(request).await)
                                                 },
                                                 Err(e) => {
@@ -126,4 +136,27 @@ pub async fn authentication_middleware(State(state): State<Arc<AppState>>, reque
             return Err(StatusCode::UNAUTHORIZED);
         }
     }
+}
+
+pub fn has_scope(claims: &Claims, scope: &str) -> bool {
+    claims.scope.split_whitespace().any(|granted_scope| granted_scope == scope)
+}
+
+pub async fn admin_authorization_middleware(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Result<Response, StatusCode>{
+    // Relies on authentication_middleware having run first and stored the validated claims
+    match request.extensions().get::<Claims>() {
+        Some(claims) => {
+            if has_scope(claims, &state.auth0_admin_scope) {
+                event!(Level::TRACE, "Admin authorization successful!");
+                Ok(next.run(request).await)
+            } else {
+                event!(Level::WARN, "Subject {} is missing the {} scope!", claims.sub, state.auth0_admin_scope);
+                Err(StatusCode::FORBIDDEN)
+            }
+        },
+        None => {
+            event!(Level::WARN, "No claims found for admin authorization!");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
 }
\ No newline at end of file
@@ -1,25 +1,105 @@
-use std::sync::Arc;
+use std::{collections::{HashMap, HashSet}, fmt, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
 
+use base64::{prelude::BASE64_STANDARD, Engine};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tracing::{event, Level};
 
-use crate::{domain::{Payment, PaymentStatus}, dtos::{CreateCheckoutSessionResponseDto, EmptyResponse, LineItemRequestDto, Response}, paymentprocessors::{self, PaymentProcessor}};
+use crate::{domain::{default_checkout_mode, default_currency, to_decimal_amount, to_minor_units, CheckoutMode, Customer, Discount, DiscountType, Dispute, LineItem, Payment, PaymentSaga, PaymentSagaCompensation, PaymentSagaEvent, PaymentSagaStatus, PaymentSagaStep, PaymentStatus, Product, Promotion, PromotionRedemption, ReconciliationDiscrepancy, ReconciliationDiscrepancyKind, ReconciliationReport, Refund, Shipping, ShippingAddress, ShippingConfiguration, ShippingRate, Subscription, SubscriptionLineItem, SubscriptionStatus}, dtos::{CancelPaymentResponseDto, CreateCheckoutSessionResponseDto, CreatePromotionResponseDto, CreateSetupCheckoutSessionResponseDto, CreateSubscriptionResponseDto, DisputeEvidenceFileRequestDto, EmptyResponse, GetCheckoutSessionResponseDto, GetOrderPaymentResponseDto, LineItemDto, LineItemRequestDto, ListPaymentMethodsResponseDto, PaymentMethodResponseDto, PaymentProcessorCheckoutSessionResponseDto, PaymentProcessorDisputeEvidenceFileDto, PaymentProcessorDisputeResponseDto, PaymentProcessorInvoiceResponseDto, PaymentProcessorRecurringDto, PaymentProcessorSubscriptionResponseDto, PaymentProcessorTransactionResponseDto, PaymentSagaResponseDto, ReconcilePaymentsResponseDto, ReconciliationDiscrepancyDto, RefundLineItemRequestDto, RelayOutboxResponseDto, RefundPaymentResponseDto, Response, ShippingRateDto, SubmitDisputeEvidenceResponseDto, SubscriptionLineItemRequestDto, SubscriptionResponseDto, SweepStalePaymentsResponseDto}, events::{Event, MessageBroker}, paymentprocessors::{PaymentProcessor, PaymentProcessorRegistry}, taxcalculators::TaxCalculator, repositories::{CustomerRepository, PaymentRepository, PaymentSagaRepository, ProductRepository, PromotionRepository, ReconciliationReportRepository, SubscriptionRepository}};
 
 // traits
 pub trait Command{}
 pub trait Query{}
 
-pub trait CommandHandler<C: Command, R: Response>{
-    async fn handle(&self, input: &C) -> Result<R, String>;
+pub trait CommandHandler<C: Command, R: Response, E = String>{
+    async fn handle(&self, input: &C) -> Result<R, E>;
 }
 
-pub trait QueryHandler<Q: Query, R: Response>{
-    async fn handle(&self, input: Option<Q>) -> Result<R, String>;
+pub trait QueryHandler<Q: Query, R: Response, E = String>{
+    async fn handle(&self, input: Option<Q>) -> Result<R, E>;
+}
+
+// For handlers whose callers need to tell a bad request apart from a failure on our side, anything else is Internal
+#[derive(Debug)]
+pub enum HandlerError {
+    BadRequest(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    Internal(String),
+}
+
+impl From<String> for HandlerError {
+    fn from(e: String) -> Self {
+        HandlerError::Internal(e)
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::BadRequest(e) | HandlerError::Forbidden(e) | HandlerError::NotFound(e) | HandlerError::Conflict(e) | HandlerError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// The signed-in shopper a checkout is for, taken from their token rather than the request body
+#[derive(Clone, Default)]
+pub struct CustomerIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    // Whether the identity provider verified the email address, only a verified one can stand in for the subject
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+impl CustomerIdentity {
+    // Payments opened from an order event have no subject, they belong to whoever the order's email address is verified for
+    pub fn owns(&self, payment: &Payment) -> bool {
+        self.is_customer(&payment.customer_subject, &payment.customer_email)
+    }
+
+    // Subscriptions saved before their subject was recorded fall back to the email address the same way
+    pub fn owns_subscription(&self, subscription: &Subscription) -> bool {
+        self.is_customer(&subscription.customer_subject, &subscription.customer_email)
+    }
+
+    fn is_customer(&self, customer_subject: &str, customer_email: &str) -> bool {
+        if !customer_subject.is_empty() {
+            return customer_subject == self.subject;
+        }
+
+        match &self.email {
+            Some(email) if self.email_verified => !customer_email.is_empty() && customer_email.eq_ignore_ascii_case(email),
+            _ => false
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CreateCheckoutSessionCommand {
-    pub line_items: Vec<LineItemRequestDto>
+    pub line_items: Vec<LineItemRequestDto>,
+    #[serde(default)]
+    pub customer_email: Option<String>,
+    #[serde(default)]
+    pub order_id: Option<String>,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    // Overrides the processor otherwise chosen from the currency
+    #[serde(default)]
+    pub payment_processor: Option<String>,
+    // One of hosted, embedded or custom. Left out, an order's existing session is handed back in whatever mode it has and
+    // a new one uses custom checkout.
+    #[serde(default)]
+    pub checkout_mode: Option<String>,
+    #[serde(default)]
+    pub promotion_code: Option<String>,
+    // Where the order ships to, when known up front options limited to that country are offered too
+    #[serde(default)]
+    pub shipping_country: Option<String>,
+    // Checkouts without one, e.g. those pre-created from an OrderCreated event, stay anonymous with the processor
+    #[serde(skip)]
+    pub customer: Option<CustomerIdentity>,
 }
 impl Command for CreateCheckoutSessionCommand{}
 
@@ -28,80 +108,3031 @@ pub struct CreateProductPricingCommand {
     pub product_id: String,
     pub product_name: String,
     pub product_price: f32,
+    #[serde(default)]
+    pub currency: Option<String>,
+    // Only set for recurring products
+    #[serde(default)]
+    pub interval: Option<String>,
+    #[serde(default)]
+    pub interval_count: Option<u32>,
+    #[serde(default)]
+    pub tax_code: Option<String>,
+    #[serde(default)]
+    pub shippable: bool,
+    #[serde(default)]
+    pub weight_grams: u32,
 }
 impl Command for CreateProductPricingCommand{}
 
+#[derive(Serialize, Deserialize)]
+pub struct RefundPaymentCommand {
+    #[serde(default)]
+    pub payment_id: String,
+    // Read at full precision so an amount in whole cents is recognised as such
+    pub amount: Option<f64>,
+    pub line_items: Option<Vec<RefundLineItemRequestDto>>,
+    #[serde(default)]
+    pub reason: String,
+    // Retries of the same refund carry the same request id, so together with the payment it identifies the refund
+    #[serde(skip)]
+    pub request_id: String,
+}
+impl Command for RefundPaymentCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct CancelPaymentCommand {
+    pub payment_id: String,
+    // Only the payment's own customer or an admin may cancel it
+    #[serde(skip)]
+    pub customer: CustomerIdentity,
+    #[serde(skip)]
+    pub is_admin: bool,
+}
+impl Command for CancelPaymentCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct SweepStalePaymentsCommand {
+    pub older_than_seconds: u64,
+}
+impl Command for SweepStalePaymentsCommand{}
+
+// Events recorded more recently are left to the handler that recorded them, none at all are left when it is 0
+#[derive(Serialize, Deserialize)]
+pub struct RelayOutboxCommand {
+    pub older_than_seconds: u64,
+}
+impl Command for RelayOutboxCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReconcilePaymentsCommand {
+    pub created_from: u64,
+    pub created_to: u64,
+    // Reconciles against the default processor when not given
+    #[serde(default)]
+    pub payment_processor: Option<String>,
+}
+impl Command for ReconcilePaymentsCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct HandlePaymentProcessorWebhookCommand {
+    pub payment_processor: String,
+    pub payload: String,
+    pub headers: HashMap<String, String>,
+}
+impl Command for HandlePaymentProcessorWebhookCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateSubscriptionCommand {
+    pub line_items: Vec<SubscriptionLineItemRequestDto>,
+    #[serde(default)]
+    pub customer_email: Option<String>,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    #[serde(default)]
+    pub payment_processor: Option<String>,
+    #[serde(default)]
+    pub checkout_mode: Option<String>,
+    #[serde(skip)]
+    pub customer: Option<CustomerIdentity>,
+}
+impl Command for CreateSubscriptionCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct CancelSubscriptionCommand {
+    #[serde(default)]
+    pub subscription_id: String,
+    // Keeps the subscription running until the period already paid for ends
+    #[serde(default)]
+    pub at_period_end: bool,
+    // Only the subscription's own customer or an admin may cancel, pause or resume it
+    #[serde(skip)]
+    pub customer: CustomerIdentity,
+    #[serde(skip)]
+    pub is_admin: bool,
+}
+impl Command for CancelSubscriptionCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct PauseSubscriptionCommand {
+    pub subscription_id: String,
+    #[serde(skip)]
+    pub customer: CustomerIdentity,
+    #[serde(skip)]
+    pub is_admin: bool,
+}
+impl Command for PauseSubscriptionCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResumeSubscriptionCommand {
+    pub subscription_id: String,
+    #[serde(skip)]
+    pub customer: CustomerIdentity,
+    #[serde(skip)]
+    pub is_admin: bool,
+}
+impl Command for ResumeSubscriptionCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreatePromotionCommand {
+    pub code: String,
+    // PercentOff or AmountOff
+    pub discount_type: String,
+    #[serde(default)]
+    pub percent_off: f32,
+    #[serde(default)]
+    pub amount_off: f32,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    #[serde(default)]
+    pub minimum_order_amount: f32,
+    #[serde(default)]
+    pub max_redemptions: u32,
+    #[serde(default)]
+    pub max_redemptions_per_customer: u32,
+    #[serde(default)]
+    pub expires_at: u64,
+}
+impl Command for CreatePromotionCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateSetupCheckoutSessionCommand {
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    #[serde(default)]
+    pub checkout_mode: Option<String>,
+    #[serde(skip)]
+    pub customer: Option<CustomerIdentity>,
+}
+impl Command for CreateSetupCheckoutSessionCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubmitDisputeEvidenceCommand {
+    #[serde(default)]
+    pub dispute_id: String,
+    // Text evidence by the payment processor's evidence field, e.g. product_description or uncategorized_text
+    #[serde(default)]
+    pub evidence: HashMap<String, String>,
+    #[serde(default)]
+    pub files: Vec<DisputeEvidenceFileRequestDto>,
+    // Drafts are kept with the dispute so more evidence can be added before it goes for review
+    #[serde(default)]
+    pub draft: bool,
+}
+impl Command for SubmitDisputeEvidenceCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct DetachPaymentMethodCommand {
+    pub subject: String,
+    pub payment_method_id: String,
+}
+impl Command for DetachPaymentMethodCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListPaymentMethodsQuery {
+    pub subject: String,
+}
+impl Query for ListPaymentMethodsQuery{}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetCheckoutSessionQuery {
+    pub session_id: String,
+    // Only the payment's own customer or an admin may read its session
+    #[serde(skip)]
+    pub customer: CustomerIdentity,
+    #[serde(skip)]
+    pub is_admin: bool,
+}
+impl Query for GetCheckoutSessionQuery{}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetOrderPaymentQuery {
+    pub order_id: String,
+    // Only the order's own customer or an admin may read its payment
+    #[serde(skip)]
+    pub customer: CustomerIdentity,
+    #[serde(skip)]
+    pub is_admin: bool,
+}
+impl Query for GetOrderPaymentQuery{}
+
+// Raised internally by the event consumers and by the handlers that move an order's payment along
+pub struct AdvancePaymentSagaCommand {
+    pub order_id: String,
+    pub payment_id: Option<String>,
+    pub event: PaymentSagaEvent,
+    pub detail: String,
+}
+impl Command for AdvancePaymentSagaCommand{}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetPaymentSagaQuery {
+    pub order_id: String,
+}
+impl Query for GetPaymentSagaQuery{}
+
+// Finds or creates the shopper's customer with the payment processor, keeping its email and name in step with their
+// identity, and returns the payment processor's customer id. A failure only costs the shopper their saved details, so
+// checkout carries on anonymously rather than failing.
+async fn ensure_customer(customer_repository: &Arc<dyn CustomerRepository + Send + Sync>, payment_processor_name: &str, payment_processor: &Arc<dyn PaymentProcessor + Send + Sync>, identity: &CustomerIdentity, fallback_email: Option<&String>) -> String {
+    let email = identity.email.as_ref().or(fallback_email).cloned().unwrap_or_default();
+    let name = identity.name.clone().unwrap_or_default();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    let stored_customer = match customer_repository.read(&identity.subject, payment_processor_name).await {
+        Ok(stored_customer) => stored_customer,
+        Err(e) => {
+            event!(Level::WARN, "Continuing without a customer for {}: {}", identity.subject, e);
+            return String::new();
+        }
+    };
+
+    let customer = match stored_customer {
+        // An identity that no longer has an email or name keeps what the processor already knows
+        Some(customer) if (email.is_empty() || customer.email == email) && (name.is_empty() || customer.name == name) => return customer.payment_processor_customer_id,
+        Some(mut customer) => {
+            if !email.is_empty() { customer.email = email; }
+            if !name.is_empty() { customer.name = name; }
+            customer.updated_at = now;
+            customer
+        },
+        None => Customer {
+            subject: identity.subject.clone(),
+            payment_processor: String::from(payment_processor_name),
+            payment_processor_customer_id: String::new(),
+            email,
+            name,
+            created_at: now,
+            updated_at: now,
+        }
+    };
+
+    let is_new = customer.payment_processor_customer_id.is_empty();
+    let customer = match payment_processor.ensure_customer(customer).await {
+        Ok(customer) => customer,
+        Err(e) => {
+            event!(Level::WARN, "Continuing without a customer for {}: {}", identity.subject, e);
+            return String::new();
+        }
+    };
+
+    let saved = if is_new {
+        match customer_repository.create(&customer).await {
+            Ok(true) => Ok(()),
+            // A concurrent checkout created the customer first, that one is kept so the shopper has a single customer
+            Ok(false) => {
+                return match customer_repository.read(&identity.subject, payment_processor_name).await {
+                    Ok(Some(stored_customer)) => stored_customer.payment_processor_customer_id,
+                    _ => customer.payment_processor_customer_id
+                };
+            },
+            Err(e) => Err(e)
+        }
+    } else {
+        customer_repository.update(&customer).await
+    };
+
+    if let Err(e) = saved {
+        event!(Level::WARN, "Error occurred when saving Customer for {}: {}", identity.subject, e);
+    }
+
+    customer.payment_processor_customer_id
+}
+
 pub struct CreateCheckoutSessionCommandHandler {
-    payment_processor: Arc<dyn PaymentProcessor + Send + Sync>
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    customer_repository: Arc<dyn CustomerRepository + Send + Sync>,
+    promotion_repository: Arc<dyn PromotionRepository + Send + Sync>,
+    product_repository: Arc<dyn ProductRepository + Send + Sync>,
+    tax_calculator: Arc<dyn TaxCalculator + Send + Sync>,
+    shipping_configuration: ShippingConfiguration,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    // How long to wait for the inventory service to reserve stock, reservations are skipped when not set
+    inventory_reservation_timeout: Option<Duration>,
+    advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>,
+}
+
+// Everything opening a checkout session draws on, from pricing and tax to stock and the order's saga
+pub struct CreateCheckoutSessionDependencies {
+    pub payment_processors: Arc<PaymentProcessorRegistry>,
+    pub payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    pub customer_repository: Arc<dyn CustomerRepository + Send + Sync>,
+    pub promotion_repository: Arc<dyn PromotionRepository + Send + Sync>,
+    pub product_repository: Arc<dyn ProductRepository + Send + Sync>,
+    pub tax_calculator: Arc<dyn TaxCalculator + Send + Sync>,
+    pub shipping_configuration: ShippingConfiguration,
+    pub message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    pub inventory_reservation_timeout: Option<Duration>,
+    pub advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>,
 }
 
 impl CreateCheckoutSessionCommandHandler {
-    pub fn new(payment_processor: Arc<dyn PaymentProcessor + Send + Sync>) -> Self {
+    pub fn new(dependencies: CreateCheckoutSessionDependencies) -> Self {
         CreateCheckoutSessionCommandHandler { 
-            payment_processor: payment_processor 
+            payment_processors: dependencies.payment_processors,
+            payment_repository: dependencies.payment_repository,
+            customer_repository: dependencies.customer_repository,
+            promotion_repository: dependencies.promotion_repository,
+            product_repository: dependencies.product_repository,
+            tax_calculator: dependencies.tax_calculator,
+            shipping_configuration: dependencies.shipping_configuration,
+            message_broker: dependencies.message_broker,
+            inventory_reservation_timeout: dependencies.inventory_reservation_timeout,
+            advance_payment_saga_command_handler: dependencies.advance_payment_saga_command_handler,
         }
     }
-}
 
-impl CommandHandler<CreateCheckoutSessionCommand, CreateCheckoutSessionResponseDto> for CreateCheckoutSessionCommandHandler {
-    async fn handle(&self, input: &CreateCheckoutSessionCommand) -> Result<CreateCheckoutSessionResponseDto, String> {
-        let payment = Payment {
-            id: uuid::Uuid::new_v4().to_string(),
-            line_items: Vec::new(),
-            status: PaymentStatus::NEW.to_string(),
-            payment_processor: String::new(),
-            payment_processor_checkout_session_id: String::new(),
-            payment_processor_checkout_session_url: String::new(),
-            payment_processor_id: String::new(),
-            payment_processor_status: String::new(),
+    // Asks the inventory service to hold the payment's line items, so checkout is never opened for stock that is not
+    // there. The reservation is named after the payment.
+    async fn reserve_inventory(&self, payment: &mut Payment) -> Result<(), HandlerError> {
+        let timeout = match self.inventory_reservation_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(())
+        };
+
+        let reservation_requested_event = Event::InventoryReservationRequestedEvent {
+            reservation_id: payment.id.clone(),
+            order_id: payment.order_id.clone(),
+            line_items: payment.line_items.iter().map(LineItemDto::from).collect(),
+        };
+
+        match self.message_broker.request(&reservation_requested_event, timeout).await {
+            Ok(Event::InventoryReservationRepliedEvent { reservation_id, reserved: true, .. }) if reservation_id == payment.id => {
+                payment.inventory_reservation_id = reservation_id;
+                advance_payment_saga(&self.advance_payment_saga_command_handler, payment, PaymentSagaEvent::INVENTORY_RESERVED, String::new()).await;
+                Ok(())
+            },
+            Ok(Event::InventoryReservationRepliedEvent { reservation_id, reserved: false, unavailable_product_ids }) if reservation_id == payment.id => {
+                event!(Level::WARN, "Insufficient stock to reserve for Payment {}: {:?}", payment.id, unavailable_product_ids);
+                advance_payment_saga(&self.advance_payment_saga_command_handler, payment, PaymentSagaEvent::INVENTORY_UNAVAILABLE, format!("Insufficient stock for products {:?}", unavailable_product_ids)).await;
+                Err(HandlerError::Conflict(format!("Insufficient stock for products {:?}", unavailable_product_ids)))
+            },
+            Ok(_) => {
+                event!(Level::WARN, "Unexpected reply to inventory reservation for Payment {}", payment.id);
+                Err(HandlerError::Internal(format!("Unexpected reply to inventory reservation for Payment {}", payment.id)))
+            },
+            Err(e) => {
+                // The inventory service may still reserve the stock after we stop waiting, so it is told to let it go
+                self.release_inventory(&payment.id, &payment.order_id).await;
+                event!(Level::WARN, "Error occurred when reserving inventory for Payment {}: {}", payment.id, e);
+                Err(HandlerError::Internal(format!("Error occurred when reserving inventory for Payment {}: {}", payment.id, e)))
+            }
+        }
+    }
+
+    async fn release_inventory(&self, reservation_id: &str, order_id: &str) {
+        let reservation_released_event = Event::InventoryReservationReleasedEvent {
+            reservation_id: String::from(reservation_id),
+            order_id: String::from(order_id),
         };
 
-        match self.payment_processor.as_ref().create_checkout_session(payment).await {
-            Ok(payment_with_session_info) => {
-                Ok(CreateCheckoutSessionResponseDto {
-                    payment_id: payment_with_session_info.id,
-                    checkout_session_id: payment_with_session_info.payment_processor_checkout_session_id,
-                    checkout_session_url: payment_with_session_info.payment_processor_checkout_session_url,
-                })
+        if let Err(e) = self.message_broker.publish_message(&reservation_released_event).await {
+            event!(Level::WARN, "Error occurred when releasing inventory reservation {}: {}", reservation_id, e);
+        }
+    }
+
+    async fn open_checkout_session(&self, payment_processor: &Arc<dyn PaymentProcessor + Send + Sync>, mut payment: Payment) -> Result<Payment, String> {
+        if let Err(e) = self.tax_calculator.calculate_tax(&mut payment).await {
+            event!(Level::WARN, "Error occurred when calculating tax for Payment {}: {}", payment.id, e);
+            return Err(format!("Error occurred when calculating tax for Payment {}: {}", payment.id, e));
+        }
+
+        match payment_processor.create_checkout_session(payment).await {
+            Ok(mut payment_with_session_info) => {
+                payment_with_session_info.status = PaymentStatus::SESSION_CREATED.to_string();
+
+                if let Err(e) = self.payment_repository.create(&payment_with_session_info).await {
+                    event!(Level::WARN, "Error occurred when saving Payment {}: {}", payment_with_session_info.id, e);
+                    return Err(format!("Error occurred when saving Payment {}: {}", payment_with_session_info.id, e));
+                }
+
+                Ok(payment_with_session_info)
             },
             Err(e) => {
                 event!(Level::WARN, "Error occurred when creating checkout session: {}", e);
-                return Err(format!("Error occurred when creating checkout session: {}", e));
+                Err(format!("Error occurred when creating checkout session: {}", e))
+            }
+        }
+    }
+
+    // Prices the configured shipping options for the cart, the customer picks one of them at checkout
+    fn quote_shipping(&self, shipping_country: Option<&str>, order_amount: i64, weight_grams: u32) -> Result<Shipping, HandlerError> {
+        let allowed_countries: Vec<String> = match shipping_country {
+            Some(country) if self.shipping_configuration.allowed_countries.iter().any(|allowed_country| allowed_country.eq_ignore_ascii_case(country)) => vec![country.to_uppercase()],
+            Some(country) => {
+                event!(Level::WARN, "Orders cannot be shipped to {}", country);
+                return Err(HandlerError::BadRequest(format!("Orders cannot be shipped to {}", country)));
+            },
+            None => self.shipping_configuration.allowed_countries.iter().map(|country| country.to_uppercase()).collect()
+        };
+
+        let offered_rates: Vec<ShippingRate> = self.shipping_configuration.options.iter()
+            .filter(|option| option.ships_to(shipping_country))
+            .map(|option| ShippingRate {
+                shipping_option_id: option.id.clone(),
+                display_name: option.display_name.clone(),
+                amount: option.amount(order_amount, weight_grams),
+            })
+            .collect();
+
+        if allowed_countries.is_empty() || offered_rates.is_empty() {
+            event!(Level::WARN, "No shipping options are available for {}", shipping_country.unwrap_or("any country"));
+            return Err(HandlerError::BadRequest(format!("No shipping options are available for {}", shipping_country.unwrap_or("any country"))));
+        }
+
+        Ok(Shipping {
+            allowed_countries,
+            offered_rates,
+            selected_rate: None,
+            address: None,
+        })
+    }
+
+    // Checks the promotion can be used on this checkout and works out its discount, its redemption limits are only
+    // enforced once the redemption is claimed
+    async fn redeem_promotion(&self, promotion_code: &str, currency: &str, subtotal: i64, redeemed_by: String) -> Result<(Promotion, Discount), HandlerError> {
+        let promotion = match self.promotion_repository.read(promotion_code).await? {
+            Some(promotion) if promotion.active => promotion,
+            _ => {
+                event!(Level::WARN, "Promotion code {} is not valid", promotion_code);
+                return Err(HandlerError::BadRequest(format!("Promotion code {} is not valid", promotion_code)));
+            }
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        if promotion.expires_at != 0 && now >= promotion.expires_at {
+            event!(Level::WARN, "Promotion code {} has expired", promotion_code);
+            return Err(HandlerError::BadRequest(format!("Promotion code {} has expired", promotion_code)));
+        }
+
+        if promotion.discount_type == DiscountType::AMOUNT_OFF.to_string() && !promotion.currency.eq_ignore_ascii_case(currency) {
+            event!(Level::WARN, "Promotion code {} only applies to {} checkouts", promotion_code, promotion.currency);
+            return Err(HandlerError::BadRequest(format!("Promotion code {} only applies to {} checkouts", promotion_code, promotion.currency)));
+        }
+
+        if subtotal < promotion.minimum_order_amount {
+            event!(Level::WARN, "Promotion code {} requires an order of at least {}", promotion_code, to_decimal_amount(promotion.minimum_order_amount));
+            return Err(HandlerError::BadRequest(format!("Promotion code {} requires an order of at least {}", promotion_code, to_decimal_amount(promotion.minimum_order_amount))));
+        }
+
+        if promotion.max_redemptions_per_customer > 0 && redeemed_by.is_empty() {
+            event!(Level::WARN, "Promotion code {} requires a signed-in shopper or customer email", promotion_code);
+            return Err(HandlerError::BadRequest(format!("Promotion code {} requires a signed-in shopper or customer email", promotion_code)));
+        }
+
+        let discount = Discount {
+            promotion_code: String::from(promotion_code),
+            amount: promotion.discount_amount(subtotal),
+            redeemed_by,
+            payment_processor_coupon_id: String::new(),
+        };
+
+        Ok((promotion, discount))
+    }
+
+    // Takes one of the promotion's limited redemptions for the payment, checked against the limits in the same update so
+    // concurrent checkouts cannot go past them. A payment being replaced hands its redemption over instead. Returns None
+    // for promotions without limits.
+    async fn claim_promotion(&self, promotion: &Promotion, discount: &Discount, payment_id: &str, replaced_payment: Option<&Payment>) -> Result<Option<PromotionClaim>, HandlerError> {
+        if !promotion.has_redemption_limit() {
+            return Ok(None);
+        }
+
+        let mut claim = PromotionClaim {
+            promotion_code: promotion.code.clone(),
+            payment_id: String::from(payment_id),
+            transferred_from: None,
+        };
+
+        let replaced_payment_id = replaced_payment.filter(|payment| payment.discount.as_ref().is_some_and(|replaced_discount| replaced_discount.promotion_code == promotion.code)).map(|payment| payment.id.clone());
+        if let Some(replaced_payment_id) = replaced_payment_id {
+            if self.promotion_repository.transfer_redemption(&promotion.code, &replaced_payment_id, payment_id).await? {
+                claim.transferred_from = Some(replaced_payment_id);
+                return Ok(Some(claim));
+            }
+        }
+
+        let redemption = PromotionRedemption {
+            payment_id: String::from(payment_id),
+            redeemed_by: discount.redeemed_by.clone(),
+            redeemed_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+
+        let mut claimed = self.promotion_repository.add_redemption(&promotion.code, &redemption, promotion.max_redemptions, promotion.max_redemptions_per_customer).await?;
+        // Redemptions of checkouts that expired or were cancelled since are given back before giving up
+        if !claimed && self.release_lapsed_redemptions(&promotion.code).await? {
+            claimed = self.promotion_repository.add_redemption(&promotion.code, &redemption, promotion.max_redemptions, promotion.max_redemptions_per_customer).await?;
+        }
+        if claimed {
+            return Ok(Some(claim));
+        }
+
+        let customer_redemptions = promotion.redemptions.iter().filter(|redemption| redemption.redeemed_by == discount.redeemed_by).count();
+        if promotion.max_redemptions_per_customer > 0 && customer_redemptions >= promotion.max_redemptions_per_customer as usize {
+            event!(Level::WARN, "Promotion code {} was already redeemed by {}", promotion.code, discount.redeemed_by);
+            return Err(HandlerError::Conflict(format!("Promotion code {} has already been redeemed the maximum number of times", promotion.code)));
+        }
+
+        event!(Level::WARN, "Promotion code {} has been fully redeemed", promotion.code);
+        Err(HandlerError::Conflict(format!("Promotion code {} has been fully redeemed", promotion.code)))
+    }
+
+    // Gives back the redemptions of payments that expired or were cancelled, and of checkouts that never saved their
+    // payment. Returns whether any were given back.
+    async fn release_lapsed_redemptions(&self, promotion_code: &str) -> Result<bool, String> {
+        let promotion = match self.promotion_repository.read(promotion_code).await? {
+            Some(promotion) => promotion,
+            None => return Ok(false)
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let inactive_statuses = [PaymentStatus::EXPIRED.to_string(), PaymentStatus::CANCELLED.to_string()];
+        let mut lapsed_payment_ids = Vec::new();
+        for redemption in &promotion.redemptions {
+            let lapsed = match self.payment_repository.read(&redemption.payment_id).await? {
+                Some(payment) => inactive_statuses.contains(&payment.status),
+                None => now.saturating_sub(redemption.redeemed_at) > UNSAVED_REDEMPTION_SECONDS
+            };
+            if lapsed {
+                lapsed_payment_ids.push(redemption.payment_id.clone());
+            }
+        }
+
+        if lapsed_payment_ids.is_empty() {
+            return Ok(false);
+        }
+
+        self.promotion_repository.remove_redemptions(promotion_code, &lapsed_payment_ids).await?;
+        Ok(true)
+    }
+
+    // Gives the redemption back when the checkout it was claimed for did not go through
+    async fn release_promotion(&self, claim: &PromotionClaim) {
+        let released = match &claim.transferred_from {
+            Some(replaced_payment_id) => self.promotion_repository.transfer_redemption(&claim.promotion_code, &claim.payment_id, replaced_payment_id).await.map(|_| ()),
+            None => self.promotion_repository.remove_redemptions(&claim.promotion_code, std::slice::from_ref(&claim.payment_id)).await
+        };
+
+        if let Err(e) = released {
+            event!(Level::WARN, "Error occurred when releasing redemption of Promotion {} for Payment {}: {}", claim.promotion_code, claim.payment_id, e);
+        }
+    }
+}
+
+// A redemption taken for a checkout, given back if the checkout fails
+struct PromotionClaim {
+    promotion_code: String,
+    payment_id: String,
+    // The replaced payment that handed its redemption over, it gets it back
+    transferred_from: Option<String>,
+}
+
+// A redemption whose payment is still not saved after this long belongs to a checkout that failed without giving it back
+const UNSAVED_REDEMPTION_SECONDS: u64 = 3600;
+
+impl CreateCheckoutSessionCommandHandler {
+    fn to_response(payment: Payment) -> CreateCheckoutSessionResponseDto {
+        let tax_amount = if payment.automatic_tax { None } else { Some(payment.tax_total()).filter(|tax_amount| *tax_amount > 0).map(to_decimal_amount) };
+
+        CreateCheckoutSessionResponseDto {
+            payment_id: payment.id,
+            checkout_mode: payment.checkout_mode,
+            checkout_session_id: payment.payment_processor_checkout_session_id,
+            checkout_session_url: if payment.payment_processor_checkout_session_url.is_empty() { None } else { Some(payment.payment_processor_checkout_session_url) },
+            client_secret: if payment.payment_processor_checkout_session_client_secret.is_empty() { None } else { Some(payment.payment_processor_checkout_session_client_secret) },
+            promotion_code: payment.discount.as_ref().map(|discount| discount.promotion_code.clone()),
+            discount_amount: payment.discount.as_ref().map(|discount| to_decimal_amount(discount.amount)),
+            tax_amount,
+            shipping_rates: payment.shipping.map(|shipping| shipping.offered_rates.iter().map(ShippingRateDto::from).collect()).unwrap_or_default(),
+        }
+    }
+}
+
+// Problems with the request or with what it asks for, like stock or a promotion running out, come back as client errors
+// so a client knows whether retrying can help
+impl CommandHandler<CreateCheckoutSessionCommand, CreateCheckoutSessionResponseDto, HandlerError> for CreateCheckoutSessionCommandHandler {
+    async fn handle(&self, input: &CreateCheckoutSessionCommand) -> Result<CreateCheckoutSessionResponseDto, HandlerError> {
+        let checkout_mode = input.checkout_mode.clone().unwrap_or(default_checkout_mode());
+        let checkout_modes = [CheckoutMode::HOSTED.to_string(), CheckoutMode::EMBEDDED.to_string(), CheckoutMode::CUSTOM.to_string()];
+        if !checkout_modes.contains(&checkout_mode) {
+            event!(Level::WARN, "Checkout mode {} is not one of {:?}", checkout_mode, checkout_modes);
+            return Err(HandlerError::BadRequest(format!("Checkout mode {} is not one of {:?}", checkout_mode, checkout_modes)));
+        }
+
+        let promotion_code = input.promotion_code.as_ref().map(|code| code.trim().to_uppercase()).filter(|code| !code.is_empty());
+
+        // An order only ever has one active payment, asking again while it is awaiting checkout hands back the same session
+        // so a redelivered OrderCreated event or a repeated checkout does not open a second one
+        let mut replaced_payment = None;
+        if let Some(order_id) = &input.order_id {
+            if let Some(active_payment) = self.payment_repository.read_active_by_order_id(order_id).await? {
+                // A shopper checking out an order cannot take over or cancel the payment of somebody else's order
+                if input.customer.as_ref().is_some_and(|identity| !identity.owns(&active_payment)) {
+                    event!(Level::WARN, "Order {} already has Payment {} of another customer", order_id, active_payment.id);
+                    return Err(HandlerError::Forbidden(format!("Order {} already has Payment {} of another customer", order_id, active_payment.id)));
+                }
+
+                if active_payment.status != PaymentStatus::NEW.to_string() && active_payment.status != PaymentStatus::SESSION_CREATED.to_string() {
+                    event!(Level::WARN, "Order {} already has Payment {} which is {}", order_id, active_payment.id, active_payment.status);
+                    return Err(HandlerError::Conflict(format!("Order {} already has Payment {} which is {}", order_id, active_payment.id, active_payment.status)));
+                }
+
+                let same_checkout_mode = input.checkout_mode.is_none() || active_payment.checkout_mode == checkout_mode;
+                let same_promotion = promotion_code.is_none() || active_payment.discount.as_ref().map(|discount| &discount.promotion_code) == promotion_code.as_ref();
+                if same_checkout_mode && same_promotion {
+                    return Ok(Self::to_response(active_payment));
+                }
+
+                replaced_payment = Some(active_payment);
+            }
+        }
+
+        // Prices and tax codes come from the catalog as announced by ProductCreated events, never from the request
+        let products = self.product_repository.read_by_ids(input.line_items.iter().map(|line_item| line_item.product_id.clone()).collect()).await?;
+        let mut line_items: Vec<LineItem> = Vec::new();
+        for line_item in &input.line_items {
+            let product = match products.iter().find(|product| product.id == line_item.product_id) {
+                Some(product) if product.price > 0 => product,
+                _ => {
+                    event!(Level::WARN, "Product {} is not for sale", line_item.product_id);
+                    return Err(HandlerError::BadRequest(format!("Product {} is not for sale", line_item.product_id)));
+                }
+            };
+
+            if !product.currency.eq_ignore_ascii_case(&input.currency) {
+                event!(Level::WARN, "Product {} is priced in {} and cannot be bought in {}", product.id, product.currency, input.currency);
+                return Err(HandlerError::BadRequest(format!("Product {} is priced in {} and cannot be bought in {}", product.id, product.currency, input.currency)));
+            }
+
+            if line_item.quantity == 0 {
+                event!(Level::WARN, "Product {} needs a quantity of at least 1", line_item.product_id);
+                return Err(HandlerError::BadRequest(format!("Product {} needs a quantity of at least 1", line_item.product_id)));
+            }
+
+            line_items.push(LineItem {
+                product_id: product.id.clone(),
+                quantity: line_item.quantity,
+                price: product.price,
+                tax_code: product.tax_code.clone(),
+                tax_amount: 0,
+            });
+        }
+        let subtotal: i64 = line_items.iter().map(|line_item| line_item.price * line_item.quantity as i64).sum();
+
+        let (payment_processor_name, payment_processor) = self.payment_processors.select(input.payment_processor.as_deref(), &input.currency).map_err(HandlerError::BadRequest)?;
+
+        let payment_processor_customer_id = match &input.customer {
+            Some(identity) => ensure_customer(&self.customer_repository, &payment_processor_name, &payment_processor, identity, input.customer_email.as_ref()).await,
+            None => String::new()
+        };
+
+        let (promotion, discount) = match &promotion_code {
+            Some(promotion_code) => {
+                let redeemed_by = input.customer.as_ref().map(|identity| identity.subject.clone()).or(input.customer_email.clone()).unwrap_or_default();
+                let (promotion, discount) = self.redeem_promotion(promotion_code, &input.currency, subtotal, redeemed_by).await?;
+                (Some(promotion), Some(discount))
+            },
+            None => (None, None)
+        };
+
+        // Only carts with physical goods collect a shipping address
+        let shippable_products: Vec<&Product> = products.iter().filter(|product| product.shippable).collect();
+        let shipping = if shippable_products.is_empty() {
+            None
+        } else {
+            let weight_grams = line_items.iter()
+                .filter_map(|line_item| shippable_products.iter().find(|product| product.id == line_item.product_id).map(|product| product.weight_grams * line_item.quantity))
+                .sum();
+            let order_amount = subtotal - discount.as_ref().map(|discount| discount.amount).unwrap_or(0);
+            Some(self.quote_shipping(input.shipping_country.as_deref(), order_amount, weight_grams)?)
+        };
+
+        let payment_id = uuid::Uuid::new_v4().to_string();
+        let promotion_claim = match (&promotion, &discount) {
+            (Some(promotion), Some(discount)) => self.claim_promotion(promotion, discount, &payment_id, replaced_payment.as_ref()).await?,
+            _ => None
+        };
+
+        let checkout = async {
+            // A session's mode and amount cannot be changed, so the unpaid session is cancelled and replaced by one in the mode
+            // and with the promotion asked for, e.g. when an email link asks for hosted checkout of an order pre-created for
+            // the web app
+            if let Some(active_payment) = replaced_payment {
+                let session_expired = !active_payment.payment_processor_checkout_session_id.is_empty();
+                if session_expired {
+                    self.payment_processors.get(&active_payment.payment_processor)?.expire_checkout_session(active_payment.payment_processor_checkout_session_id.clone()).await?;
+                }
+
+                let active_payment = save_payment(&self.payment_repository, active_payment, |active_payment| {
+                    if active_payment.status != PaymentStatus::NEW.to_string() && active_payment.status != PaymentStatus::SESSION_CREATED.to_string() {
+                        return Err(format!("Payment {} became {} while being replaced", active_payment.id, active_payment.status));
+                    }
+                    if session_expired {
+                        active_payment.payment_processor_status = String::from("expired");
+                    }
+                    active_payment.status = PaymentStatus::CANCELLED.to_string();
+                    record_payment_status_events(active_payment, &PaymentStatus::CANCELLED);
+                    Ok(true)
+                }).await?;
+                relay_outbox(&self.payment_repository, &self.message_broker, &active_payment).await;
+            }
+
+            let mut payment = Payment {
+                id: payment_id.clone(),
+                order_id: input.order_id.clone().unwrap_or_default(),
+                line_items,
+                currency: input.currency.to_lowercase(),
+                checkout_mode,
+                status: PaymentStatus::NEW.to_string(),
+                payment_processor: payment_processor_name,
+                payment_processor_checkout_session_id: String::new(),
+                payment_processor_checkout_session_url: String::new(),
+                payment_processor_checkout_session_client_secret: String::new(),
+                payment_processor_checkout_session_expires_at: 0,
+                payment_processor_id: String::new(),
+                payment_processor_status: String::new(),
+                payment_processor_customer_id,
+                customer_subject: input.customer.as_ref().map(|identity| identity.subject.clone()).unwrap_or_default(),
+                customer_email: input.customer_email.clone().unwrap_or_default(),
+                discount,
+                automatic_tax: false,
+                shipping,
+                inventory_reservation_id: String::new(),
+                refunds: Vec::new(),
+                disputes: Vec::new(),
+                outbox: Vec::new(),
+                version: 0,
+                created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                paid_at: 0,
+            };
+
+            self.reserve_inventory(&mut payment).await?;
+
+            let inventory_reservation_id = payment.inventory_reservation_id.clone();
+            let order_id = payment.order_id.clone();
+            match self.open_checkout_session(&payment_processor, payment).await {
+                Ok(payment) => Ok::<_, HandlerError>(Self::to_response(payment)),
+                Err(e) => {
+                    // Nothing can be paid for without a saved session, so the stock goes straight back
+                    if !inventory_reservation_id.is_empty() {
+                        self.release_inventory(&inventory_reservation_id, &order_id).await;
+                    }
+                    Err(HandlerError::Internal(e))
+                }
+            }
+        }.await;
+
+        // The promotion's redemption goes back when the checkout fails after claiming it
+        if checkout.is_err() {
+            if let Some(promotion_claim) = &promotion_claim {
+                self.release_promotion(promotion_claim).await;
             }
         }
+
+        checkout
     }
 }
 
 pub struct CreateProductPricingCommandHandler {
-    payment_processor: Arc<dyn PaymentProcessor + Send + Sync>,
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    product_repository: Arc<dyn ProductRepository + Send + Sync>,
+    // Prices products whose event does not name a currency
+    default_currency: String,
 }
 
 impl CreateProductPricingCommandHandler {
-    pub fn new(payment_processor: Arc<dyn PaymentProcessor + Send + Sync>) -> Self {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, product_repository: Arc<dyn ProductRepository + Send + Sync>, default_currency: String) -> Self {
         CreateProductPricingCommandHandler { 
-            payment_processor: payment_processor,
+            payment_processors,
+            product_repository,
+            default_currency: default_currency.to_lowercase(),
         }
     }
 }
 
 impl CommandHandler<CreateProductPricingCommand, EmptyResponse> for CreateProductPricingCommandHandler {
     async fn handle(&self, input: &CreateProductPricingCommand) -> Result<EmptyResponse, String> {
-        match self.payment_processor.create_product(input.product_id.clone(), input.product_name.clone()).await {
-            Ok(()) => {
-                match self.payment_processor.create_product_pricing(input.product_id.clone(), String::from("usd"), input.product_price as i32).await {
-                    Ok(()) => {
-                        Ok(EmptyResponse {})
-                    },
-                    Err(e) => {
-                        event!(Level::WARN, "Error occurred when creating Pricing in payment processor: {}", e);
-                        return Err(format!("Error occurred when creating Pricing in payment processor: {}", e));
-                    }
-                }
-            },
-            Err(e) => {
-                event!(Level::WARN, "Error occurred when creating Product in payment processor: {}", e);
-                return Err(format!("Error occurred when creating Product in payment processor: {}", e));
+        let currency = input.currency.as_ref().map(|currency| currency.to_lowercase()).unwrap_or(self.default_currency.clone());
+
+        // Prices are kept in the currency's minor unit, which has to fit an i64 for amounts to stay exact
+        let unit_amount = (input.product_price as f64 * 100.0).round();
+        if !unit_amount.is_finite() || unit_amount < 0.0 || unit_amount > i64::MAX as f64 {
+            event!(Level::WARN, "Product {} has an invalid price of {}", input.product_id, input.product_price);
+            return Err(format!("Product {} has an invalid price of {}", input.product_id, input.product_price));
+        }
+        let unit_amount = unit_amount as i64;
+
+        let product = Product {
+            id: input.product_id.clone(),
+            name: input.product_name.clone(),
+            price: unit_amount,
+            currency: currency.clone(),
+            interval: input.interval.clone(),
+            interval_count: input.interval.as_ref().map(|_| input.interval_count.unwrap_or(1)),
+            tax_code: input.tax_code.clone().unwrap_or_default(),
+            shippable: input.shippable,
+            weight_grams: input.weight_grams,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+        self.product_repository.upsert(&product).await?;
+
+        // Every configured processor keeps its own catalog, so a product has to exist in all of them
+        for payment_processor_name in self.payment_processors.names() {
+            let payment_processor = self.payment_processors.get(&payment_processor_name)?;
+
+            if let Err(e) = payment_processor.create_product(input.product_id.clone(), input.product_name.clone(), input.tax_code.clone()).await {
+                event!(Level::WARN, "Error occurred when creating Product in payment processor {}: {}", payment_processor_name, e);
+                return Err(format!("Error occurred when creating Product in payment processor {}: {}", payment_processor_name, e));
+            }
+
+            let recurring = input.interval.as_ref().map(|interval| PaymentProcessorRecurringDto {
+                interval: interval.clone(),
+                interval_count: input.interval_count.unwrap_or(1),
+            });
+
+            if let Err(e) = payment_processor.create_product_pricing(input.product_id.clone(), currency.clone(), unit_amount, recurring).await {
+                event!(Level::WARN, "Error occurred when creating Pricing in payment processor {}: {}", payment_processor_name, e);
+                return Err(format!("Error occurred when creating Pricing in payment processor {}: {}", payment_processor_name, e));
             }
         }
+
+        Ok(EmptyResponse {})
+    }
+}
+
+pub struct RefundPaymentCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+}
+
+impl RefundPaymentCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, payment_repository: Arc<dyn PaymentRepository + Send + Sync>, message_broker: Arc<dyn MessageBroker + Send + Sync>) -> Self {
+        RefundPaymentCommandHandler {
+            payment_processors,
+            payment_repository,
+            message_broker,
+        }
+    }
+
+    // The requested amount in cents, None unless it is a positive amount in whole cents as anything finer would be rounded
+    // into a refund other than the one asked for
+    fn refund_amount(amount: f64) -> Option<i64> {
+        let amount_in_cents = amount * 100.0;
+        if !amount.is_finite() || amount <= 0.0 || (amount_in_cents - amount_in_cents.round()).abs() > 1e-6 {
+            return None;
+        }
+        Some(amount_in_cents.round() as i64)
     }
-}
\ No newline at end of file
+
+    // Resolves the refunded line items against what was purchased, so the same unit can never be refunded twice
+    fn refund_line_items(payment: &Payment, requested_line_items: &[RefundLineItemRequestDto]) -> Result<Vec<LineItem>, String> {
+        let mut line_items = Vec::new();
+
+        for requested_line_item in requested_line_items {
+            let purchased_line_item = match payment.line_items.iter().find(|line_item| line_item.product_id == requested_line_item.product_id) {
+                Some(line_item) => line_item,
+                None => return Err(format!("Product {} is not part of Payment {}", requested_line_item.product_id, payment.id))
+            };
+
+            let refundable_quantity = purchased_line_item.quantity.saturating_sub(payment.refunded_quantity(&purchased_line_item.product_id));
+            if requested_line_item.quantity == 0 || requested_line_item.quantity > refundable_quantity {
+                return Err(format!("Cannot refund {} of product {}, {} remain refundable", requested_line_item.quantity, requested_line_item.product_id, refundable_quantity));
+            }
+
+            line_items.push(LineItem {
+                product_id: purchased_line_item.product_id.clone(),
+                quantity: requested_line_item.quantity,
+                price: purchased_line_item.price,
+                tax_code: purchased_line_item.tax_code.clone(),
+                // The tax charged on the line is refunded with its units
+                tax_amount: (purchased_line_item.tax_amount as f64 * requested_line_item.quantity as f64 / purchased_line_item.quantity as f64).round() as i64,
+            });
+        }
+
+        Ok(line_items)
+    }
+}
+
+impl CommandHandler<RefundPaymentCommand, RefundPaymentResponseDto, HandlerError> for RefundPaymentCommandHandler {
+    async fn handle(&self, input: &RefundPaymentCommand) -> Result<RefundPaymentResponseDto, HandlerError> {
+        if input.request_id.is_empty() {
+            event!(Level::WARN, "Refund for Payment {} has no request id", input.payment_id);
+            return Err(HandlerError::BadRequest(format!("Refund for Payment {} has no request id", input.payment_id)));
+        }
+
+        let payment = match self.payment_repository.read(&input.payment_id).await? {
+            Some(payment) => payment,
+            None => {
+                event!(Level::WARN, "Payment {} not found", input.payment_id);
+                return Err(HandlerError::NotFound(format!("Payment {} not found", input.payment_id)));
+            }
+        };
+
+        // The processor is sent the refund id as its idempotency key, so a retry after the refund was created but not
+        // saved gets the same refund back instead of a second one
+        let refund_id = format!("{:x}", Sha256::digest(format!("{}:{}", payment.id, input.request_id)));
+        if let Some(refund) = payment.refunds.iter().find(|refund| refund.id == refund_id) {
+            event!(Level::INFO, "Refund {} for Payment {} was already made", refund.id, payment.id);
+            return Ok(RefundPaymentResponseDto {
+                payment_id: payment.id.clone(),
+                refund_id: refund.id.clone(),
+                amount: to_decimal_amount(refund.amount),
+                payment_status: payment.status.clone(),
+            });
+        }
+
+        if payment.status != PaymentStatus::SUCCEEDED.to_string() && payment.status != PaymentStatus::PARTIALLY_REFUNDED.to_string() {
+            event!(Level::WARN, "Payment {} cannot be refunded while {}", payment.id, payment.status);
+            return Err(HandlerError::Conflict(format!("Payment {} cannot be refunded while {}", payment.id, payment.status)));
+        }
+
+        if payment.payment_processor_id.is_empty() {
+            event!(Level::WARN, "Payment {} has no payment processor id to refund against", payment.id);
+            return Err(HandlerError::Conflict(format!("Payment {} has no payment processor id to refund against", payment.id)));
+        }
+
+        let refundable_amount = payment.total() - payment.refunded_total();
+
+        // An explicit amount wins over line items, and no amount or line items at all means refund whatever is left
+        let (amount, line_items) = match (input.amount, &input.line_items) {
+            (Some(amount), _) => match Self::refund_amount(amount) {
+                Some(amount) => (amount, Vec::new()),
+                None => {
+                    event!(Level::WARN, "Cannot refund {} for Payment {}, refunds are positive amounts in whole cents", amount, payment.id);
+                    return Err(HandlerError::BadRequest(format!("Cannot refund {} for Payment {}, refunds are positive amounts in whole cents", amount, payment.id)));
+                }
+            },
+            (None, Some(requested_line_items)) => {
+                let line_items = Self::refund_line_items(&payment, requested_line_items).map_err(HandlerError::BadRequest)?;
+                let line_items_amount: i64 = line_items.iter().map(|line_item| line_item.price * line_item.quantity as i64).sum();
+                let line_items_tax: i64 = line_items.iter().map(|line_item| line_item.tax_amount).sum();
+                // A discounted payment refunds its line items at the share of the discount they were charged with
+                let subtotal = payment.subtotal();
+                let charged_amount = if subtotal > 0 { (line_items_amount as f64 * (subtotal - payment.discount_total()).max(0) as f64 / subtotal as f64).round() as i64 } else { 0 };
+                (charged_amount + line_items_tax, line_items)
+            },
+            (None, None) => (refundable_amount, Vec::new())
+        };
+
+        if amount <= 0 || amount > refundable_amount {
+            event!(Level::WARN, "Cannot refund {} for Payment {}, {} remains refundable", to_decimal_amount(amount), payment.id, to_decimal_amount(refundable_amount));
+            return Err(HandlerError::BadRequest(format!("Cannot refund {} for Payment {}, {} remains refundable", to_decimal_amount(amount), payment.id, to_decimal_amount(refundable_amount))));
+        }
+
+        let refund = Refund {
+            id: refund_id,
+            amount,
+            line_items,
+            reason: input.reason.clone(),
+            payment_processor_refund_id: String::new(),
+            payment_processor_status: String::new(),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+
+        let refund = match self.payment_processors.get(&payment.payment_processor)?.create_refund(&payment, refund).await {
+            Ok(refund) => refund,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when creating refund for Payment {}: {}", payment.id, e);
+                return Err(HandlerError::Internal(format!("Error occurred when creating refund for Payment {}: {}", payment.id, e)));
+            }
+        };
+
+        let mut fully_refunded = false;
+        let payment = match save_payment(&self.payment_repository, payment, |payment| {
+            if payment.refunds.iter().any(|saved_refund| saved_refund.id == refund.id) {
+                return Ok(false);
+            }
+
+            payment.refunds.push(refund.clone());
+            fully_refunded = payment.refunded_total() >= payment.total();
+            payment.status = if fully_refunded {
+                PaymentStatus::REFUNDED.to_string()
+            } else {
+                PaymentStatus::PARTIALLY_REFUNDED.to_string()
+            };
+            payment.record_event(Event::PaymentRefundedEvent {
+                payment_id: payment.id.clone(),
+                order_id: payment.order_id.clone(),
+                refund_id: refund.id.clone(),
+                amount: to_decimal_amount(refund.amount),
+                line_items: refund.line_items.iter().map(LineItemDto::from).collect(),
+                fully_refunded,
+            });
+            Ok(true)
+        }).await {
+            Ok(payment) => payment,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when saving refund {} for Payment {}: {}", refund.id, input.payment_id, e);
+                return Err(HandlerError::Internal(format!("Error occurred when saving refund {} for Payment {}: {}", refund.id, input.payment_id, e)));
+            }
+        };
+
+        relay_outbox(&self.payment_repository, &self.message_broker, &payment).await;
+
+        Ok(RefundPaymentResponseDto {
+            payment_id: payment.id,
+            refund_id: refund.id,
+            amount: to_decimal_amount(refund.amount),
+            payment_status: payment.status,
+        })
+    }
+}
+
+pub struct CancelPaymentCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>,
+}
+
+impl CancelPaymentCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, payment_repository: Arc<dyn PaymentRepository + Send + Sync>, message_broker: Arc<dyn MessageBroker + Send + Sync>, advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>) -> Self {
+        CancelPaymentCommandHandler {
+            payment_processors,
+            payment_repository,
+            message_broker,
+            advance_payment_saga_command_handler,
+        }
+    }
+}
+
+impl CommandHandler<CancelPaymentCommand, CancelPaymentResponseDto, HandlerError> for CancelPaymentCommandHandler {
+    async fn handle(&self, input: &CancelPaymentCommand) -> Result<CancelPaymentResponseDto, HandlerError> {
+        let payment = match self.payment_repository.read(&input.payment_id).await? {
+            Some(payment) => payment,
+            None => {
+                event!(Level::WARN, "Payment {} not found", input.payment_id);
+                return Err(HandlerError::NotFound(format!("Payment {} not found", input.payment_id)));
+            }
+        };
+
+        if !input.is_admin && !input.customer.owns(&payment) {
+            event!(Level::WARN, "Subject {} cannot cancel Payment {} of another customer", input.customer.subject, payment.id);
+            return Err(HandlerError::Forbidden(format!("Payment {} belongs to another customer", payment.id)));
+        }
+
+        if payment.status != PaymentStatus::NEW.to_string() && payment.status != PaymentStatus::SESSION_CREATED.to_string() {
+            event!(Level::WARN, "Payment {} cannot be cancelled while {}", payment.id, payment.status);
+            return Err(HandlerError::Conflict(format!("Payment {} cannot be cancelled while {}", payment.id, payment.status)));
+        }
+
+        let session_expired = !payment.payment_processor_checkout_session_id.is_empty();
+        if session_expired {
+            if let Err(e) = self.payment_processors.get(&payment.payment_processor)?.expire_checkout_session(payment.payment_processor_checkout_session_id.clone()).await {
+                event!(Level::WARN, "Error occurred when expiring checkout session for Payment {}: {}", payment.id, e);
+                return Err(HandlerError::Internal(format!("Error occurred when expiring checkout session for Payment {}: {}", payment.id, e)));
+            }
+        }
+
+        let payment = match save_payment(&self.payment_repository, payment, |payment| {
+            if payment.status != PaymentStatus::NEW.to_string() && payment.status != PaymentStatus::SESSION_CREATED.to_string() {
+                return Err(format!("Payment {} became {} while being cancelled", payment.id, payment.status));
+            }
+            if session_expired {
+                payment.payment_processor_status = String::from("expired");
+            }
+            payment.status = PaymentStatus::CANCELLED.to_string();
+            record_payment_status_events(payment, &PaymentStatus::CANCELLED);
+            Ok(true)
+        }).await {
+            Ok(payment) => payment,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when cancelling Payment {}: {}", input.payment_id, e);
+                return Err(HandlerError::Internal(format!("Error occurred when cancelling Payment {}: {}", input.payment_id, e)));
+            }
+        };
+
+        relay_outbox(&self.payment_repository, &self.message_broker, &payment).await;
+        advance_payment_saga_for_status(&self.advance_payment_saga_command_handler, &payment, &PaymentStatus::CANCELLED).await;
+
+        Ok(CancelPaymentResponseDto {
+            payment_id: payment.id,
+            payment_status: payment.status,
+        })
+    }
+}
+
+const PAYMENT_SAVE_ATTEMPTS: u32 = 5;
+
+// Saves a change to a payment without overwriting one another writer saved since it was read: when the payment moved on,
+// it is read again and the change applied on top. The change returns false when there is nothing to save, or an error
+// when it no longer applies. Anything done outside the payment, like calling the processor, has to happen beforehand so
+// it is never repeated.
+async fn save_payment<F>(payment_repository: &Arc<dyn PaymentRepository + Send + Sync>, mut payment: Payment, mut apply: F) -> Result<Payment, String>
+where F: FnMut(&mut Payment) -> Result<bool, String> {
+    for _ in 0..PAYMENT_SAVE_ATTEMPTS {
+        if !apply(&mut payment)? {
+            return Ok(payment);
+        }
+
+        let expected_version = payment.version;
+        payment.version += 1;
+        if payment_repository.update(&payment, expected_version).await? {
+            return Ok(payment);
+        }
+
+        event!(Level::DEBUG, "Payment {} changed while saving, retrying", payment.id);
+        payment = match payment_repository.read(&payment.id).await? {
+            Some(payment) => payment,
+            None => return Err(format!("Payment {} not found", payment.id))
+        };
+    }
+
+    event!(Level::WARN, "Payment {} kept changing while saving", payment.id);
+    Err(format!("Payment {} kept changing while saving", payment.id))
+}
+
+// Brings a stored Payment in line with the checkout session reported by the payment processor, which is the source
+// of truth. Returns the new status when the Payment transitioned.
+fn apply_checkout_session(payment: &mut Payment, checkout_session: &PaymentProcessorCheckoutSessionResponseDto) -> Option<PaymentStatus> {
+    let session_status = checkout_session.status.clone().unwrap_or(String::from("open"));
+
+    payment.payment_processor_status = session_status.clone();
+    if let Some(payment_intent) = &checkout_session.payment_intent {
+        payment.payment_processor_id = payment_intent.clone();
+    }
+    if let Some(email) = checkout_session.customer_details.as_ref().and_then(|customer_details| customer_details.email.clone()) {
+        payment.customer_email = email;
+    }
+    if let (true, Some(session_line_items)) = (payment.automatic_tax, &checkout_session.line_items) {
+        for line_item in payment.line_items.iter_mut() {
+            line_item.tax_amount = session_line_items.iter()
+                .filter(|session_line_item| session_line_item.price.as_ref().is_some_and(|price| price.product == line_item.product_id))
+                .map(|session_line_item| session_line_item.amount_tax)
+                .sum();
+        }
+    }
+
+    if let Some(shipping) = payment.shipping.as_mut() {
+        if let Some(shipping_cost) = &checkout_session.shipping_cost {
+            let shipping_option_id = shipping_cost.shipping_rate.as_ref()
+                .and_then(|shipping_rate| shipping_rate.pointer("/metadata/shipping_option_id"))
+                .and_then(|shipping_option_id| shipping_option_id.as_str());
+            if let Some(offered_rate) = shipping.offered_rates.iter().find(|offered_rate| Some(offered_rate.shipping_option_id.as_str()) == shipping_option_id) {
+                shipping.selected_rate = Some(ShippingRate {
+                    amount: shipping_cost.amount_total,
+                    ..offered_rate.clone()
+                });
+            }
+        }
+
+        let shipping_details = checkout_session.collected_information.as_ref()
+            .and_then(|collected_information| collected_information.shipping_details.as_ref())
+            .or(checkout_session.shipping_details.as_ref());
+        if let Some(shipping_details) = shipping_details {
+            let address = shipping_details.address.as_ref();
+            shipping.address = Some(ShippingAddress {
+                name: shipping_details.name.clone().unwrap_or_default(),
+                line1: address.and_then(|address| address.line1.clone()).unwrap_or_default(),
+                line2: address.and_then(|address| address.line2.clone()).unwrap_or_default(),
+                city: address.and_then(|address| address.city.clone()).unwrap_or_default(),
+                state: address.and_then(|address| address.state.clone()).unwrap_or_default(),
+                postal_code: address.and_then(|address| address.postal_code.clone()).unwrap_or_default(),
+                country: address.and_then(|address| address.country.clone()).unwrap_or_default(),
+            });
+        }
+    }
+
+    payment.reconcile_checkout_session(&session_status, &checkout_session.payment_status)
+}
+
+// Payments still awaiting checkout are completed with the processor, which is where processors that need to be told to
+// take the money capture it. Anything else is only read so a cancelled or refunded payment is never charged.
+async fn fetch_checkout_session(payment_processors: &Arc<PaymentProcessorRegistry>, payment: &Payment) -> Result<PaymentProcessorCheckoutSessionResponseDto, String> {
+    let payment_processor = payment_processors.get(&payment.payment_processor)?;
+    let session_id = payment.payment_processor_checkout_session_id.clone();
+
+    if payment.status == PaymentStatus::NEW.to_string() || payment.status == PaymentStatus::SESSION_CREATED.to_string() {
+        payment_processor.complete_checkout_session(session_id).await
+    } else {
+        payment_processor.get_checkout_session(session_id).await
+    }
+}
+
+// Brings a payment awaiting checkout in line with its checkout session, a payment that never got a checkout session
+// cannot be paid for anymore and expires. It is only saved when it transitions and not at all when another writer already
+// moved it on, after which the events recorded with the transition are published and the order's saga is told. Returns
+// the new status along with the payment when it transitioned.
+async fn save_checkout_session_outcome(payment_repository: &Arc<dyn PaymentRepository + Send + Sync>, message_broker: &Arc<dyn MessageBroker + Send + Sync>, advance_payment_saga_command_handler: &Arc<AdvancePaymentSagaCommandHandler>, payment: Payment, checkout_session: Option<&PaymentProcessorCheckoutSessionResponseDto>) -> Result<(Payment, Option<PaymentStatus>), String> {
+    let mut new_status = None;
+    let payment = save_payment(payment_repository, payment, |payment| {
+        new_status = match checkout_session {
+            Some(checkout_session) => apply_checkout_session(payment, checkout_session),
+            None if payment.status == PaymentStatus::NEW.to_string() || payment.status == PaymentStatus::SESSION_CREATED.to_string() => {
+                payment.status = PaymentStatus::EXPIRED.to_string();
+                Some(PaymentStatus::EXPIRED)
+            },
+            None => None
+        };
+        if let Some(status) = &new_status {
+            record_payment_status_events(payment, status);
+        }
+        Ok(new_status.is_some())
+    }).await?;
+
+    if let Some(status) = &new_status {
+        event!(Level::INFO, "Payment {} moved to {} by its checkout session", payment.id, payment.status);
+        relay_outbox(payment_repository, message_broker, &payment).await;
+        advance_payment_saga_for_status(advance_payment_saga_command_handler, &payment, status).await;
+    }
+
+    Ok((payment, new_status))
+}
+
+// Puts the events announcing the payment's new status in its outbox, so they are saved along with the status
+fn record_payment_status_events(payment: &mut Payment, status: &PaymentStatus) {
+    if let Some(payment_status_event) = Event::for_payment_status(payment, status) {
+        payment.record_event(payment_status_event);
+    }
+
+    if let Some(inventory_release_event) = Event::for_inventory_release(payment, status) {
+        payment.record_event(inventory_release_event);
+    }
+}
+
+// Publishes the events in the payment's outbox in the order they were recorded, removing each once RabbitMQ confirmed it.
+// The first that fails and everything after it stay for the outbox relay to retry. Returns how many were published.
+async fn relay_outbox(payment_repository: &Arc<dyn PaymentRepository + Send + Sync>, message_broker: &Arc<dyn MessageBroker + Send + Sync>, payment: &Payment) -> u32 {
+    let mut published = 0;
+
+    for outbox_event in &payment.outbox {
+        if let Err(e) = message_broker.publish_event(&outbox_event.event_id, &outbox_event.event).await {
+            event!(Level::WARN, "Error occurred when publishing {} {} for Payment {}: {}", outbox_event.event.event_type(), outbox_event.event_id, payment.id, e);
+            break;
+        }
+
+        // Published again by the relay when this fails, which consumers recognise by the unchanged event id
+        if let Err(e) = payment_repository.remove_outbox_event(&payment.id, &outbox_event.event_id).await {
+            event!(Level::WARN, "Error occurred when removing published {} {} from Payment {}: {}", outbox_event.event.event_type(), outbox_event.event_id, payment.id, e);
+            break;
+        }
+        published += 1;
+    }
+
+    published
+}
+
+pub struct GetCheckoutSessionQueryHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>,
+}
+
+impl GetCheckoutSessionQueryHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, payment_repository: Arc<dyn PaymentRepository + Send + Sync>, message_broker: Arc<dyn MessageBroker + Send + Sync>, advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>) -> Self {
+        GetCheckoutSessionQueryHandler {
+            payment_processors,
+            payment_repository,
+            message_broker,
+            advance_payment_saga_command_handler,
+        }
+    }
+}
+
+// A customer returning from checkout usually asks before the webhook arrives, so a payment still awaiting checkout is
+// brought in line with its checkout session first, the same way the sweeper does it
+impl QueryHandler<GetCheckoutSessionQuery, GetCheckoutSessionResponseDto, HandlerError> for GetCheckoutSessionQueryHandler {
+    async fn handle(&self, input: Option<GetCheckoutSessionQuery>) -> Result<GetCheckoutSessionResponseDto, HandlerError> {
+        let query = match input {
+            Some(query) => query,
+            None => return Err(HandlerError::BadRequest(String::from("A checkout session id is required")))
+        };
+
+        let payment = match self.payment_repository.read_by_checkout_session_id(&query.session_id).await? {
+            Some(payment) => payment,
+            None => {
+                event!(Level::WARN, "No Payment found for checkout session {}", query.session_id);
+                return Err(HandlerError::NotFound(format!("No Payment found for checkout session {}", query.session_id)));
+            }
+        };
+
+        if !query.is_admin && !query.customer.owns(&payment) {
+            event!(Level::WARN, "Subject {} cannot read checkout session {} of another customer", query.customer.subject, query.session_id);
+            return Err(HandlerError::Forbidden(format!("Checkout session {} belongs to another customer", query.session_id)));
+        }
+
+        let payment = if payment.status == PaymentStatus::NEW.to_string() || payment.status == PaymentStatus::SESSION_CREATED.to_string() {
+            match fetch_checkout_session(&self.payment_processors, &payment).await {
+                Ok(checkout_session) => save_checkout_session_outcome(&self.payment_repository, &self.message_broker, &self.advance_payment_saga_command_handler, payment, Some(&checkout_session)).await?.0,
+                // What is stored is still worth reporting, the webhook or the sweeper catch up with the session later
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when retrieving checkout session {}, reporting the stored Payment: {}", query.session_id, e);
+                    payment
+                }
+            }
+        } else {
+            payment
+        };
+
+        Ok(GetCheckoutSessionResponseDto {
+            payment_id: payment.id.clone(),
+            checkout_session_id: query.session_id,
+            status: payment.checkout_session_status().to_string(),
+            payment_status: payment.status,
+            customer_email: payment.customer_email,
+        })
+    }
+}
+
+pub struct HandlePaymentProcessorWebhookCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>,
+}
+
+impl HandlePaymentProcessorWebhookCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, payment_repository: Arc<dyn PaymentRepository + Send + Sync>, subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>, message_broker: Arc<dyn MessageBroker + Send + Sync>, advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>) -> Self {
+        HandlePaymentProcessorWebhookCommandHandler {
+            payment_processors,
+            payment_repository,
+            subscription_repository,
+            message_broker,
+            advance_payment_saga_command_handler,
+        }
+    }
+
+    // Subscriptions created by this service carry our subscription id in their metadata, the payment processor's
+    // subscription id is used for anything else
+    async fn resolve_subscription(&self, subscription_id: Option<&String>, payment_processor_subscription_id: &str) -> Result<Option<Subscription>, String> {
+        if let Some(subscription_id) = subscription_id {
+            if let Some(subscription) = self.subscription_repository.read(subscription_id).await? {
+                return Ok(Some(subscription));
+            }
+        }
+
+        self.subscription_repository.read_by_payment_processor_subscription_id(payment_processor_subscription_id).await
+    }
+
+    // Completing checkout links the subscription to the one the payment processor created, its status follows from the
+    // subscription's own events
+    async fn handle_subscription_checkout_session_event(&self, checkout_session: PaymentProcessorCheckoutSessionResponseDto) -> Result<(), String> {
+        let mut subscription = match checkout_session.metadata.get("subscription_id").or(checkout_session.client_reference_id.as_ref()) {
+            Some(subscription_id) => self.subscription_repository.read(subscription_id).await?,
+            None => self.subscription_repository.read_by_checkout_session_id(&checkout_session.session_id).await?
+        };
+
+        let subscription = match subscription.as_mut() {
+            Some(subscription) => subscription,
+            None => {
+                event!(Level::WARN, "No Subscription found for checkout session {}", checkout_session.session_id);
+                return Ok(());
+            }
+        };
+
+        if let Some(payment_processor_subscription_id) = checkout_session.subscription {
+            subscription.payment_processor_subscription_id = payment_processor_subscription_id;
+        }
+        if let Some(customer) = checkout_session.customer {
+            subscription.payment_processor_customer_id = customer;
+        }
+        if let Some(email) = checkout_session.customer_details.and_then(|customer_details| customer_details.email) {
+            subscription.customer_email = email;
+        }
+        if checkout_session.status.as_deref() == Some("expired") && subscription.status == SubscriptionStatus::PENDING.to_string() {
+            subscription.status = SubscriptionStatus::EXPIRED.to_string();
+        }
+
+        self.subscription_repository.update(subscription).await
+    }
+
+    async fn handle_subscription_event(&self, payment_processor_subscription: PaymentProcessorSubscriptionResponseDto) -> Result<(), String> {
+        let mut subscription = match self.resolve_subscription(payment_processor_subscription.metadata.get("subscription_id"), &payment_processor_subscription.id).await? {
+            Some(subscription) => subscription,
+            None => {
+                event!(Level::WARN, "No Subscription found for payment processor subscription {}", payment_processor_subscription.id);
+                return Ok(());
+            }
+        };
+
+        if let Some(status) = apply_payment_processor_subscription(&mut subscription, &payment_processor_subscription) {
+            event!(Level::INFO, "Subscription {} moved to {:?}", subscription.id, status);
+        }
+
+        self.subscription_repository.update(&subscription).await
+    }
+
+    async fn handle_invoice_event(&self, invoice: PaymentProcessorInvoiceResponseDto, paid: bool) -> Result<(), String> {
+        let payment_processor_subscription_id = match invoice.subscription {
+            Some(payment_processor_subscription_id) => payment_processor_subscription_id,
+            // One-off invoices have nothing to do with subscriptions
+            None => return Ok(())
+        };
+
+        let mut subscription = match self.resolve_subscription(None, &payment_processor_subscription_id).await? {
+            Some(subscription) => subscription,
+            None => {
+                event!(Level::WARN, "No Subscription found for invoice {}", invoice.id);
+                return Ok(());
+            }
+        };
+
+        if let Some(status) = subscription.reconcile_invoice(paid) {
+            event!(Level::INFO, "Subscription {} moved to {:?} by invoice {}", subscription.id, status, invoice.id);
+            self.subscription_repository.update(&subscription).await?;
+        }
+
+        Ok(())
+    }
+
+    // Sessions created by this service carry our payment id in their metadata and client reference id, the session id
+    // is only used for sessions created before that was the case
+    async fn resolve_payment(&self, checkout_session: &PaymentProcessorCheckoutSessionResponseDto) -> Result<Option<Payment>, String> {
+        let payment_ids = checkout_session.metadata.get("payment_id").into_iter().chain(checkout_session.client_reference_id.iter());
+        for payment_id in payment_ids {
+            if let Some(payment) = self.payment_repository.read(payment_id).await? {
+                return Ok(Some(payment));
+            }
+        }
+
+        self.payment_repository.read_by_checkout_session_id(&checkout_session.session_id).await
+    }
+
+    // A payment under dispute stays Disputed until all its disputes are closed, it then goes back to what it was when the
+    // first was opened, or to ChargedBack if any was lost
+    async fn handle_dispute_event(&self, payment_processor_dispute: PaymentProcessorDisputeResponseDto) -> Result<(), String> {
+        let payment = match &payment_processor_dispute.payment_intent {
+            Some(payment_intent) => self.payment_repository.read_by_payment_processor_id(payment_intent).await?,
+            None => None
+        };
+        let payment = match payment {
+            Some(payment) => payment,
+            None => {
+                // Acknowledged rather than failed, e.g. a dispute on a subscription invoice has no Payment
+                event!(Level::WARN, "No Payment found for dispute {}", payment_processor_dispute.id);
+                return Ok(());
+            }
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut is_new_dispute = false;
+        let payment = match save_payment(&self.payment_repository, payment, |payment| {
+            let was_charged_back = payment.status == PaymentStatus::CHARGED_BACK.to_string();
+            is_new_dispute = Self::apply_dispute(payment, &payment_processor_dispute, now);
+            if is_new_dispute {
+                record_payment_status_events(payment, &PaymentStatus::DISPUTED);
+            }
+            // Announced in the same save that charges the payment back, so it is never lost in between
+            if !was_charged_back && payment.status == PaymentStatus::CHARGED_BACK.to_string() {
+                record_payment_status_events(payment, &PaymentStatus::CHARGED_BACK);
+            }
+            Ok(true)
+        }).await {
+            Ok(payment) => payment,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when saving dispute {}: {}", payment_processor_dispute.id, e);
+                return Err(format!("Error occurred when saving dispute {}: {}", payment_processor_dispute.id, e));
+            }
+        };
+
+        event!(Level::INFO, "Dispute {} for Payment {} is {}, Payment is {}", payment_processor_dispute.id, payment.id, payment_processor_dispute.status, payment.status);
+        relay_outbox(&self.payment_repository, &self.message_broker, &payment).await;
+
+        Ok(())
+    }
+
+    // Records the dispute as the processor reported it on the payment and moves the payment to match its disputes. Returns
+    // whether the dispute is new to the payment.
+    fn apply_dispute(payment: &mut Payment, payment_processor_dispute: &PaymentProcessorDisputeResponseDto, now: u64) -> bool {
+        let is_new_dispute = !payment.disputes.iter().any(|dispute| dispute.id == payment_processor_dispute.id);
+        if is_new_dispute {
+            let payment_status_before_dispute = payment.disputes.first().map(|dispute| dispute.payment_status_before_dispute.clone()).unwrap_or(payment.status.clone());
+            payment.disputes.push(Dispute {
+                id: payment_processor_dispute.id.clone(),
+                amount: payment_processor_dispute.amount,
+                currency: payment_processor_dispute.currency.clone(),
+                reason: payment_processor_dispute.reason.clone(),
+                payment_processor_status: String::new(),
+                evidence_due_by: 0,
+                evidence_submitted_at: 0,
+                payment_status_before_dispute,
+                created_at: payment_processor_dispute.created,
+                updated_at: now,
+                closed_at: 0,
+            });
+        }
+
+        let dispute = payment.disputes.iter_mut().find(|dispute| dispute.id == payment_processor_dispute.id).unwrap();
+        let was_closed = dispute.is_closed();
+        dispute.payment_processor_status = payment_processor_dispute.status.clone();
+        dispute.reason = payment_processor_dispute.reason.clone();
+        dispute.evidence_due_by = payment_processor_dispute.evidence_details.as_ref().and_then(|evidence_details| evidence_details.due_by).unwrap_or(0);
+        dispute.updated_at = now;
+        if dispute.is_closed() && !was_closed {
+            dispute.closed_at = now;
+        }
+
+        let new_status = if payment.disputes.iter().all(|dispute| dispute.is_closed()) {
+            if payment.disputes.iter().any(|dispute| dispute.is_lost()) {
+                Some(PaymentStatus::CHARGED_BACK.to_string())
+            } else {
+                payment.disputes.first().map(|dispute| dispute.payment_status_before_dispute.clone())
+            }
+        } else {
+            Some(PaymentStatus::DISPUTED.to_string())
+        };
+        if let Some(new_status) = new_status {
+            payment.status = new_status;
+        }
+
+        is_new_dispute
+    }
+
+    async fn handle_checkout_session_event(&self, mut checkout_session: PaymentProcessorCheckoutSessionResponseDto) -> Result<(), String> {
+        let payment = match self.resolve_payment(&checkout_session).await? {
+            Some(payment) => payment,
+            None => {
+                // Acknowledged rather than failed, retrying a delivery for a session we never created cannot succeed
+                event!(Level::WARN, "No Payment found for checkout session {}", checkout_session.session_id);
+                return Ok(());
+            }
+        };
+
+        // The event carries the session without its line items or shipping rate, so the tax the processor worked out and
+        // the shipping option picked have to be fetched
+        if (payment.automatic_tax || payment.shipping.is_some()) && checkout_session.status.as_deref() == Some("complete") {
+            checkout_session = self.payment_processors.get(&payment.payment_processor)?.get_checkout_session(checkout_session.session_id.clone()).await?;
+        }
+
+        // A transition another writer already saved is not reported again
+        let mut new_status = None;
+        let payment = match save_payment(&self.payment_repository, payment, |payment| {
+            new_status = apply_checkout_session(payment, &checkout_session);
+            if let Some(status) = &new_status {
+                record_payment_status_events(payment, status);
+            }
+            Ok(true)
+        }).await {
+            Ok(payment) => payment,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when saving Payment for checkout session {} from webhook: {}", checkout_session.session_id, e);
+                return Err(format!("Error occurred when saving Payment for checkout session {} from webhook: {}", checkout_session.session_id, e));
+            }
+        };
+
+        relay_outbox(&self.payment_repository, &self.message_broker, &payment).await;
+        if let Some(status) = new_status {
+            advance_payment_saga_for_status(&self.advance_payment_saga_command_handler, &payment, &status).await;
+        }
+
+        Ok(())
+    }
+}
+
+impl CommandHandler<HandlePaymentProcessorWebhookCommand, EmptyResponse> for HandlePaymentProcessorWebhookCommandHandler {
+    async fn handle(&self, input: &HandlePaymentProcessorWebhookCommand) -> Result<EmptyResponse, String> {
+        let webhook_event = self.payment_processors.get(&input.payment_processor)?.parse_webhook_event(&input.payload, &input.headers)?;
+        event!(Level::DEBUG, "Received payment processor webhook event {} of type {}", webhook_event.id, webhook_event.event_type);
+
+        match webhook_event.event_type.as_str() {
+            "checkout.session.completed" | "checkout.session.async_payment_succeeded" | "checkout.session.async_payment_failed" | "checkout.session.expired" => {
+                match serde_json::from_value::<PaymentProcessorCheckoutSessionResponseDto>(webhook_event.data.object) {
+                    Ok(checkout_session) if checkout_session.mode.as_deref() == Some("subscription") => self.handle_subscription_checkout_session_event(checkout_session).await?,
+                    Ok(checkout_session) => self.handle_checkout_session_event(checkout_session).await?,
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when deserializing checkout session from webhook event {}: {}", webhook_event.id, e);
+                        return Err(format!("Error occurred when deserializing checkout session from webhook event {}: {}", webhook_event.id, e));
+                    }
+                }
+            },
+            "customer.subscription.created" | "customer.subscription.updated" | "customer.subscription.deleted" | "customer.subscription.paused" | "customer.subscription.resumed" => {
+                match serde_json::from_value::<PaymentProcessorSubscriptionResponseDto>(webhook_event.data.object) {
+                    Ok(payment_processor_subscription) => self.handle_subscription_event(payment_processor_subscription).await?,
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when deserializing subscription from webhook event {}: {}", webhook_event.id, e);
+                        return Err(format!("Error occurred when deserializing subscription from webhook event {}: {}", webhook_event.id, e));
+                    }
+                }
+            },
+            "invoice.paid" | "invoice.payment_failed" => {
+                let paid = webhook_event.event_type == "invoice.paid";
+                match serde_json::from_value::<PaymentProcessorInvoiceResponseDto>(webhook_event.data.object) {
+                    Ok(invoice) => self.handle_invoice_event(invoice, paid).await?,
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when deserializing invoice from webhook event {}: {}", webhook_event.id, e);
+                        return Err(format!("Error occurred when deserializing invoice from webhook event {}: {}", webhook_event.id, e));
+                    }
+                }
+            },
+            "charge.dispute.created" | "charge.dispute.updated" | "charge.dispute.closed" => {
+                match serde_json::from_value::<PaymentProcessorDisputeResponseDto>(webhook_event.data.object) {
+                    Ok(dispute) => self.handle_dispute_event(dispute).await?,
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when deserializing dispute from webhook event {}: {}", webhook_event.id, e);
+                        return Err(format!("Error occurred when deserializing dispute from webhook event {}: {}", webhook_event.id, e));
+                    }
+                }
+            },
+            x => event!(Level::INFO, "Webhook event type {} is not handled", x)
+        }
+
+        Ok(EmptyResponse {})
+    }
+}
+
+// Brings a stored Subscription in line with the subscription reported by the payment processor. Returns the new status
+// when the Subscription transitioned.
+fn apply_payment_processor_subscription(subscription: &mut Subscription, payment_processor_subscription: &PaymentProcessorSubscriptionResponseDto) -> Option<SubscriptionStatus> {
+    subscription.payment_processor_subscription_id = payment_processor_subscription.id.clone();
+    subscription.payment_processor_status = payment_processor_subscription.status.clone();
+    subscription.cancel_at_period_end = payment_processor_subscription.cancel_at_period_end;
+    if let Some(customer) = &payment_processor_subscription.customer {
+        subscription.payment_processor_customer_id = customer.clone();
+    }
+    if let Some(current_period_end) = payment_processor_subscription.current_period_end {
+        subscription.current_period_end = current_period_end;
+    }
+
+    subscription.reconcile_subscription(&payment_processor_subscription.status, payment_processor_subscription.pause_collection.is_some())
+}
+
+fn to_subscription_response(subscription: Subscription) -> SubscriptionResponseDto {
+    SubscriptionResponseDto {
+        subscription_id: subscription.id,
+        subscription_status: subscription.status,
+        cancel_at_period_end: subscription.cancel_at_period_end,
+        current_period_end: subscription.current_period_end,
+    }
+}
+
+pub struct CreateSubscriptionCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>,
+    customer_repository: Arc<dyn CustomerRepository + Send + Sync>,
+    product_repository: Arc<dyn ProductRepository + Send + Sync>,
+}
+
+impl CreateSubscriptionCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>, customer_repository: Arc<dyn CustomerRepository + Send + Sync>, product_repository: Arc<dyn ProductRepository + Send + Sync>) -> Self {
+        CreateSubscriptionCommandHandler {
+            payment_processors,
+            subscription_repository,
+            customer_repository,
+            product_repository,
+        }
+    }
+}
+
+impl CommandHandler<CreateSubscriptionCommand, CreateSubscriptionResponseDto> for CreateSubscriptionCommandHandler {
+    async fn handle(&self, input: &CreateSubscriptionCommand) -> Result<CreateSubscriptionResponseDto, String> {
+        let checkout_mode = input.checkout_mode.clone().unwrap_or(default_checkout_mode());
+        let checkout_modes = [CheckoutMode::HOSTED.to_string(), CheckoutMode::EMBEDDED.to_string(), CheckoutMode::CUSTOM.to_string()];
+        if !checkout_modes.contains(&checkout_mode) {
+            event!(Level::WARN, "Checkout mode {} is not one of {:?}", checkout_mode, checkout_modes);
+            return Err(format!("Checkout mode {} is not one of {:?}", checkout_mode, checkout_modes));
+        }
+
+        if input.line_items.is_empty() {
+            return Err(String::from("A subscription needs at least one line item"));
+        }
+
+        // Prices and billing intervals come from the catalog as announced by ProductCreated events, never from the request
+        let products = self.product_repository.read_by_ids(input.line_items.iter().map(|line_item| line_item.product_id.clone()).collect()).await?;
+        let mut line_items: Vec<SubscriptionLineItem> = Vec::new();
+        for line_item in &input.line_items {
+            let product = match products.iter().find(|product| product.id == line_item.product_id) {
+                Some(product) if product.price > 0 => product,
+                _ => {
+                    event!(Level::WARN, "Product {} is not for sale", line_item.product_id);
+                    return Err(format!("Product {} is not for sale", line_item.product_id));
+                }
+            };
+
+            let (interval, interval_count) = match (&product.interval, product.interval_count) {
+                (Some(interval), Some(interval_count)) if ["day", "week", "month", "year"].contains(&interval.as_str()) && interval_count > 0 => (interval.clone(), interval_count),
+                _ => {
+                    event!(Level::WARN, "Product {} is not sold as a subscription", product.id);
+                    return Err(format!("Product {} is not sold as a subscription", product.id));
+                }
+            };
+
+            if !product.currency.eq_ignore_ascii_case(&input.currency) {
+                event!(Level::WARN, "Product {} is priced in {} and cannot be bought in {}", product.id, product.currency, input.currency);
+                return Err(format!("Product {} is priced in {} and cannot be bought in {}", product.id, product.currency, input.currency));
+            }
+
+            if line_item.quantity == 0 {
+                event!(Level::WARN, "Product {} needs a quantity of at least 1", product.id);
+                return Err(format!("Product {} needs a quantity of at least 1", product.id));
+            }
+
+            // Everything in one subscription is billed together, so every line item has to share the billing interval
+            if let Some(first_line_item) = line_items.first() {
+                if interval != first_line_item.interval || interval_count != first_line_item.interval_count {
+                    event!(Level::WARN, "Product {} is not billed on the same interval as product {}", product.id, first_line_item.product_id);
+                    return Err(format!("Product {} is not billed on the same interval as product {}", product.id, first_line_item.product_id));
+                }
+            }
+
+            line_items.push(SubscriptionLineItem {
+                product_id: product.id.clone(),
+                quantity: line_item.quantity,
+                price: product.price,
+                interval,
+                interval_count,
+            });
+        }
+
+        let (payment_processor_name, payment_processor) = self.payment_processors.select(input.payment_processor.as_deref(), &input.currency)?;
+
+        let payment_processor_customer_id = match &input.customer {
+            Some(identity) => ensure_customer(&self.customer_repository, &payment_processor_name, &payment_processor, identity, input.customer_email.as_ref()).await,
+            None => String::new()
+        };
+
+        let subscription = Subscription {
+            id: uuid::Uuid::new_v4().to_string(),
+            line_items,
+            currency: input.currency.to_lowercase(),
+            checkout_mode,
+            status: SubscriptionStatus::PENDING.to_string(),
+            payment_processor: payment_processor_name,
+            payment_processor_checkout_session_id: String::new(),
+            payment_processor_checkout_session_url: String::new(),
+            payment_processor_checkout_session_client_secret: String::new(),
+            payment_processor_checkout_session_expires_at: 0,
+            payment_processor_subscription_id: String::new(),
+            payment_processor_customer_id,
+            payment_processor_status: String::new(),
+            customer_subject: input.customer.as_ref().map(|identity| identity.subject.clone()).unwrap_or_default(),
+            customer_email: input.customer_email.clone().or(input.customer.as_ref().and_then(|identity| identity.email.clone())).unwrap_or_default(),
+            current_period_end: 0,
+            cancel_at_period_end: false,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+
+        let subscription = match payment_processor.create_subscription_checkout_session(subscription).await {
+            Ok(subscription) => subscription,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when creating subscription checkout session: {}", e);
+                return Err(format!("Error occurred when creating subscription checkout session: {}", e));
+            }
+        };
+
+        if let Err(e) = self.subscription_repository.create(&subscription).await {
+            event!(Level::WARN, "Error occurred when saving Subscription {}: {}", subscription.id, e);
+            return Err(format!("Error occurred when saving Subscription {}: {}", subscription.id, e));
+        }
+
+        Ok(CreateSubscriptionResponseDto {
+            subscription_id: subscription.id,
+            checkout_mode: subscription.checkout_mode,
+            checkout_session_id: subscription.payment_processor_checkout_session_id,
+            checkout_session_url: if subscription.payment_processor_checkout_session_url.is_empty() { None } else { Some(subscription.payment_processor_checkout_session_url) },
+            client_secret: if subscription.payment_processor_checkout_session_client_secret.is_empty() { None } else { Some(subscription.payment_processor_checkout_session_client_secret) },
+        })
+    }
+}
+
+pub struct CancelSubscriptionCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>,
+}
+
+impl CancelSubscriptionCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>) -> Self {
+        CancelSubscriptionCommandHandler {
+            payment_processors,
+            subscription_repository,
+        }
+    }
+}
+
+impl CommandHandler<CancelSubscriptionCommand, SubscriptionResponseDto, HandlerError> for CancelSubscriptionCommandHandler {
+    async fn handle(&self, input: &CancelSubscriptionCommand) -> Result<SubscriptionResponseDto, HandlerError> {
+        let mut subscription = match self.subscription_repository.read(&input.subscription_id).await? {
+            Some(subscription) => subscription,
+            None => {
+                event!(Level::WARN, "Subscription {} not found", input.subscription_id);
+                return Err(HandlerError::NotFound(format!("Subscription {} not found", input.subscription_id)));
+            }
+        };
+
+        if !input.is_admin && !input.customer.owns_subscription(&subscription) {
+            event!(Level::WARN, "Subject {} cannot cancel Subscription {} of another customer", input.customer.subject, subscription.id);
+            return Err(HandlerError::Forbidden(format!("Subscription {} belongs to another customer", subscription.id)));
+        }
+
+        if subscription.status == SubscriptionStatus::CANCELLED.to_string() || subscription.status == SubscriptionStatus::EXPIRED.to_string() {
+            event!(Level::WARN, "Subscription {} cannot be cancelled while {}", subscription.id, subscription.status);
+            return Err(HandlerError::Conflict(format!("Subscription {} cannot be cancelled while {}", subscription.id, subscription.status)));
+        }
+
+        let payment_processor = self.payment_processors.get(&subscription.payment_processor)?;
+
+        if subscription.payment_processor_subscription_id.is_empty() {
+            // Checkout never completed, so there is only the session to stop
+            if !subscription.payment_processor_checkout_session_id.is_empty() {
+                payment_processor.expire_checkout_session(subscription.payment_processor_checkout_session_id.clone()).await?;
+            }
+
+            subscription.status = SubscriptionStatus::CANCELLED.to_string();
+        } else {
+            let payment_processor_subscription = match payment_processor.cancel_subscription(subscription.payment_processor_subscription_id.clone(), input.at_period_end).await {
+                Ok(payment_processor_subscription) => payment_processor_subscription,
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when cancelling Subscription {}: {}", subscription.id, e);
+                    return Err(HandlerError::Internal(format!("Error occurred when cancelling Subscription {}: {}", subscription.id, e)));
+                }
+            };
+
+            apply_payment_processor_subscription(&mut subscription, &payment_processor_subscription);
+        }
+
+        self.subscription_repository.update(&subscription).await?;
+
+        Ok(to_subscription_response(subscription))
+    }
+}
+
+pub struct PauseSubscriptionCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>,
+}
+
+impl PauseSubscriptionCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>) -> Self {
+        PauseSubscriptionCommandHandler {
+            payment_processors,
+            subscription_repository,
+        }
+    }
+}
+
+impl CommandHandler<PauseSubscriptionCommand, SubscriptionResponseDto, HandlerError> for PauseSubscriptionCommandHandler {
+    async fn handle(&self, input: &PauseSubscriptionCommand) -> Result<SubscriptionResponseDto, HandlerError> {
+        let mut subscription = match self.subscription_repository.read(&input.subscription_id).await? {
+            Some(subscription) => subscription,
+            None => {
+                event!(Level::WARN, "Subscription {} not found", input.subscription_id);
+                return Err(HandlerError::NotFound(format!("Subscription {} not found", input.subscription_id)));
+            }
+        };
+
+        if !input.is_admin && !input.customer.owns_subscription(&subscription) {
+            event!(Level::WARN, "Subject {} cannot pause Subscription {} of another customer", input.customer.subject, subscription.id);
+            return Err(HandlerError::Forbidden(format!("Subscription {} belongs to another customer", subscription.id)));
+        }
+
+        if subscription.status != SubscriptionStatus::ACTIVE.to_string() && subscription.status != SubscriptionStatus::PAST_DUE.to_string() {
+            event!(Level::WARN, "Subscription {} cannot be paused while {}", subscription.id, subscription.status);
+            return Err(HandlerError::Conflict(format!("Subscription {} cannot be paused while {}", subscription.id, subscription.status)));
+        }
+
+        let payment_processor_subscription = match self.payment_processors.get(&subscription.payment_processor)?.pause_subscription(subscription.payment_processor_subscription_id.clone()).await {
+            Ok(payment_processor_subscription) => payment_processor_subscription,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when pausing Subscription {}: {}", subscription.id, e);
+                return Err(HandlerError::Internal(format!("Error occurred when pausing Subscription {}: {}", subscription.id, e)));
+            }
+        };
+
+        apply_payment_processor_subscription(&mut subscription, &payment_processor_subscription);
+        self.subscription_repository.update(&subscription).await?;
+
+        Ok(to_subscription_response(subscription))
+    }
+}
+
+pub struct ResumeSubscriptionCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>,
+}
+
+impl ResumeSubscriptionCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, subscription_repository: Arc<dyn SubscriptionRepository + Send + Sync>) -> Self {
+        ResumeSubscriptionCommandHandler {
+            payment_processors,
+            subscription_repository,
+        }
+    }
+}
+
+impl CommandHandler<ResumeSubscriptionCommand, SubscriptionResponseDto, HandlerError> for ResumeSubscriptionCommandHandler {
+    async fn handle(&self, input: &ResumeSubscriptionCommand) -> Result<SubscriptionResponseDto, HandlerError> {
+        let mut subscription = match self.subscription_repository.read(&input.subscription_id).await? {
+            Some(subscription) => subscription,
+            None => {
+                event!(Level::WARN, "Subscription {} not found", input.subscription_id);
+                return Err(HandlerError::NotFound(format!("Subscription {} not found", input.subscription_id)));
+            }
+        };
+
+        if !input.is_admin && !input.customer.owns_subscription(&subscription) {
+            event!(Level::WARN, "Subject {} cannot resume Subscription {} of another customer", input.customer.subject, subscription.id);
+            return Err(HandlerError::Forbidden(format!("Subscription {} belongs to another customer", subscription.id)));
+        }
+
+        if subscription.status != SubscriptionStatus::PAUSED.to_string() {
+            event!(Level::WARN, "Subscription {} cannot be resumed while {}", subscription.id, subscription.status);
+            return Err(HandlerError::Conflict(format!("Subscription {} cannot be resumed while {}", subscription.id, subscription.status)));
+        }
+
+        let payment_processor_subscription = match self.payment_processors.get(&subscription.payment_processor)?.resume_subscription(subscription.payment_processor_subscription_id.clone()).await {
+            Ok(payment_processor_subscription) => payment_processor_subscription,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when resuming Subscription {}: {}", subscription.id, e);
+                return Err(HandlerError::Internal(format!("Error occurred when resuming Subscription {}: {}", subscription.id, e)));
+            }
+        };
+
+        apply_payment_processor_subscription(&mut subscription, &payment_processor_subscription);
+        self.subscription_repository.update(&subscription).await?;
+
+        Ok(to_subscription_response(subscription))
+    }
+}
+
+pub struct CreatePromotionCommandHandler {
+    promotion_repository: Arc<dyn PromotionRepository + Send + Sync>,
+}
+
+impl CreatePromotionCommandHandler {
+    pub fn new(promotion_repository: Arc<dyn PromotionRepository + Send + Sync>) -> Self {
+        CreatePromotionCommandHandler {
+            promotion_repository,
+        }
+    }
+}
+
+impl CommandHandler<CreatePromotionCommand, CreatePromotionResponseDto> for CreatePromotionCommandHandler {
+    async fn handle(&self, input: &CreatePromotionCommand) -> Result<CreatePromotionResponseDto, String> {
+        // Codes are matched case-insensitively, so they are kept uppercase
+        let code = input.code.trim().to_uppercase();
+        if code.is_empty() {
+            return Err(String::from("A promotion code is required"));
+        }
+
+        let valid_discount = if input.discount_type == DiscountType::PERCENT_OFF.to_string() {
+            input.percent_off > 0.0 && input.percent_off <= 100.0
+        } else if input.discount_type == DiscountType::AMOUNT_OFF.to_string() {
+            to_minor_units(input.amount_off) > 0
+        } else {
+            false
+        };
+        if !valid_discount {
+            event!(Level::WARN, "Promotion {} needs a percent off between 0 and 100 or a positive amount off", code);
+            return Err(format!("Promotion {} needs a percent off between 0 and 100 or a positive amount off", code));
+        }
+
+        let promotion = Promotion {
+            code: code.clone(),
+            discount_type: input.discount_type.clone(),
+            percent_off: input.percent_off,
+            amount_off: to_minor_units(input.amount_off),
+            currency: input.currency.to_lowercase(),
+            minimum_order_amount: to_minor_units(input.minimum_order_amount),
+            max_redemptions: input.max_redemptions,
+            max_redemptions_per_customer: input.max_redemptions_per_customer,
+            expires_at: input.expires_at,
+            active: true,
+            redemptions: Vec::new(),
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        };
+
+        if !self.promotion_repository.create(&promotion).await? {
+            event!(Level::WARN, "Promotion {} already exists", code);
+            return Err(format!("Promotion {} already exists", code));
+        }
+
+        Ok(CreatePromotionResponseDto { code })
+    }
+}
+
+// Saved payment methods live on the shopper's customer with the default payment processor
+pub struct ListPaymentMethodsQueryHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    customer_repository: Arc<dyn CustomerRepository + Send + Sync>,
+}
+
+impl ListPaymentMethodsQueryHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, customer_repository: Arc<dyn CustomerRepository + Send + Sync>) -> Self {
+        ListPaymentMethodsQueryHandler {
+            payment_processors,
+            customer_repository,
+        }
+    }
+}
+
+impl QueryHandler<ListPaymentMethodsQuery, ListPaymentMethodsResponseDto> for ListPaymentMethodsQueryHandler {
+    async fn handle(&self, input: Option<ListPaymentMethodsQuery>) -> Result<ListPaymentMethodsResponseDto, String> {
+        let subject = match input {
+            Some(query) => query.subject,
+            None => return Err(String::from("A subject is required"))
+        };
+
+        let payment_processor_name = self.payment_processors.resolve_name("");
+        let customer = match self.customer_repository.read(&subject, &payment_processor_name).await? {
+            Some(customer) => customer,
+            // Shoppers who never checked out while signed in have nothing saved
+            None => return Ok(ListPaymentMethodsResponseDto { payment_methods: Vec::new() })
+        };
+
+        let payment_methods = self.payment_processors.get(&payment_processor_name)?.list_payment_methods(customer.payment_processor_customer_id).await?;
+
+        Ok(ListPaymentMethodsResponseDto {
+            payment_methods: payment_methods.into_iter().map(|payment_method| PaymentMethodResponseDto {
+                payment_method_id: payment_method.id,
+                payment_method_type: payment_method.payment_method_type,
+                brand: payment_method.card.as_ref().map(|card| card.brand.clone()),
+                last4: payment_method.card.as_ref().map(|card| card.last4.clone()),
+                exp_month: payment_method.card.as_ref().map(|card| card.exp_month),
+                exp_year: payment_method.card.as_ref().map(|card| card.exp_year),
+            }).collect(),
+        })
+    }
+}
+
+pub struct DetachPaymentMethodCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    customer_repository: Arc<dyn CustomerRepository + Send + Sync>,
+}
+
+impl DetachPaymentMethodCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, customer_repository: Arc<dyn CustomerRepository + Send + Sync>) -> Self {
+        DetachPaymentMethodCommandHandler {
+            payment_processors,
+            customer_repository,
+        }
+    }
+}
+
+impl CommandHandler<DetachPaymentMethodCommand, EmptyResponse> for DetachPaymentMethodCommandHandler {
+    async fn handle(&self, input: &DetachPaymentMethodCommand) -> Result<EmptyResponse, String> {
+        let payment_processor_name = self.payment_processors.resolve_name("");
+        let payment_processor = self.payment_processors.get(&payment_processor_name)?;
+
+        let customer = match self.customer_repository.read(&input.subject, &payment_processor_name).await? {
+            Some(customer) => customer,
+            None => {
+                event!(Level::WARN, "Payment method {} not found for {}", input.payment_method_id, input.subject);
+                return Err(format!("Payment method {} not found", input.payment_method_id));
+            }
+        };
+
+        // Only the shopper's own payment methods can be removed
+        let payment_methods = payment_processor.list_payment_methods(customer.payment_processor_customer_id).await?;
+        if !payment_methods.iter().any(|payment_method| payment_method.id == input.payment_method_id) {
+            event!(Level::WARN, "Payment method {} not found for {}", input.payment_method_id, input.subject);
+            return Err(format!("Payment method {} not found", input.payment_method_id));
+        }
+
+        payment_processor.detach_payment_method(input.payment_method_id.clone()).await?;
+
+        Ok(EmptyResponse {})
+    }
+}
+
+pub struct CreateSetupCheckoutSessionCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    customer_repository: Arc<dyn CustomerRepository + Send + Sync>,
+}
+
+impl CreateSetupCheckoutSessionCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, customer_repository: Arc<dyn CustomerRepository + Send + Sync>) -> Self {
+        CreateSetupCheckoutSessionCommandHandler {
+            payment_processors,
+            customer_repository,
+        }
+    }
+}
+
+impl CommandHandler<CreateSetupCheckoutSessionCommand, CreateSetupCheckoutSessionResponseDto> for CreateSetupCheckoutSessionCommandHandler {
+    async fn handle(&self, input: &CreateSetupCheckoutSessionCommand) -> Result<CreateSetupCheckoutSessionResponseDto, String> {
+        let checkout_mode = input.checkout_mode.clone().unwrap_or(default_checkout_mode());
+        let checkout_modes = [CheckoutMode::HOSTED.to_string(), CheckoutMode::EMBEDDED.to_string(), CheckoutMode::CUSTOM.to_string()];
+        if !checkout_modes.contains(&checkout_mode) {
+            event!(Level::WARN, "Checkout mode {} is not one of {:?}", checkout_mode, checkout_modes);
+            return Err(format!("Checkout mode {} is not one of {:?}", checkout_mode, checkout_modes));
+        }
+
+        let identity = match &input.customer {
+            Some(identity) => identity,
+            None => return Err(String::from("Saving a payment method requires a signed-in shopper"))
+        };
+
+        let payment_processor_name = self.payment_processors.resolve_name("");
+        let payment_processor = self.payment_processors.get(&payment_processor_name)?;
+
+        let payment_processor_customer_id = ensure_customer(&self.customer_repository, &payment_processor_name, &payment_processor, identity, None).await;
+        if payment_processor_customer_id.is_empty() {
+            event!(Level::WARN, "No customer could be found or created for {}", identity.subject);
+            return Err(format!("No customer could be found or created for {}", identity.subject));
+        }
+
+        let checkout_session = match payment_processor.create_setup_checkout_session(payment_processor_customer_id, input.currency.clone(), checkout_mode.clone()).await {
+            Ok(checkout_session) => checkout_session,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when creating setup checkout session: {}", e);
+                return Err(format!("Error occurred when creating setup checkout session: {}", e));
+            }
+        };
+
+        Ok(CreateSetupCheckoutSessionResponseDto {
+            checkout_mode,
+            checkout_session_id: checkout_session.session_id,
+            checkout_session_url: checkout_session.session_url,
+            client_secret: checkout_session.client_secret,
+        })
+    }
+}
+
+pub struct GetOrderPaymentQueryHandler {
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+}
+
+impl GetOrderPaymentQueryHandler {
+    pub fn new(payment_repository: Arc<dyn PaymentRepository + Send + Sync>) -> Self {
+        GetOrderPaymentQueryHandler {
+            payment_repository,
+        }
+    }
+}
+
+impl QueryHandler<GetOrderPaymentQuery, GetOrderPaymentResponseDto, HandlerError> for GetOrderPaymentQueryHandler {
+    async fn handle(&self, input: Option<GetOrderPaymentQuery>) -> Result<GetOrderPaymentResponseDto, HandlerError> {
+        let query = match input {
+            Some(query) => query,
+            None => return Err(HandlerError::BadRequest(String::from("An order id is required")))
+        };
+
+        let payment = match self.payment_repository.read_active_by_order_id(&query.order_id).await? {
+            Some(payment) => payment,
+            None => {
+                event!(Level::WARN, "No active Payment found for order {}", query.order_id);
+                return Err(HandlerError::NotFound(format!("No active Payment found for order {}", query.order_id)));
+            }
+        };
+
+        // The client secret and session url let whoever holds them pay for the order, so they are only handed to its customer
+        if !query.is_admin && !query.customer.owns(&payment) {
+            event!(Level::WARN, "Subject {} cannot read the Payment of order {} of another customer", query.customer.subject, query.order_id);
+            return Err(HandlerError::Forbidden(format!("Order {} belongs to another customer", query.order_id)));
+        }
+
+        Ok(GetOrderPaymentResponseDto {
+            payment_id: payment.id,
+            order_id: payment.order_id,
+            payment_status: payment.status,
+            checkout_mode: payment.checkout_mode,
+            checkout_session_id: payment.payment_processor_checkout_session_id,
+            checkout_session_url: if payment.payment_processor_checkout_session_url.is_empty() { None } else { Some(payment.payment_processor_checkout_session_url) },
+            client_secret: if payment.payment_processor_checkout_session_client_secret.is_empty() { None } else { Some(payment.payment_processor_checkout_session_client_secret) },
+        })
+    }
+}
+
+pub struct SweepStalePaymentsCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>,
+}
+
+impl SweepStalePaymentsCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, payment_repository: Arc<dyn PaymentRepository + Send + Sync>, message_broker: Arc<dyn MessageBroker + Send + Sync>, advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>) -> Self {
+        SweepStalePaymentsCommandHandler {
+            payment_processors,
+            payment_repository,
+            message_broker,
+            advance_payment_saga_command_handler,
+        }
+    }
+}
+
+impl CommandHandler<SweepStalePaymentsCommand, SweepStalePaymentsResponseDto> for SweepStalePaymentsCommandHandler {
+    async fn handle(&self, input: &SweepStalePaymentsCommand) -> Result<SweepStalePaymentsResponseDto, String> {
+        let created_before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().saturating_sub(input.older_than_seconds);
+        let stale_payments = self.payment_repository.read_by_statuses_created_before(
+            vec![PaymentStatus::NEW.to_string(), PaymentStatus::SESSION_CREATED.to_string()],
+            created_before).await?;
+
+        let mut response = SweepStalePaymentsResponseDto {
+            checked: stale_payments.len() as u32,
+            transitioned: 0,
+            resumed_sagas: 0,
+        };
+
+        for payment in stale_payments {
+            let checkout_session = if payment.payment_processor_checkout_session_id.is_empty() {
+                None
+            } else {
+                match fetch_checkout_session(&self.payment_processors, &payment).await {
+                    Ok(checkout_session) => Some(checkout_session),
+                    Err(e) => {
+                        // One unreachable session should not stop the rest of the sweep, it is retried on the next run
+                        event!(Level::WARN, "Error occurred when retrieving checkout session for stale Payment {}: {}", payment.id, e);
+                        continue;
+                    }
+                }
+            };
+
+            let payment_id = payment.id.clone();
+            match save_checkout_session_outcome(&self.payment_repository, &self.message_broker, &self.advance_payment_saga_command_handler, payment, checkout_session.as_ref()).await {
+                Ok((_, Some(_))) => response.transitioned += 1,
+                Ok((_, None)) => {},
+                Err(e) => event!(Level::WARN, "Error occurred when saving stale Payment {}: {}", payment_id, e)
+            }
+        }
+
+        // Sagas that stopped compensating halfway are as stale as the payments by now
+        response.resumed_sagas = self.advance_payment_saga_command_handler.resume_compensations(created_before).await?;
+
+        Ok(response)
+    }
+}
+
+pub struct RelayOutboxCommandHandler {
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+}
+
+impl RelayOutboxCommandHandler {
+    pub fn new(payment_repository: Arc<dyn PaymentRepository + Send + Sync>, message_broker: Arc<dyn MessageBroker + Send + Sync>) -> Self {
+        RelayOutboxCommandHandler {
+            payment_repository,
+            message_broker,
+        }
+    }
+}
+
+// Publishes what was left in payment outboxes, e.g. while RabbitMQ was unreachable or when the instance stopped right
+// after saving
+impl CommandHandler<RelayOutboxCommand, RelayOutboxResponseDto> for RelayOutboxCommandHandler {
+    async fn handle(&self, input: &RelayOutboxCommand) -> Result<RelayOutboxResponseDto, String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        // Events are compared by the second they were recorded in, which this second has to be included in as well
+        let created_before = if input.older_than_seconds == 0 { now + 1 } else { now.saturating_sub(input.older_than_seconds) };
+        let payments = self.payment_repository.read_with_outbox_events_created_before(created_before).await?;
+
+        let mut response = RelayOutboxResponseDto {
+            payments: payments.len() as u32,
+            published: 0,
+            remaining: 0,
+        };
+
+        for payment in &payments {
+            let published = relay_outbox(&self.payment_repository, &self.message_broker, payment).await;
+            response.published += published;
+            response.remaining += payment.outbox.len() as u32 - published;
+        }
+
+        Ok(response)
+    }
+}
+
+pub struct ReconcilePaymentsCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    reconciliation_report_repository: Arc<dyn ReconciliationReportRepository + Send + Sync>,
+}
+
+impl ReconcilePaymentsCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, payment_repository: Arc<dyn PaymentRepository + Send + Sync>, reconciliation_report_repository: Arc<dyn ReconciliationReportRepository + Send + Sync>) -> Self {
+        ReconcilePaymentsCommandHandler {
+            payment_processors,
+            payment_repository,
+            reconciliation_report_repository,
+        }
+    }
+
+    // Transactions created through this service carry our payment id in their metadata, anything else is matched on the
+    // payment intent it belongs to
+    async fn resolve_payment(&self, transaction: &PaymentProcessorTransactionResponseDto) -> Result<Option<Payment>, String> {
+        if let Some(payment_id) = transaction.metadata.get("payment_id") {
+            if let Some(payment) = self.payment_repository.read(payment_id).await? {
+                return Ok(Some(payment));
+            }
+        }
+
+        match &transaction.payment_intent {
+            Some(payment_intent) => self.payment_repository.read_by_payment_processor_id(payment_intent).await,
+            None => Ok(None)
+        }
+    }
+}
+
+impl CommandHandler<ReconcilePaymentsCommand, ReconcilePaymentsResponseDto> for ReconcilePaymentsCommandHandler {
+    async fn handle(&self, input: &ReconcilePaymentsCommand) -> Result<ReconcilePaymentsResponseDto, String> {
+        if input.created_from > input.created_to {
+            return Err(format!("Reconciliation range start {} is after its end {}", input.created_from, input.created_to));
+        }
+
+        let payment_processor_name = self.payment_processors.resolve_name(input.payment_processor.as_deref().unwrap_or_default());
+        let payment_processor = self.payment_processors.get(&payment_processor_name)?;
+
+        let charges = payment_processor.list_charges(input.created_from, input.created_to).await?;
+        let refunds = payment_processor.list_refunds(input.created_from, input.created_to).await?;
+
+        let mut discrepancies = Vec::new();
+        let mut matched_payment_ids = HashSet::new();
+        let mut matched_refund_ids = HashSet::new();
+
+        for charge in charges.iter().filter(|charge| charge.status.as_deref() == Some("succeeded")) {
+            match self.resolve_payment(charge).await? {
+                Some(payment) => {
+                    if charge.amount != payment.total() {
+                        discrepancies.push(ReconciliationDiscrepancy {
+                            kind: ReconciliationDiscrepancyKind::MISMATCHED_AMOUNT.to_string(),
+                            payment_id: payment.id.clone(),
+                            payment_processor_transaction_id: charge.id.clone(),
+                            expected_amount: payment.total(),
+                            actual_amount: charge.amount,
+                        });
+                    }
+
+                    matched_payment_ids.insert(payment.id);
+                },
+                None => {
+                    discrepancies.push(ReconciliationDiscrepancy {
+                        kind: ReconciliationDiscrepancyKind::ORPHANED.to_string(),
+                        payment_id: String::new(),
+                        payment_processor_transaction_id: charge.id.clone(),
+                        expected_amount: 0,
+                        actual_amount: charge.amount,
+                    });
+                }
+            }
+        }
+
+        for processor_refund in refunds.iter().filter(|processor_refund| processor_refund.status.as_deref() != Some("failed") && processor_refund.status.as_deref() != Some("canceled")) {
+            let payment = self.resolve_payment(processor_refund).await?;
+            let local_refund = payment.as_ref().and_then(|payment| payment.refunds.iter().find(|refund| refund.payment_processor_refund_id == processor_refund.id).cloned());
+
+            match (payment, local_refund) {
+                (Some(payment), Some(local_refund)) => {
+                    if processor_refund.amount != local_refund.amount {
+                        discrepancies.push(ReconciliationDiscrepancy {
+                            kind: ReconciliationDiscrepancyKind::MISMATCHED_AMOUNT.to_string(),
+                            payment_id: payment.id.clone(),
+                            payment_processor_transaction_id: processor_refund.id.clone(),
+                            expected_amount: local_refund.amount,
+                            actual_amount: processor_refund.amount,
+                        });
+                    }
+
+                    matched_refund_ids.insert(local_refund.id);
+                },
+                (payment, _) => {
+                    discrepancies.push(ReconciliationDiscrepancy {
+                        kind: ReconciliationDiscrepancyKind::ORPHANED.to_string(),
+                        payment_id: payment.map(|payment| payment.id).unwrap_or_default(),
+                        payment_processor_transaction_id: processor_refund.id.clone(),
+                        expected_amount: 0,
+                        actual_amount: processor_refund.amount,
+                    });
+                }
+            }
+        }
+
+        let paid_statuses = [PaymentStatus::SUCCEEDED.to_string(), PaymentStatus::PARTIALLY_REFUNDED.to_string(), PaymentStatus::REFUNDED.to_string(), PaymentStatus::DISPUTED.to_string(), PaymentStatus::CHARGED_BACK.to_string()];
+        // Charges are listed by when they were made, so payments are picked by when they were paid rather than when their
+        // checkout started, which can be a whole session lifetime earlier
+        let paid_payments = self.payment_repository.read_paid_between(input.created_from, input.created_to).await?;
+
+        for payment in paid_payments.iter().filter(|payment| self.payment_processors.resolve_name(&payment.payment_processor) == payment_processor_name) {
+            if paid_statuses.contains(&payment.status) && !matched_payment_ids.contains(&payment.id) {
+                discrepancies.push(ReconciliationDiscrepancy {
+                    kind: ReconciliationDiscrepancyKind::MISSING.to_string(),
+                    payment_id: payment.id.clone(),
+                    payment_processor_transaction_id: payment.payment_processor_id.clone(),
+                    expected_amount: payment.total(),
+                    actual_amount: 0,
+                });
+            }
+        }
+
+        // Likewise refunds are picked by when they were made, whenever their payment was paid
+        let refunded_payments = self.payment_repository.read_with_refunds_created_between(input.created_from, input.created_to).await?;
+
+        for payment in refunded_payments.iter().filter(|payment| self.payment_processors.resolve_name(&payment.payment_processor) == payment_processor_name) {
+            for refund in payment.refunds.iter().filter(|refund| refund.created_at >= input.created_from && refund.created_at <= input.created_to) {
+                if !matched_refund_ids.contains(&refund.id) {
+                    discrepancies.push(ReconciliationDiscrepancy {
+                        kind: ReconciliationDiscrepancyKind::MISSING.to_string(),
+                        payment_id: payment.id.clone(),
+                        payment_processor_transaction_id: refund.payment_processor_refund_id.clone(),
+                        expected_amount: refund.amount,
+                        actual_amount: 0,
+                    });
+                }
+            }
+        }
+
+        let reconciliation_report = ReconciliationReport {
+            id: uuid::Uuid::new_v4().to_string(),
+            payment_processor: payment_processor_name,
+            created_from: input.created_from,
+            created_to: input.created_to,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            discrepancies,
+        };
+
+        if let Err(e) = self.reconciliation_report_repository.create(&reconciliation_report).await {
+            event!(Level::WARN, "Error occurred when saving ReconciliationReport {}: {}", reconciliation_report.id, e);
+            return Err(format!("Error occurred when saving ReconciliationReport {}: {}", reconciliation_report.id, e));
+        }
+
+        event!(Level::INFO, "Reconciliation {} of {} found {} discrepancies between {} and {}", reconciliation_report.id, reconciliation_report.payment_processor, reconciliation_report.discrepancies.len(), input.created_from, input.created_to);
+
+        Ok(ReconcilePaymentsResponseDto {
+            report_id: reconciliation_report.id,
+            payment_processor: reconciliation_report.payment_processor,
+            created_from: reconciliation_report.created_from,
+            created_to: reconciliation_report.created_to,
+            discrepancies: reconciliation_report.discrepancies.iter().map(ReconciliationDiscrepancyDto::from).collect(),
+        })
+    }
+}
+fn to_payment_saga_response(saga: PaymentSaga) -> PaymentSagaResponseDto {
+    PaymentSagaResponseDto {
+        order_id: saga.order_id,
+        status: saga.status,
+        payment_id: saga.payment_id,
+        inventory_reservation_id: saga.inventory_reservation_id,
+        failure_reason: if saga.failure_reason.is_empty() { None } else { Some(saga.failure_reason) },
+        steps: saga.steps,
+        created_at: saga.created_at,
+        updated_at: saga.updated_at,
+    }
+}
+
+// Tells the order's saga what happened to one of its payments, payments without an order have no saga. A saga that
+// cannot be advanced does not undo what happened to the payment.
+async fn advance_payment_saga(advance_payment_saga_command_handler: &Arc<AdvancePaymentSagaCommandHandler>, payment: &Payment, event: PaymentSagaEvent, detail: String) {
+    if payment.order_id.is_empty() {
+        return;
+    }
+
+    let advance_payment_saga_command = AdvancePaymentSagaCommand {
+        order_id: payment.order_id.clone(),
+        payment_id: Some(payment.id.clone()),
+        event,
+        detail,
+    };
+
+    if let Err(e) = advance_payment_saga_command_handler.handle(&advance_payment_saga_command).await {
+        event!(Level::WARN, "Error occurred when advancing PaymentSaga for order {} with Payment {}: {}", payment.order_id, payment.id, e);
+    }
+}
+
+async fn advance_payment_saga_for_status(advance_payment_saga_command_handler: &Arc<AdvancePaymentSagaCommandHandler>, payment: &Payment, status: &PaymentStatus) {
+    let event = match status {
+        PaymentStatus::SUCCEEDED => PaymentSagaEvent::PAYMENT_SUCCEEDED,
+        PaymentStatus::EXPIRED => PaymentSagaEvent::PAYMENT_EXPIRED,
+        PaymentStatus::CANCELLED => PaymentSagaEvent::PAYMENT_CANCELLED,
+        _ => return
+    };
+
+    advance_payment_saga(advance_payment_saga_command_handler, payment, event, String::new()).await;
+}
+
+pub struct AdvancePaymentSagaCommandHandler {
+    payment_saga_repository: Arc<dyn PaymentSagaRepository + Send + Sync>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+    refund_payment_command_handler: Arc<RefundPaymentCommandHandler>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+}
+
+impl AdvancePaymentSagaCommandHandler {
+    // How many times an event is applied to a saga that other events keep saving over before giving up
+    const MAX_ATTEMPTS: u32 = 5;
+
+    pub fn new(payment_saga_repository: Arc<dyn PaymentSagaRepository + Send + Sync>, payment_repository: Arc<dyn PaymentRepository + Send + Sync>, refund_payment_command_handler: Arc<RefundPaymentCommandHandler>, message_broker: Arc<dyn MessageBroker + Send + Sync>) -> Self {
+        AdvancePaymentSagaCommandHandler {
+            payment_saga_repository,
+            payment_repository,
+            refund_payment_command_handler,
+            message_broker,
+        }
+    }
+
+    // Moves the saga on for the event and returns the compensating actions it calls for, if any
+    fn transition(saga: &mut PaymentSaga, input: &AdvancePaymentSagaCommand) -> Vec<PaymentSagaCompensation> {
+        let payment_id = input.payment_id.clone().unwrap_or_default();
+        // An order's payment can be replaced by a new one, only the latest ending means the order cannot be paid for
+        let is_current_payment = saga.payment_id.is_empty() || payment_id.is_empty() || saga.payment_id == payment_id;
+
+        match input.event {
+            PaymentSagaEvent::ORDER_CREATED => Vec::new(),
+            PaymentSagaEvent::INVENTORY_RESERVED => {
+                // Reservations are named after the payment they were taken for
+                saga.payment_id = payment_id.clone();
+                saga.inventory_reservation_id = payment_id;
+                saga.status = PaymentSagaStatus::INVENTORY_RESERVED.to_string();
+                Vec::new()
+            },
+            PaymentSagaEvent::PAYMENT_SUCCEEDED => {
+                saga.payment_id = payment_id;
+                saga.status = PaymentSagaStatus::PAYMENT_SUCCEEDED.to_string();
+                Vec::new()
+            },
+            PaymentSagaEvent::FULFILLMENT_COMPLETED => {
+                saga.status = PaymentSagaStatus::COMPLETED.to_string();
+                Vec::new()
+            },
+            // A payment that ended gave its stock back itself, so only the order is left to cancel
+            PaymentSagaEvent::PAYMENT_EXPIRED | PaymentSagaEvent::PAYMENT_CANCELLED if !is_current_payment => Vec::new(),
+            PaymentSagaEvent::INVENTORY_UNAVAILABLE | PaymentSagaEvent::PAYMENT_EXPIRED | PaymentSagaEvent::PAYMENT_CANCELLED => {
+                if !payment_id.is_empty() {
+                    saga.payment_id = payment_id;
+                }
+                vec![PaymentSagaCompensation::CANCEL_ORDER]
+            },
+            PaymentSagaEvent::FULFILLMENT_FAILED => {
+                let mut compensations = Vec::new();
+                if !saga.payment_id.is_empty() {
+                    compensations.push(PaymentSagaCompensation::REFUND_PAYMENT);
+                }
+                if !saga.inventory_reservation_id.is_empty() {
+                    compensations.push(PaymentSagaCompensation::RELEASE_INVENTORY);
+                }
+                compensations.push(PaymentSagaCompensation::CANCEL_ORDER);
+                compensations
+            },
+        }
+    }
+
+    // Every pending compensating action is attempted even when an earlier one fails, each is recorded as a step of the
+    // saga. The saga is saved after every action, so when compensating is interrupted the actions that already succeeded
+    // are not run again once it is resumed.
+    async fn compensate(&self, mut saga: PaymentSaga) -> Result<PaymentSagaResponseDto, String> {
+        for compensation in saga.pending_compensations.clone() {
+            // Published under an id derived from the order, so a compensation that is resumed after publishing but before
+            // saving is recognised by consumers as the same event
+            let event_id = format!("compensation:{}:{}", saga.order_id, compensation);
+            let result = match compensation {
+                PaymentSagaCompensation::REFUND_PAYMENT => self.refund_payment(&saga).await,
+                PaymentSagaCompensation::RELEASE_INVENTORY => {
+                    let reservation_released_event = Event::InventoryReservationReleasedEvent {
+                        reservation_id: saga.inventory_reservation_id.clone(),
+                        order_id: saga.order_id.clone(),
+                    };
+                    self.message_broker.publish_event(&event_id, &reservation_released_event).await.map(|_| format!("Released reservation {}", saga.inventory_reservation_id))
+                },
+                PaymentSagaCompensation::CANCEL_ORDER => {
+                    let order_cancellation_requested_event = Event::OrderCancellationRequestedEvent {
+                        order_id: saga.order_id.clone(),
+                        reason: saga.failure_reason.clone(),
+                    };
+                    self.message_broker.publish_event(&event_id, &order_cancellation_requested_event).await.map(|_| String::from("Requested order cancellation"))
+                },
+            };
+
+            let detail = match result {
+                Ok(detail) => {
+                    saga.pending_compensations.retain(|pending| *pending != compensation);
+                    detail
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Compensation {:?} failed for PaymentSaga of order {}: {}", compensation, saga.order_id, e);
+                    e
+                }
+            };
+
+            saga.steps.push(PaymentSagaStep {
+                name: compensation.to_string(),
+                payment_id: saga.payment_id.clone(),
+                detail,
+                occurred_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            });
+            self.save_compensation(&mut saga).await?;
+        }
+
+        // The actions that failed stay pending on the saga, for whoever looks into it
+        saga.status = if saga.pending_compensations.is_empty() { PaymentSagaStatus::COMPENSATED.to_string() } else { PaymentSagaStatus::COMPENSATION_FAILED.to_string() };
+        self.save_compensation(&mut saga).await?;
+
+        event!(Level::INFO, "PaymentSaga for order {} is {}", saga.order_id, saga.status);
+        Ok(to_payment_saga_response(saga))
+    }
+
+    // Fails when the saga was saved by someone else since, which means another delivery is compensating it as well and
+    // this one stops
+    async fn save_compensation(&self, saga: &mut PaymentSaga) -> Result<(), String> {
+        saga.updated_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let expected_version = saga.version;
+        saga.version += 1;
+
+        if !self.payment_saga_repository.update(saga, expected_version).await? {
+            event!(Level::WARN, "PaymentSaga for order {} changed while compensating", saga.order_id);
+            return Err(format!("PaymentSaga for order {} changed while compensating", saga.order_id));
+        }
+        Ok(())
+    }
+
+    // Resumes sagas left compensating since before the given time, when the process compensating them stopped and no
+    // further event for the order arrived to resume them. Returns how many were resumed.
+    pub async fn resume_compensations(&self, updated_before: u64) -> Result<u32, String> {
+        let sagas = self.payment_saga_repository.read_compensating_updated_before(updated_before).await?;
+
+        let mut resumed = 0;
+        for saga in sagas {
+            let order_id = saga.order_id.clone();
+            match self.compensate(saga).await {
+                Ok(_) => resumed += 1,
+                // Left compensating, so it is picked up again on the next run
+                Err(e) => event!(Level::WARN, "Error occurred when resuming compensation of PaymentSaga for order {}: {}", order_id, e),
+            }
+        }
+        Ok(resumed)
+    }
+
+    async fn refund_payment(&self, saga: &PaymentSaga) -> Result<String, String> {
+        let payment = match self.payment_repository.read(&saga.payment_id).await? {
+            Some(payment) => payment,
+            None => return Err(format!("Payment {} not found", saga.payment_id))
+        };
+
+        if payment.status != PaymentStatus::SUCCEEDED.to_string() && payment.status != PaymentStatus::PARTIALLY_REFUNDED.to_string() {
+            return Ok(format!("Payment {} is {}, nothing to refund", payment.id, payment.status));
+        }
+
+        let refund_payment_command = RefundPaymentCommand {
+            payment_id: payment.id,
+            amount: None,
+            line_items: None,
+            reason: format!("Order {} could not be fulfilled", saga.order_id),
+            // An order is compensated at most once, however often the saga retries it
+            request_id: format!("compensation:{}", saga.order_id),
+        };
+
+        let response = self.refund_payment_command_handler.handle(&refund_payment_command).await.map_err(|e| e.to_string())?;
+        Ok(format!("Refunded {} as {}", response.amount, response.refund_id))
+    }
+}
+
+impl CommandHandler<AdvancePaymentSagaCommand, PaymentSagaResponseDto> for AdvancePaymentSagaCommandHandler {
+    async fn handle(&self, input: &AdvancePaymentSagaCommand) -> Result<PaymentSagaResponseDto, String> {
+        // Another event for the order may save the saga between reading and saving it, the event is then applied again
+        // on top of what that event did
+        for _ in 0..Self::MAX_ATTEMPTS {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+            let (mut saga, is_new) = match self.payment_saga_repository.read(&input.order_id).await? {
+                Some(saga) => (saga, false),
+                None => (PaymentSaga {
+                    order_id: input.order_id.clone(),
+                    status: PaymentSagaStatus::STARTED.to_string(),
+                    payment_id: String::new(),
+                    inventory_reservation_id: String::new(),
+                    failure_reason: String::new(),
+                    pending_compensations: Vec::new(),
+                    steps: Vec::new(),
+                    version: 0,
+                    created_at: now,
+                    updated_at: now,
+                }, true)
+            };
+
+            if saga.is_settled() {
+                event!(Level::INFO, "PaymentSaga for order {} is {}, ignoring {:?}", saga.order_id, saga.status, input.event);
+                return Ok(to_payment_saga_response(saga));
+            }
+
+            // A redelivered or later event finds the saga still compensating when that was interrupted
+            if saga.is_compensating() {
+                event!(Level::INFO, "PaymentSaga for order {} is compensating, resuming it instead of applying {:?}", saga.order_id, input.event);
+                return self.compensate(saga).await;
+            }
+
+            let compensations = Self::transition(&mut saga, input);
+            saga.steps.push(PaymentSagaStep {
+                name: input.event.to_string(),
+                payment_id: input.payment_id.clone().unwrap_or_default(),
+                detail: input.detail.clone(),
+                occurred_at: now,
+            });
+            if !compensations.is_empty() {
+                saga.status = PaymentSagaStatus::COMPENSATING.to_string();
+                saga.pending_compensations = compensations.clone();
+                saga.failure_reason = if input.detail.is_empty() { input.event.to_string() } else { format!("{}: {}", input.event, input.detail) };
+            }
+            saga.updated_at = now;
+            let expected_version = saga.version;
+            saga.version += 1;
+
+            let saved = if is_new {
+                self.payment_saga_repository.create(&saga).await?
+            } else {
+                self.payment_saga_repository.update(&saga, expected_version).await?
+            };
+
+            if !saved {
+                event!(Level::DEBUG, "PaymentSaga for order {} changed while applying {:?}, retrying", input.order_id, input.event);
+                continue;
+            }
+
+            if compensations.is_empty() {
+                return Ok(to_payment_saga_response(saga));
+            }
+
+            return self.compensate(saga).await;
+        }
+
+        event!(Level::WARN, "PaymentSaga for order {} kept changing while applying {:?}", input.order_id, input.event);
+        Err(format!("PaymentSaga for order {} kept changing while applying {:?}", input.order_id, input.event))
+    }
+}
+
+pub struct GetPaymentSagaQueryHandler {
+    payment_saga_repository: Arc<dyn PaymentSagaRepository + Send + Sync>,
+}
+
+impl GetPaymentSagaQueryHandler {
+    pub fn new(payment_saga_repository: Arc<dyn PaymentSagaRepository + Send + Sync>) -> Self {
+        GetPaymentSagaQueryHandler {
+            payment_saga_repository,
+        }
+    }
+}
+
+impl QueryHandler<GetPaymentSagaQuery, PaymentSagaResponseDto> for GetPaymentSagaQueryHandler {
+    async fn handle(&self, input: Option<GetPaymentSagaQuery>) -> Result<PaymentSagaResponseDto, String> {
+        let order_id = match input {
+            Some(query) => query.order_id,
+            None => return Err(String::from("An order id is required"))
+        };
+
+        match self.payment_saga_repository.read(&order_id).await? {
+            Some(saga) => Ok(to_payment_saga_response(saga)),
+            None => {
+                event!(Level::WARN, "No PaymentSaga found for order {}", order_id);
+                Err(format!("No PaymentSaga found for order {}", order_id))
+            }
+        }
+    }
+}
+
+pub struct SubmitDisputeEvidenceCommandHandler {
+    payment_processors: Arc<PaymentProcessorRegistry>,
+    payment_repository: Arc<dyn PaymentRepository + Send + Sync>,
+}
+
+impl SubmitDisputeEvidenceCommandHandler {
+    pub fn new(payment_processors: Arc<PaymentProcessorRegistry>, payment_repository: Arc<dyn PaymentRepository + Send + Sync>) -> Self {
+        SubmitDisputeEvidenceCommandHandler {
+            payment_processors,
+            payment_repository,
+        }
+    }
+}
+
+impl CommandHandler<SubmitDisputeEvidenceCommand, SubmitDisputeEvidenceResponseDto> for SubmitDisputeEvidenceCommandHandler {
+    async fn handle(&self, input: &SubmitDisputeEvidenceCommand) -> Result<SubmitDisputeEvidenceResponseDto, String> {
+        let payment = match self.payment_repository.read_by_dispute_id(&input.dispute_id).await? {
+            Some(payment) => payment,
+            None => {
+                event!(Level::WARN, "Dispute {} not found", input.dispute_id);
+                return Err(format!("Dispute {} not found", input.dispute_id));
+            }
+        };
+
+        if payment.disputes.iter().any(|dispute| dispute.id == input.dispute_id && dispute.is_closed()) {
+            event!(Level::WARN, "Dispute {} is closed and no longer takes evidence", input.dispute_id);
+            return Err(format!("Dispute {} is closed and no longer takes evidence", input.dispute_id));
+        }
+
+        if input.evidence.is_empty() && input.files.is_empty() {
+            event!(Level::WARN, "No evidence given for dispute {}", input.dispute_id);
+            return Err(format!("No evidence given for dispute {}", input.dispute_id));
+        }
+
+        let mut files = Vec::new();
+        for file in &input.files {
+            match BASE64_STANDARD.decode(&file.content) {
+                Ok(content) => files.push(PaymentProcessorDisputeEvidenceFileDto {
+                    evidence_type: file.evidence_type.clone(),
+                    file_name: file.file_name.clone(),
+                    content_type: file.content_type.clone(),
+                    content,
+                }),
+                Err(e) => {
+                    event!(Level::WARN, "File {} for dispute {} is not valid base64: {}", file.file_name, input.dispute_id, e);
+                    return Err(format!("File {} for dispute {} is not valid base64: {}", file.file_name, input.dispute_id, e));
+                }
+            }
+        }
+
+        let payment_processor_dispute = match self.payment_processors.get(&payment.payment_processor)?.submit_dispute_evidence(input.dispute_id.clone(), input.evidence.clone(), files, !input.draft).await {
+            Ok(payment_processor_dispute) => payment_processor_dispute,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when submitting evidence for dispute {}: {}", input.dispute_id, e);
+                return Err(format!("Error occurred when submitting evidence for dispute {}: {}", input.dispute_id, e));
+            }
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let payment_id = payment.id.clone();
+        let payment = match save_payment(&self.payment_repository, payment, |payment| {
+            if let Some(dispute) = payment.disputes.iter_mut().find(|dispute| dispute.id == input.dispute_id) {
+                dispute.payment_processor_status = payment_processor_dispute.status.clone();
+                dispute.updated_at = now;
+                if !input.draft {
+                    dispute.evidence_submitted_at = now;
+                }
+            }
+            Ok(true)
+        }).await {
+            Ok(payment) => payment,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when saving dispute {} for Payment {}: {}", input.dispute_id, payment_id, e);
+                return Err(format!("Error occurred when saving dispute {} for Payment {}: {}", input.dispute_id, payment_id, e));
+            }
+        };
+
+        Ok(SubmitDisputeEvidenceResponseDto {
+            dispute_id: input.dispute_id.clone(),
+            payment_id: payment.id,
+            dispute_status: payment_processor_dispute.status,
+            evidence_submitted: !input.draft,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::dtos::PaymentProcessorDisputeEvidenceDetailsDto;
+
+    use super::*;
+
+    fn payment(status: PaymentStatus) -> Payment {
+        serde_json::from_value(json!({
+            "id": "payment-1",
+            "order_id": "order-1",
+            "line_items": [
+                {"product_id": "product-1", "quantity": 3, "price": 1000, "tax_amount": 300},
+                {"product_id": "product-2", "quantity": 1, "price": 500}
+            ],
+            "status": status.to_string(),
+            "payment_processor": "fake",
+            "payment_processor_checkout_session_id": "session-1",
+            "payment_processor_checkout_session_url": "",
+            "payment_processor_id": "",
+            "payment_processor_status": "",
+            "customer_email": "customer@example.com"
+        })).unwrap()
+    }
+
+    fn dispute(id: &str, status: &str) -> PaymentProcessorDisputeResponseDto {
+        PaymentProcessorDisputeResponseDto {
+            id: String::from(id),
+            amount: 3500,
+            currency: String::from("usd"),
+            reason: String::from("fraudulent"),
+            status: String::from(status),
+            payment_intent: None,
+            evidence_details: Some(PaymentProcessorDisputeEvidenceDetailsDto { due_by: Some(2000) }),
+            created: 100,
+        }
+    }
+
+    fn refund_line_item(product_id: &str, quantity: u32) -> RefundLineItemRequestDto {
+        RefundLineItemRequestDto {
+            product_id: String::from(product_id),
+            quantity,
+        }
+    }
+
+    #[test]
+    fn customer_owns_payment_without_subject_only_by_verified_email() {
+        let payment = payment(PaymentStatus::SUCCEEDED);
+        let mut customer = CustomerIdentity {
+            subject: String::from("auth0|customer"),
+            email: Some(String::from("Customer@example.com")),
+            email_verified: false,
+            name: None,
+        };
+
+        assert!(!customer.owns(&payment));
+
+        customer.email_verified = true;
+        assert!(customer.owns(&payment));
+    }
+
+    #[test]
+    fn refund_line_items_takes_price_and_share_of_tax_from_purchase() {
+        let payment = payment(PaymentStatus::SUCCEEDED);
+
+        let line_items = RefundPaymentCommandHandler::refund_line_items(&payment, &[refund_line_item("product-1", 2)]).unwrap();
+
+        assert_eq!(line_items.len(), 1);
+        assert_eq!(line_items[0].quantity, 2);
+        assert_eq!(line_items[0].price, 1000);
+        assert_eq!(line_items[0].tax_amount, 200);
+    }
+
+    #[test]
+    fn refund_amount_takes_positive_amounts_in_whole_cents() {
+        assert_eq!(RefundPaymentCommandHandler::refund_amount(19.99), Some(1999));
+        assert_eq!(RefundPaymentCommandHandler::refund_amount(5.0), Some(500));
+        for amount in [0.0, -1.0, 19.995, f64::NAN, f64::INFINITY] {
+            assert_eq!(RefundPaymentCommandHandler::refund_amount(amount), None);
+        }
+    }
+
+    #[test]
+    fn refund_line_items_rejects_product_not_purchased() {
+        let payment = payment(PaymentStatus::SUCCEEDED);
+
+        assert!(RefundPaymentCommandHandler::refund_line_items(&payment, &[refund_line_item("product-3", 1)]).is_err());
+    }
+
+    #[test]
+    fn refund_line_items_rejects_zero_quantity() {
+        let payment = payment(PaymentStatus::SUCCEEDED);
+
+        assert!(RefundPaymentCommandHandler::refund_line_items(&payment, &[refund_line_item("product-1", 0)]).is_err());
+    }
+
+    #[test]
+    fn refund_line_items_rejects_units_already_refunded() {
+        let mut payment = payment(PaymentStatus::PARTIALLY_REFUNDED);
+        let first_refund = RefundPaymentCommandHandler::refund_line_items(&payment, &[refund_line_item("product-1", 2)]).unwrap();
+        payment.refunds.push(Refund {
+            id: String::from("refund-1"),
+            amount: 2000,
+            line_items: first_refund,
+            reason: String::new(),
+            payment_processor_refund_id: String::new(),
+            payment_processor_status: String::new(),
+            created_at: 0,
+        });
+
+        assert!(RefundPaymentCommandHandler::refund_line_items(&payment, &[refund_line_item("product-1", 2)]).is_err());
+        assert_eq!(RefundPaymentCommandHandler::refund_line_items(&payment, &[refund_line_item("product-1", 1)]).unwrap()[0].quantity, 1);
+    }
+
+    #[test]
+    fn apply_dispute_records_new_dispute_and_disputes_payment() {
+        let mut payment = payment(PaymentStatus::SUCCEEDED);
+
+        assert!(HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "needs_response"), 1000));
+
+        assert_eq!(payment.status, PaymentStatus::DISPUTED.to_string());
+        assert_eq!(payment.disputes.len(), 1);
+        assert_eq!(payment.disputes[0].amount, 3500);
+        assert_eq!(payment.disputes[0].evidence_due_by, 2000);
+        assert_eq!(payment.disputes[0].payment_status_before_dispute, PaymentStatus::SUCCEEDED.to_string());
+    }
+
+    #[test]
+    fn apply_dispute_updates_known_dispute() {
+        let mut payment = payment(PaymentStatus::SUCCEEDED);
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "needs_response"), 1000);
+
+        assert!(!HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "under_review"), 1100));
+
+        assert_eq!(payment.status, PaymentStatus::DISPUTED.to_string());
+        assert_eq!(payment.disputes.len(), 1);
+        assert_eq!(payment.disputes[0].payment_processor_status, "under_review");
+        assert_eq!(payment.disputes[0].closed_at, 0);
+    }
+
+    #[test]
+    fn apply_dispute_restores_status_once_dispute_is_won() {
+        let mut payment = payment(PaymentStatus::PARTIALLY_REFUNDED);
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "needs_response"), 1000);
+
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "won"), 1200);
+
+        assert_eq!(payment.status, PaymentStatus::PARTIALLY_REFUNDED.to_string());
+        assert_eq!(payment.disputes[0].closed_at, 1200);
+    }
+
+    #[test]
+    fn apply_dispute_charges_back_once_dispute_is_lost() {
+        let mut payment = payment(PaymentStatus::SUCCEEDED);
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "needs_response"), 1000);
+
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "lost"), 1200);
+
+        assert_eq!(payment.status, PaymentStatus::CHARGED_BACK.to_string());
+        match Event::for_payment_status(&payment, &PaymentStatus::CHARGED_BACK) {
+            Some(Event::PaymentChargedBackEvent { dispute_id, amount, .. }) => {
+                assert_eq!(dispute_id, "dispute-1");
+                assert_eq!(amount, 35.0);
+            },
+            _ => panic!("Expected a PaymentChargedBackEvent")
+        }
+    }
+
+    #[test]
+    fn apply_dispute_keeps_payment_disputed_while_any_dispute_is_open() {
+        let mut payment = payment(PaymentStatus::SUCCEEDED);
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "needs_response"), 1000);
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-2", "needs_response"), 1100);
+
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-1", "won"), 1200);
+        assert_eq!(payment.status, PaymentStatus::DISPUTED.to_string());
+        assert_eq!(payment.disputes[1].payment_status_before_dispute, PaymentStatus::SUCCEEDED.to_string());
+
+        HandlePaymentProcessorWebhookCommandHandler::apply_dispute(&mut payment, &dispute("dispute-2", "won"), 1300);
+        assert_eq!(payment.status, PaymentStatus::SUCCEEDED.to_string());
+    }
+}
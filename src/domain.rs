@@ -1,29 +1,944 @@
+use std::{fmt, time::{SystemTime, UNIX_EPOCH}};
+
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::events::Event;
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct LineItem {
     pub product_id: String,
     pub quantity: u32,
-    pub price: f32
+    #[serde(deserialize_with = "deserialize_minor_units")]
+    pub price: i64,
+    #[serde(default)]
+    pub tax_code: String,
+    // Tax on the whole line rather than per unit
+    #[serde(default, deserialize_with = "deserialize_minor_units")]
+    pub tax_amount: i64,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Payment {
     pub id: String,
+    #[serde(default)]
+    pub order_id: String,
     pub line_items: Vec<LineItem>,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    #[serde(default = "default_checkout_mode")]
+    pub checkout_mode: String,
     pub status: String,
     pub payment_processor: String,
     pub payment_processor_checkout_session_id: String,
     pub payment_processor_checkout_session_url: String,
+    #[serde(default)]
+    pub payment_processor_checkout_session_client_secret: String,
+    #[serde(default)]
+    pub payment_processor_checkout_session_expires_at: u64,
     pub payment_processor_id: String,
     pub payment_processor_status: String,
+    #[serde(default)]
+    pub payment_processor_customer_id: String,
+    // The token subject of the shopper who checked out, empty for payments opened from an order event
+    #[serde(default)]
+    pub customer_subject: String,
+    pub customer_email: String,
+    #[serde(default)]
+    pub discount: Option<Discount>,
+    // Set when the payment processor works out the tax during checkout, the line items' tax is only known once paid
+    #[serde(default)]
+    pub automatic_tax: bool,
+    // Only set when something in the payment has to be shipped
+    #[serde(default)]
+    pub shipping: Option<Shipping>,
+    // Held by the inventory service for the payment's line items until it is paid for or released, empty when no stock
+    // was reserved
+    #[serde(default)]
+    pub inventory_reservation_id: String,
+    #[serde(default)]
+    pub refunds: Vec<Refund>,
+    #[serde(default)]
+    pub disputes: Vec<Dispute>,
+    // Events announcing changes to the payment, saved along with them and removed once published
+    #[serde(default)]
+    pub outbox: Vec<OutboxEvent>,
+    // Bumped on every save, so a writer never saves over a change it did not read
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub created_at: u64,
+    // When the payment was found to be paid, which is what its charge is reconciled by. Absent on payments paid before
+    // it was recorded, and 0 until paid.
+    #[serde(default)]
+    pub paid_at: u64,
+}
+
+// Payments recorded before currencies were selectable were all charged in usd
+pub fn default_currency() -> String {
+    String::from("usd")
+}
+
+// Amounts are kept in the currency's minor unit, cents for every currency the shop sells in, and only converted from and
+// to decimal amounts where requests, responses and events carry them
+pub fn to_minor_units(amount: f32) -> i64 {
+    (amount as f64 * 100.0).round() as i64
+}
+
+pub fn to_decimal_amount(amount: i64) -> f32 {
+    (amount as f64 / 100.0) as f32
+}
+
+// Amounts saved before they were kept in minor units were saved as decimal amounts, which are read as such
+fn deserialize_minor_units<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where D: Deserializer<'de> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SavedAmount {
+        MinorUnits(i64),
+        Decimal(f64),
+    }
+
+    Ok(match SavedAmount::deserialize(deserializer)? {
+        SavedAmount::MinorUnits(amount) => amount,
+        SavedAmount::Decimal(amount) => (amount * 100.0).round() as i64,
+    })
+}
+
+// The shipping options are configured with decimal amounts
+fn deserialize_decimal_amount<'de, D>(deserializer: D) -> Result<i64, D::Error>
+where D: Deserializer<'de> {
+    Ok(to_minor_units(f32::deserialize(deserializer)?))
+}
+
+// Payments recorded before the checkout mode was selectable all used custom checkout
+pub fn default_checkout_mode() -> String {
+    CheckoutMode::CUSTOM.to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Refund {
+    pub id: String,
+    #[serde(deserialize_with = "deserialize_minor_units")]
+    pub amount: i64,
+    pub line_items: Vec<LineItem>,
+    pub reason: String,
+    pub payment_processor_refund_id: String,
+    pub payment_processor_status: String,
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+// A chargeback raised by the customer's bank, kept in step with the payment processor's dispute
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Dispute {
+    pub id: String,
+    #[serde(deserialize_with = "deserialize_minor_units")]
+    pub amount: i64,
+    pub currency: String,
+    pub reason: String,
+    pub payment_processor_status: String,
+    // When evidence has to be submitted by, 0 when the processor gave no deadline
+    #[serde(default)]
+    pub evidence_due_by: u64,
+    #[serde(default)]
+    pub evidence_submitted_at: u64,
+    // The payment's status when the dispute was opened, it goes back to it if the dispute is won
+    pub payment_status_before_dispute: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub closed_at: u64,
+}
+
+impl Dispute {
+    pub fn is_closed(&self) -> bool {
+        ["won", "lost", "warning_closed", "prevented"].contains(&self.payment_processor_status.as_str())
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.payment_processor_status == "lost"
+    }
+}
+
+// An event waiting in a payment's outbox, its id stays the same however often publishing it is retried
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutboxEvent {
+    pub event_id: String,
+    pub event: Event,
+    pub created_at: u64,
+}
+
+impl Payment {
+    pub fn record_event(&mut self, event: Event) {
+        self.outbox.push(OutboxEvent {
+            event_id: uuid::Uuid::new_v4().to_string(),
+            event,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        });
+    }
+
+    pub fn subtotal(&self) -> i64 {
+        self.line_items.iter().map(|line_item| line_item.price * line_item.quantity as i64).sum()
+    }
+
+    pub fn tax_total(&self) -> i64 {
+        self.line_items.iter().map(|line_item| line_item.tax_amount).sum()
+    }
+
+    // Nothing until the customer has picked one of the offered rates at checkout
+    pub fn shipping_total(&self) -> i64 {
+        self.shipping.as_ref().and_then(|shipping| shipping.selected_rate.as_ref()).map(|rate| rate.amount).unwrap_or(0)
+    }
+
+    pub fn discount_total(&self) -> i64 {
+        self.discount.as_ref().map(|discount| discount.amount).unwrap_or(0)
+    }
+
+    // What the customer is charged, tax is worked out on the discounted amount and added on top along with shipping
+    pub fn total(&self) -> i64 {
+        (self.subtotal() - self.discount_total()).max(0) + self.tax_total() + self.shipping_total()
+    }
+
+    pub fn refunded_total(&self) -> i64 {
+        self.refunds.iter().map(|refund| refund.amount).sum()
+    }
+
+    // Applies the checkout session state reported by the payment processor, only payments still awaiting checkout can
+    // transition so a later refund or cancellation is never overwritten. Returns the new status when it changed.
+    pub fn reconcile_checkout_session(&mut self, session_status: &str, session_payment_status: &str) -> Option<PaymentStatus> {
+        if self.status != PaymentStatus::NEW.to_string() && self.status != PaymentStatus::SESSION_CREATED.to_string() {
+            return None;
+        }
+
+        let new_status = match session_status {
+            "complete" if session_payment_status == "paid" || session_payment_status == "no_payment_required" => PaymentStatus::SUCCEEDED,
+            "expired" => PaymentStatus::EXPIRED,
+            _ => return None
+        };
+
+        if let PaymentStatus::SUCCEEDED = new_status {
+            self.paid_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        }
+        self.status = new_status.to_string();
+        Some(new_status)
+    }
+
+    // The checkout session's status as open, complete or expired, however the payment processor words it
+    pub fn checkout_session_status(&self) -> &'static str {
+        if self.status == PaymentStatus::NEW.to_string() || self.status == PaymentStatus::SESSION_CREATED.to_string() {
+            "open"
+        } else if self.status == PaymentStatus::EXPIRED.to_string() || self.status == PaymentStatus::CANCELLED.to_string() {
+            "expired"
+        } else {
+            "complete"
+        }
+    }
+
+    pub fn refunded_quantity(&self, product_id: &str) -> u32 {
+        self.refunds.iter()
+            .flat_map(|refund| refund.line_items.iter())
+            .filter(|line_item| line_item.product_id == product_id)
+            .map(|line_item| line_item.quantity)
+            .sum()
+    }
 }
 
 #[derive(Debug)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum PaymentStatus {
     NEW,
+    SESSION_CREATED,
+    SUCCEEDED,
+    EXPIRED,
+    PARTIALLY_REFUNDED,
+    REFUNDED,
+    CANCELLED,
+    DISPUTED,
+    // A dispute was lost and the money taken back by the customer's bank
+    CHARGED_BACK,
+}
+
+impl fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentStatus::NEW => write!(f, "New"),
+            PaymentStatus::SESSION_CREATED => write!(f, "SessionCreated"),
+            PaymentStatus::SUCCEEDED => write!(f, "Succeeded"),
+            PaymentStatus::EXPIRED => write!(f, "Expired"),
+            PaymentStatus::PARTIALLY_REFUNDED => write!(f, "PartiallyRefunded"),
+            PaymentStatus::REFUNDED => write!(f, "Refunded"),
+            PaymentStatus::CANCELLED => write!(f, "Cancelled"),
+            PaymentStatus::DISPUTED => write!(f, "Disputed"),
+            PaymentStatus::CHARGED_BACK => write!(f, "ChargedBack"),
+        }
+    }
+}
+
+// A catalog product as announced by its ProductCreated event, kept for what checkout needs to know about it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Product {
+    pub id: String,
+    pub name: String,
+    // The unit price checkouts charge, products announced before prices were kept have none and cannot be bought
+    #[serde(default, deserialize_with = "deserialize_minor_units")]
+    pub price: i64,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    // Only set for recurring products, billed every interval_count intervals
+    #[serde(default)]
+    pub interval: Option<String>,
+    #[serde(default)]
+    pub interval_count: Option<u32>,
+    #[serde(default)]
+    pub tax_code: String,
+    // Physical goods are shipped, everything else is delivered without an address
+    #[serde(default)]
+    pub shippable: bool,
+    #[serde(default)]
+    pub weight_grams: u32,
+    pub created_at: u64,
+}
+
+// How a payment's goods are shipped: the countries the address can be in and the rates offered for them, then once checkout
+// completes the rate the customer picked and where to
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Shipping {
+    pub allowed_countries: Vec<String>,
+    pub offered_rates: Vec<ShippingRate>,
+    #[serde(default)]
+    pub selected_rate: Option<ShippingRate>,
+    #[serde(default)]
+    pub address: Option<ShippingAddress>,
 }
 
-impl ToString for PaymentStatus {
-    fn to_string(&self) -> String {
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ShippingRate {
+    pub shipping_option_id: String,
+    pub display_name: String,
+    #[serde(deserialize_with = "deserialize_minor_units")]
+    pub amount: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ShippingAddress {
+    pub name: String,
+    pub line1: String,
+    pub line2: String,
+    pub city: String,
+    pub state: String,
+    pub postal_code: String,
+    pub country: String,
+}
+
+// The shipping options configured for the shop, priced for each cart at checkout
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ShippingConfiguration {
+    // ISO country codes an address may be collected in
+    pub allowed_countries: Vec<String>,
+    pub options: Vec<ShippingOption>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ShippingOption {
+    pub id: String,
+    pub display_name: String,
+    // Flat, WeightBased or FreeOverThreshold
+    pub rate_type: String,
+    // The flat fee, the base fee a weight-based rate adds to, or the fee below a free shipping threshold
+    #[serde(default, deserialize_with = "deserialize_decimal_amount")]
+    pub amount: i64,
+    #[serde(default, deserialize_with = "deserialize_decimal_amount")]
+    pub amount_per_kg: i64,
+    #[serde(default, deserialize_with = "deserialize_decimal_amount")]
+    pub free_over_amount: i64,
+    // Options limited to some countries are only offered once the shipping country is known, none means anywhere
+    #[serde(default)]
+    pub countries: Vec<String>,
+}
+
+impl ShippingOption {
+    pub fn ships_to(&self, country: Option<&str>) -> bool {
+        self.countries.is_empty() || country.is_some_and(|country| self.countries.iter().any(|allowed_country| allowed_country.eq_ignore_ascii_case(country)))
+    }
+
+    pub fn amount(&self, order_amount: i64, weight_grams: u32) -> i64 {
+        if self.rate_type == ShippingRateType::WEIGHT_BASED.to_string() {
+            // Rounded to the nearest cent like any other share of an amount
+            self.amount + (self.amount_per_kg as f64 * weight_grams as f64 / 1000.0).round() as i64
+        } else if self.rate_type == ShippingRateType::FREE_OVER_THRESHOLD.to_string() && order_amount >= self.free_over_amount {
+            0
+        } else {
+            self.amount
+        }
+    }
+}
+
+#[derive(Debug)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum ShippingRateType {
+    FLAT,
+    WEIGHT_BASED,
+    FREE_OVER_THRESHOLD,
+}
+
+impl fmt::Display for ShippingRateType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            PaymentStatus::NEW => String::from("New")
+            ShippingRateType::FLAT => write!(f, "Flat"),
+            ShippingRateType::WEIGHT_BASED => write!(f, "WeightBased"),
+            ShippingRateType::FREE_OVER_THRESHOLD => write!(f, "FreeOverThreshold"),
         }
     }
-}
\ No newline at end of file
+}
+
+// The promotion a payment was checked out with and what it took off the subtotal
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Discount {
+    pub promotion_code: String,
+    #[serde(deserialize_with = "deserialize_minor_units")]
+    pub amount: i64,
+    // Who redeemed it, the shopper's subject or else the customer email, counted against the per-customer limit
+    #[serde(default)]
+    pub redeemed_by: String,
+    #[serde(default)]
+    pub payment_processor_coupon_id: String,
+}
+
+// A promotion code shoppers can enter at checkout. Limits of 0 and an expires_at of 0 mean unlimited.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Promotion {
+    pub code: String,
+    pub discount_type: String,
+    #[serde(default)]
+    pub percent_off: f32,
+    // Amount off promotions only apply to checkouts in their currency
+    #[serde(default, deserialize_with = "deserialize_minor_units")]
+    pub amount_off: i64,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    #[serde(default, deserialize_with = "deserialize_minor_units")]
+    pub minimum_order_amount: i64,
+    #[serde(default)]
+    pub max_redemptions: u32,
+    #[serde(default)]
+    pub max_redemptions_per_customer: u32,
+    #[serde(default)]
+    pub expires_at: u64,
+    pub active: bool,
+    // The checkouts holding one of the promotion's limited redemptions, only kept for promotions with a limit. Held on
+    // the promotion so a redemption is checked against the limits and taken in one update.
+    #[serde(default)]
+    pub redemptions: Vec<PromotionRedemption>,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PromotionRedemption {
+    pub payment_id: String,
+    pub redeemed_by: String,
+    pub redeemed_at: u64,
+}
+
+impl Promotion {
+    pub fn has_redemption_limit(&self) -> bool {
+        self.max_redemptions > 0 || self.max_redemptions_per_customer > 0
+    }
+
+    // What the promotion takes off a subtotal, never more than the subtotal itself
+    pub fn discount_amount(&self, subtotal: i64) -> i64 {
+        let amount = if self.discount_type == DiscountType::PERCENT_OFF.to_string() {
+            (subtotal as f64 * self.percent_off as f64 / 100.0).round() as i64
+        } else {
+            self.amount_off
+        };
+
+        amount.min(subtotal)
+    }
+}
+
+#[derive(Debug)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum DiscountType {
+    PERCENT_OFF,
+    AMOUNT_OFF,
+}
+
+impl fmt::Display for DiscountType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscountType::PERCENT_OFF => write!(f, "PercentOff"),
+            DiscountType::AMOUNT_OFF => write!(f, "AmountOff"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SubscriptionLineItem {
+    pub product_id: String,
+    pub quantity: u32,
+    #[serde(deserialize_with = "deserialize_minor_units")]
+    pub price: i64,
+    // One of day, week, month or year, billed every interval_count intervals
+    pub interval: String,
+    pub interval_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Subscription {
+    pub id: String,
+    pub line_items: Vec<SubscriptionLineItem>,
+    pub currency: String,
+    pub checkout_mode: String,
+    pub status: String,
+    pub payment_processor: String,
+    pub payment_processor_checkout_session_id: String,
+    pub payment_processor_checkout_session_url: String,
+    pub payment_processor_checkout_session_client_secret: String,
+    pub payment_processor_checkout_session_expires_at: u64,
+    pub payment_processor_subscription_id: String,
+    pub payment_processor_customer_id: String,
+    pub payment_processor_status: String,
+    // The token subject of the shopper who subscribed, empty for subscriptions saved before it was recorded
+    #[serde(default)]
+    pub customer_subject: String,
+    pub customer_email: String,
+    pub current_period_end: u64,
+    pub cancel_at_period_end: bool,
+    pub created_at: u64,
+}
+
+impl Subscription {
+    // Applies the subscription state reported by the payment processor. Cancelled and expired subscriptions never come
+    // back, so a late delivery cannot revive them. Returns the new status when it changed.
+    pub fn reconcile_subscription(&mut self, payment_processor_status: &str, paused: bool) -> Option<SubscriptionStatus> {
+        if self.status == SubscriptionStatus::CANCELLED.to_string() || self.status == SubscriptionStatus::EXPIRED.to_string() {
+            return None;
+        }
+
+        let new_status = match payment_processor_status {
+            "canceled" => SubscriptionStatus::CANCELLED,
+            "incomplete_expired" => SubscriptionStatus::EXPIRED,
+            _ if paused => SubscriptionStatus::PAUSED,
+            "paused" => SubscriptionStatus::PAUSED,
+            "active" | "trialing" => SubscriptionStatus::ACTIVE,
+            "past_due" | "unpaid" => SubscriptionStatus::PAST_DUE,
+            "incomplete" => SubscriptionStatus::PENDING,
+            _ => return None
+        };
+
+        if self.status == new_status.to_string() {
+            return None;
+        }
+
+        self.status = new_status.to_string();
+        Some(new_status)
+    }
+
+    // A paid invoice brings a pending or past due subscription back to active, a failed one puts an active subscription
+    // past due. Returns the new status when it changed.
+    pub fn reconcile_invoice(&mut self, paid: bool) -> Option<SubscriptionStatus> {
+        let new_status = if paid && (self.status == SubscriptionStatus::PENDING.to_string() || self.status == SubscriptionStatus::PAST_DUE.to_string()) {
+            SubscriptionStatus::ACTIVE
+        } else if !paid && self.status == SubscriptionStatus::ACTIVE.to_string() {
+            SubscriptionStatus::PAST_DUE
+        } else {
+            return None;
+        };
+
+        self.status = new_status.to_string();
+        Some(new_status)
+    }
+}
+
+#[derive(Debug)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum SubscriptionStatus {
+    PENDING,
+    ACTIVE,
+    PAST_DUE,
+    PAUSED,
+    CANCELLED,
+    EXPIRED,
+}
+
+impl fmt::Display for SubscriptionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionStatus::PENDING => write!(f, "Pending"),
+            SubscriptionStatus::ACTIVE => write!(f, "Active"),
+            SubscriptionStatus::PAST_DUE => write!(f, "PastDue"),
+            SubscriptionStatus::PAUSED => write!(f, "Paused"),
+            SubscriptionStatus::CANCELLED => write!(f, "Cancelled"),
+            SubscriptionStatus::EXPIRED => write!(f, "Expired"),
+        }
+    }
+}
+
+// A signed-in shopper's customer record with one payment processor, so returning shoppers are recognised there
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Customer {
+    pub subject: String,
+    pub payment_processor: String,
+    pub payment_processor_customer_id: String,
+    pub email: String,
+    pub name: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// How the customer goes through checkout: redirected to the processor's hosted page, with the processor's form embedded
+// in our page, or with our own form built on the processor's elements. The latter two are driven by a client secret.
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum CheckoutMode {
+    HOSTED,
+    EMBEDDED,
+    CUSTOM,
+}
+
+impl fmt::Display for CheckoutMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckoutMode::HOSTED => write!(f, "hosted"),
+            CheckoutMode::EMBEDDED => write!(f, "embedded"),
+            CheckoutMode::CUSTOM => write!(f, "custom"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReconciliationReport {
+    pub id: String,
+    #[serde(default)]
+    pub payment_processor: String,
+    pub created_from: u64,
+    pub created_to: u64,
+    pub created_at: u64,
+    pub discrepancies: Vec<ReconciliationDiscrepancy>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReconciliationDiscrepancy {
+    pub kind: String,
+    pub payment_id: String,
+    pub payment_processor_transaction_id: String,
+    #[serde(deserialize_with = "deserialize_minor_units")]
+    pub expected_amount: i64,
+    #[serde(deserialize_with = "deserialize_minor_units")]
+    pub actual_amount: i64,
+}
+
+#[derive(Debug)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum ReconciliationDiscrepancyKind {
+    // Recorded locally but never seen by the payment processor
+    MISSING,
+    MISMATCHED_AMOUNT,
+    // Seen by the payment processor but not recorded locally
+    ORPHANED,
+}
+
+impl fmt::Display for ReconciliationDiscrepancyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReconciliationDiscrepancyKind::MISSING => write!(f, "Missing"),
+            ReconciliationDiscrepancyKind::MISMATCHED_AMOUNT => write!(f, "MismatchedAmount"),
+            ReconciliationDiscrepancyKind::ORPHANED => write!(f, "Orphaned"),
+        }
+    }
+}
+
+// A response is only present once the original request finished, until then the key is held by the in-flight request
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IdempotencyKey {
+    pub subject: String,
+    pub key: String,
+    pub request_hash: String,
+    pub response_status: Option<u16>,
+    pub response_body: Option<String>,
+    pub expires_at: DateTime,
+}
+// Follows an order through inventory, payment and fulfillment so that when one of them fails the others are compensated
+// for, one saga per order
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PaymentSaga {
+    pub order_id: String,
+    pub status: String,
+    #[serde(default)]
+    pub payment_id: String,
+    #[serde(default)]
+    pub inventory_reservation_id: String,
+    #[serde(default)]
+    pub failure_reason: String,
+    // Compensating actions still to run, each is taken off once it succeeds so an interrupted compensation resumes
+    // where it stopped
+    #[serde(default)]
+    pub pending_compensations: Vec<PaymentSagaCompensation>,
+    pub steps: Vec<PaymentSagaStep>,
+    // Bumped on every save, events for the same order arriving together must not overwrite each other's steps
+    pub version: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl PaymentSaga {
+    // Events arriving once the saga has settled no longer change its course
+    pub fn is_settled(&self) -> bool {
+        self.status == PaymentSagaStatus::COMPLETED.to_string()
+            || self.status == PaymentSagaStatus::COMPENSATED.to_string()
+            || self.status == PaymentSagaStatus::COMPENSATION_FAILED.to_string()
+    }
+
+    // Events arriving while the saga is compensating resume the compensation instead of changing its course
+    pub fn is_compensating(&self) -> bool {
+        self.status == PaymentSagaStatus::COMPENSATING.to_string()
+    }
+}
+
+// Either an event the saga reacted to or a compensating action it took
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PaymentSagaStep {
+    pub name: String,
+    #[serde(default)]
+    pub payment_id: String,
+    #[serde(default)]
+    pub detail: String,
+    pub occurred_at: u64,
+}
+
+#[derive(Debug)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum PaymentSagaStatus {
+    STARTED,
+    INVENTORY_RESERVED,
+    PAYMENT_SUCCEEDED,
+    COMPLETED,
+    COMPENSATING,
+    COMPENSATED,
+    // A compensating action failed and has to be finished by hand
+    COMPENSATION_FAILED,
+}
+
+impl fmt::Display for PaymentSagaStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentSagaStatus::STARTED => write!(f, "Started"),
+            PaymentSagaStatus::INVENTORY_RESERVED => write!(f, "InventoryReserved"),
+            PaymentSagaStatus::PAYMENT_SUCCEEDED => write!(f, "PaymentSucceeded"),
+            PaymentSagaStatus::COMPLETED => write!(f, "Completed"),
+            PaymentSagaStatus::COMPENSATING => write!(f, "Compensating"),
+            PaymentSagaStatus::COMPENSATED => write!(f, "Compensated"),
+            PaymentSagaStatus::COMPENSATION_FAILED => write!(f, "CompensationFailed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum PaymentSagaEvent {
+    ORDER_CREATED,
+    INVENTORY_RESERVED,
+    INVENTORY_UNAVAILABLE,
+    PAYMENT_SUCCEEDED,
+    PAYMENT_EXPIRED,
+    PAYMENT_CANCELLED,
+    FULFILLMENT_COMPLETED,
+    FULFILLMENT_FAILED,
+}
+
+impl fmt::Display for PaymentSagaEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentSagaEvent::ORDER_CREATED => write!(f, "OrderCreated"),
+            PaymentSagaEvent::INVENTORY_RESERVED => write!(f, "InventoryReserved"),
+            PaymentSagaEvent::INVENTORY_UNAVAILABLE => write!(f, "InventoryUnavailable"),
+            PaymentSagaEvent::PAYMENT_SUCCEEDED => write!(f, "PaymentSucceeded"),
+            PaymentSagaEvent::PAYMENT_EXPIRED => write!(f, "PaymentExpired"),
+            PaymentSagaEvent::PAYMENT_CANCELLED => write!(f, "PaymentCancelled"),
+            PaymentSagaEvent::FULFILLMENT_COMPLETED => write!(f, "FulfillmentCompleted"),
+            PaymentSagaEvent::FULFILLMENT_FAILED => write!(f, "FulfillmentFailed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum PaymentSagaCompensation {
+    REFUND_PAYMENT,
+    RELEASE_INVENTORY,
+    CANCEL_ORDER,
+}
+
+impl fmt::Display for PaymentSagaCompensation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentSagaCompensation::REFUND_PAYMENT => write!(f, "RefundPayment"),
+            PaymentSagaCompensation::RELEASE_INVENTORY => write!(f, "ReleaseInventory"),
+            PaymentSagaCompensation::CANCEL_ORDER => write!(f, "CancelOrder"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn payment(status: PaymentStatus) -> Payment {
+        serde_json::from_value(json!({
+            "id": "payment-1",
+            "line_items": [],
+            "status": status.to_string(),
+            "payment_processor": "fake",
+            "payment_processor_checkout_session_id": "session-1",
+            "payment_processor_checkout_session_url": "",
+            "payment_processor_id": "",
+            "payment_processor_status": "",
+            "customer_email": "customer@example.com"
+        })).unwrap()
+    }
+
+    fn subscription(status: SubscriptionStatus) -> Subscription {
+        Subscription {
+            id: String::from("subscription-1"),
+            line_items: Vec::new(),
+            currency: default_currency(),
+            checkout_mode: default_checkout_mode(),
+            status: status.to_string(),
+            payment_processor: String::from("fake"),
+            payment_processor_checkout_session_id: String::new(),
+            payment_processor_checkout_session_url: String::new(),
+            payment_processor_checkout_session_client_secret: String::new(),
+            payment_processor_checkout_session_expires_at: 0,
+            payment_processor_subscription_id: String::new(),
+            payment_processor_customer_id: String::new(),
+            payment_processor_status: String::new(),
+            customer_subject: String::from("subject-1"),
+            customer_email: String::from("customer@example.com"),
+            current_period_end: 0,
+            cancel_at_period_end: false,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn reconcile_checkout_session_succeeds_paid_session() {
+        let mut payment = payment(PaymentStatus::SESSION_CREATED);
+
+        assert!(matches!(payment.reconcile_checkout_session("complete", "paid"), Some(PaymentStatus::SUCCEEDED)));
+        assert_eq!(payment.status, PaymentStatus::SUCCEEDED.to_string());
+        assert!(payment.paid_at > 0);
+    }
+
+    #[test]
+    fn reconcile_checkout_session_succeeds_session_without_payment_required() {
+        let mut payment = payment(PaymentStatus::NEW);
+
+        assert!(matches!(payment.reconcile_checkout_session("complete", "no_payment_required"), Some(PaymentStatus::SUCCEEDED)));
+    }
+
+    #[test]
+    fn reconcile_checkout_session_waits_for_unpaid_complete_session() {
+        let mut payment = payment(PaymentStatus::SESSION_CREATED);
+
+        assert!(payment.reconcile_checkout_session("complete", "unpaid").is_none());
+        assert!(payment.reconcile_checkout_session("open", "unpaid").is_none());
+        assert_eq!(payment.status, PaymentStatus::SESSION_CREATED.to_string());
+    }
+
+    #[test]
+    fn reconcile_checkout_session_expires_expired_session() {
+        let mut payment = payment(PaymentStatus::SESSION_CREATED);
+
+        assert!(matches!(payment.reconcile_checkout_session("expired", "unpaid"), Some(PaymentStatus::EXPIRED)));
+        assert_eq!(payment.status, PaymentStatus::EXPIRED.to_string());
+    }
+
+    #[test]
+    fn reconcile_checkout_session_leaves_settled_payment() {
+        for status in [PaymentStatus::SUCCEEDED, PaymentStatus::REFUNDED, PaymentStatus::CANCELLED, PaymentStatus::DISPUTED] {
+            let mut payment = payment(status);
+            let status_before = payment.status.clone();
+
+            assert!(payment.reconcile_checkout_session("expired", "unpaid").is_none());
+            assert_eq!(payment.status, status_before);
+        }
+    }
+
+    #[test]
+    fn amounts_saved_as_decimals_are_read_in_minor_units() {
+        let line_item: LineItem = serde_json::from_value(json!({"product_id": "product-1", "quantity": 1, "price": 19.99, "tax_amount": 2.0})).unwrap();
+        assert_eq!(line_item.price, 1999);
+        assert_eq!(line_item.tax_amount, 200);
+
+        let line_item: LineItem = serde_json::from_value(json!({"product_id": "product-1", "quantity": 1, "price": 1999})).unwrap();
+        assert_eq!(line_item.price, 1999);
+        assert_eq!(line_item.tax_amount, 0);
+    }
+
+    #[test]
+    fn checkout_session_status_is_normalized_from_payment_status() {
+        assert_eq!(payment(PaymentStatus::SESSION_CREATED).checkout_session_status(), "open");
+        assert_eq!(payment(PaymentStatus::CANCELLED).checkout_session_status(), "expired");
+        for status in [PaymentStatus::SUCCEEDED, PaymentStatus::REFUNDED, PaymentStatus::CHARGED_BACK] {
+            assert_eq!(payment(status).checkout_session_status(), "complete");
+        }
+    }
+
+    #[test]
+    fn reconcile_invoice_activates_pending_and_past_due_subscription_once_paid() {
+        for status in [SubscriptionStatus::PENDING, SubscriptionStatus::PAST_DUE] {
+            let mut subscription = subscription(status);
+
+            assert!(matches!(subscription.reconcile_invoice(true), Some(SubscriptionStatus::ACTIVE)));
+            assert_eq!(subscription.status, SubscriptionStatus::ACTIVE.to_string());
+        }
+    }
+
+    #[test]
+    fn reconcile_invoice_puts_active_subscription_past_due_when_unpaid() {
+        let mut subscription = subscription(SubscriptionStatus::ACTIVE);
+
+        assert!(matches!(subscription.reconcile_invoice(false), Some(SubscriptionStatus::PAST_DUE)));
+        assert_eq!(subscription.status, SubscriptionStatus::PAST_DUE.to_string());
+    }
+
+    #[test]
+    fn reconcile_invoice_leaves_subscription_already_in_step() {
+        assert!(subscription(SubscriptionStatus::ACTIVE).reconcile_invoice(true).is_none());
+        assert!(subscription(SubscriptionStatus::PAST_DUE).reconcile_invoice(false).is_none());
+        assert!(subscription(SubscriptionStatus::PENDING).reconcile_invoice(false).is_none());
+    }
+
+    #[test]
+    fn reconcile_invoice_leaves_paused_and_ended_subscription() {
+        for paid in [true, false] {
+            for status in [SubscriptionStatus::PAUSED, SubscriptionStatus::CANCELLED, SubscriptionStatus::EXPIRED] {
+                let mut subscription = subscription(status);
+                let status_before = subscription.status.clone();
+
+                assert!(subscription.reconcile_invoice(paid).is_none());
+                assert_eq!(subscription.status, status_before);
+            }
+        }
+    }
+
+    #[test]
+    fn compensating_saga_is_not_settled_and_keeps_its_pending_compensations() {
+        let saga: PaymentSaga = serde_json::from_value(json!({
+            "order_id": "order-1",
+            "status": PaymentSagaStatus::COMPENSATING.to_string(),
+            "pending_compensations": ["RELEASE_INVENTORY", "CANCEL_ORDER"],
+            "steps": [],
+            "version": 2,
+            "created_at": 0,
+            "updated_at": 0
+        })).unwrap();
+
+        assert!(!saga.is_settled());
+        assert!(saga.is_compensating());
+        assert_eq!(saga.pending_compensations, vec![PaymentSagaCompensation::RELEASE_INVENTORY, PaymentSagaCompensation::CANCEL_ORDER]);
+    }
+}
@@ -1,41 +1,391 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::domain::{to_decimal_amount, LineItem, PaymentSagaStep, ReconciliationDiscrepancy, ShippingRate};
 
 pub trait Response{}
 
 #[derive(Serialize, Deserialize)]
+// What is bought and how many, any price sent along is ignored as line items are priced from the catalog
 pub struct LineItemRequestDto {
     pub product_id: String,
     pub quantity: u32,
-    pub price: f32
+}
+
+// A line item as events carry it, with decimal amounts
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LineItemDto {
+    pub product_id: String,
+    pub quantity: u32,
+    pub price: f32,
+    #[serde(default)]
+    pub tax_code: String,
+    // Tax on the whole line rather than per unit
+    #[serde(default)]
+    pub tax_amount: f32,
+}
+
+impl From<&LineItem> for LineItemDto {
+    fn from(line_item: &LineItem) -> Self {
+        LineItemDto {
+            product_id: line_item.product_id.clone(),
+            quantity: line_item.quantity,
+            price: to_decimal_amount(line_item.price),
+            tax_code: line_item.tax_code.clone(),
+            tax_amount: to_decimal_amount(line_item.tax_amount),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ShippingRateDto {
+    pub shipping_option_id: String,
+    pub display_name: String,
+    pub amount: f32,
+}
+
+impl From<&ShippingRate> for ShippingRateDto {
+    fn from(shipping_rate: &ShippingRate) -> Self {
+        ShippingRateDto {
+            shipping_option_id: shipping_rate.shipping_option_id.clone(),
+            display_name: shipping_rate.display_name.clone(),
+            amount: to_decimal_amount(shipping_rate.amount),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+// Like one-off line items, the price and billing interval come from the catalog rather than the request
+pub struct SubscriptionLineItemRequestDto {
+    pub product_id: String,
+    pub quantity: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PaymentProcessorLineItemRequestDto {
-    pub price: String,
+    pub price_data: PaymentProcessorPriceDataRequestDto,
     pub quantity: u32,
 }
 
+// Prices are created inline against the product registered under our catalog id, at the catalog price the payment's
+// line items were given on our side
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorPriceDataRequestDto {
+    pub currency: String,
+    pub unit_amount: i64,
+    // Charges that are not catalog products, e.g. locally calculated tax, name an inline product instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_data: Option<PaymentProcessorProductDataRequestDto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recurring: Option<PaymentProcessorRecurringDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorProductDataRequestDto {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorAutomaticTaxDto {
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCustomerUpdateRequestDto {
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorRecurringDto {
+    pub interval: String,
+    pub interval_count: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorPaymentMetadataDto {
+    pub payment_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorPaymentIntentDataRequestDto {
+    pub metadata: PaymentProcessorPaymentMetadataDto,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreateCheckoutSessionResponseDto {
     pub payment_id: String,
+    pub checkout_mode: String,
     pub checkout_session_id: String,
-    pub checkout_session_url: String,
+    // Hosted checkout redirects to the url, embedded and custom checkout are initialized with the client secret
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkout_session_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub promotion_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount_amount: Option<f32>,
+    // Not set while the payment processor has yet to work out the tax during checkout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tax_amount: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shipping_rates: Vec<ShippingRateDto>,
 }
 impl Response for CreateCheckoutSessionResponseDto{}
 
+#[derive(Serialize, Deserialize)]
+pub struct CreatePromotionResponseDto {
+    pub code: String,
+}
+impl Response for CreatePromotionResponseDto{}
+
 #[derive(Serialize, Deserialize)]
 pub struct PaymentProcessorCreateCheckoutSessionRequestDto {
     pub ui_mode: String,
     pub line_items: Vec<PaymentProcessorLineItemRequestDto>,
     pub mode: String,
-    pub return_url: String,
+    // Hosted checkout redirects to success_url or cancel_url, embedded and custom checkout return to return_url
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_url: Option<String>,
+    pub expires_at: u64,
+    pub client_reference_id: String,
+    // Stripe takes either an existing customer or an email to prefill, never both
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_email: Option<String>,
+    pub metadata: PaymentProcessorPaymentMetadataDto,
+    pub payment_intent_data: PaymentProcessorPaymentIntentDataRequestDto,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discounts: Option<Vec<PaymentProcessorDiscountRequestDto>>,
+    // Stripe Tax needs the customer's address, which is collected at checkout and saved to an existing customer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub automatic_tax: Option<PaymentProcessorAutomaticTaxDto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub billing_address_collection: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_update: Option<PaymentProcessorCustomerUpdateRequestDto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shipping_address_collection: Option<PaymentProcessorShippingAddressCollectionDto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shipping_options: Option<Vec<PaymentProcessorShippingOptionRequestDto>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorShippingAddressCollectionDto {
+    pub allowed_countries: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorFixedAmountDto {
+    pub amount: i64,
+    pub currency: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorShippingRateMetadataDto {
+    pub shipping_option_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorShippingRateDataRequestDto {
+    #[serde(rename = "type")]
+    pub shipping_rate_type: String,
+    pub display_name: String,
+    pub fixed_amount: PaymentProcessorFixedAmountDto,
+    pub metadata: PaymentProcessorShippingRateMetadataDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorShippingOptionRequestDto {
+    pub shipping_rate_data: PaymentProcessorShippingRateDataRequestDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorDiscountRequestDto {
+    pub coupon: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCouponMetadataDto {
+    pub payment_id: String,
+    pub promotion_code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCouponRequestDto {
+    pub name: String,
+    pub amount_off: i64,
+    pub currency: String,
+    pub duration: String,
+    pub max_redemptions: u32,
+    pub metadata: PaymentProcessorCouponMetadataDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCouponResponseDto {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorSubscriptionMetadataDto {
+    pub subscription_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorSubscriptionDataRequestDto {
+    pub metadata: PaymentProcessorSubscriptionMetadataDto,
+}
+
+// Subscription mode sessions take subscription_data in place of payment_intent_data
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCreateSubscriptionCheckoutSessionRequestDto {
+    pub ui_mode: String,
+    pub line_items: Vec<PaymentProcessorLineItemRequestDto>,
+    pub mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_url: Option<String>,
+    pub expires_at: u64,
+    pub client_reference_id: String,
+    // Stripe takes either an existing customer or an email to prefill, never both
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_email: Option<String>,
+    pub metadata: PaymentProcessorSubscriptionMetadataDto,
+    pub subscription_data: PaymentProcessorSubscriptionDataRequestDto,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct PaymentProcessorCreateCheckoutSessionResponseDto {
+    #[serde(rename = "id")]
+    pub session_id: String,
+    #[serde(rename = "url")]
+    pub session_url: Option<String>,
+    pub client_secret: Option<String>,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCustomerMetadataDto {
+    pub auth0_subject: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCustomerRequestDto {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub metadata: PaymentProcessorCustomerMetadataDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCustomerResponseDto {
+    pub id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCustomerDetailsDto {
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCheckoutSessionResponseDto {
+    #[serde(rename = "id")]
     pub session_id: String,
-    pub session_url: String,
+    pub client_reference_id: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    pub status: Option<String>,
+    pub payment_status: String,
+    pub payment_intent: Option<String>,
+    pub customer_details: Option<PaymentProcessorCustomerDetailsDto>,
+    // Only set for subscription mode sessions
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub subscription: Option<String>,
+    #[serde(default)]
+    pub customer: Option<String>,
+    #[serde(default)]
+    pub automatic_tax: Option<PaymentProcessorAutomaticTaxDto>,
+    // Never part of Stripe's session object, only filled in by processors reporting the tax they worked out per line
+    #[serde(default)]
+    pub line_items: Option<Vec<PaymentProcessorCheckoutSessionLineItemResponseDto>>,
+    #[serde(default)]
+    pub shipping_cost: Option<PaymentProcessorShippingCostResponseDto>,
+    // Older API versions report the shipping details on the session itself, newer ones with the collected information
+    #[serde(default)]
+    pub shipping_details: Option<PaymentProcessorShippingDetailsDto>,
+    #[serde(default)]
+    pub collected_information: Option<PaymentProcessorCollectedInformationDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorShippingCostResponseDto {
+    pub amount_total: i64,
+    // Only an object carrying our shipping option id in its metadata when expanded, otherwise the shipping rate's id
+    #[serde(default)]
+    pub shipping_rate: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PaymentProcessorAddressDto {
+    pub line1: Option<String>,
+    pub line2: Option<String>,
+    pub city: Option<String>,
+    pub state: Option<String>,
+    pub postal_code: Option<String>,
+    pub country: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorShippingDetailsDto {
+    pub name: Option<String>,
+    pub address: Option<PaymentProcessorAddressDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCollectedInformationDto {
+    pub shipping_details: Option<PaymentProcessorShippingDetailsDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCheckoutSessionLineItemPriceDto {
+    pub product: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCheckoutSessionLineItemResponseDto {
+    pub id: String,
+    pub amount_tax: i64,
+    pub price: Option<PaymentProcessorCheckoutSessionLineItemPriceDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetCheckoutSessionResponseDto {
+    pub payment_id: String,
+    pub checkout_session_id: String,
+    pub status: String,
+    pub payment_status: String,
+    pub customer_email: String,
 }
+impl Response for GetCheckoutSessionResponseDto{}
 
 #[derive(Serialize, Deserialize)]
 pub struct ApiError {
@@ -67,12 +417,493 @@ impl Response for EmptyResponse{}
 #[derive(Deserialize, Serialize)]
 pub struct PaymentProcessorCreateProductRequestDto {
     pub id: String,
-    pub name: String
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tax_code: Option<String>,
+    pub metadata: PaymentProcessorProductMetadataDto,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PaymentProcessorProductMetadataDto {
+    pub catalog_product_id: String,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct PaymentProcessorCreatePricingRequestDto {
     pub product: String,
     pub currency: String,
-    pub unit_amount: i32,
+    pub unit_amount: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recurring: Option<PaymentProcessorRecurringDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorSubscriptionResponseDto {
+    pub id: String,
+    pub status: String,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    pub customer: Option<String>,
+    pub current_period_end: Option<u64>,
+    #[serde(default)]
+    pub cancel_at_period_end: bool,
+    // Present while collection is paused, the subscription itself stays active
+    pub pause_collection: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCancelSubscriptionAtPeriodEndRequestDto {
+    pub cancel_at_period_end: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorPauseCollectionRequestDto {
+    pub behavior: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorPauseSubscriptionRequestDto {
+    pub pause_collection: PaymentProcessorPauseCollectionRequestDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorInvoiceResponseDto {
+    pub id: String,
+    pub subscription: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefundLineItemRequestDto {
+    pub product_id: String,
+    pub quantity: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefundPaymentResponseDto {
+    pub payment_id: String,
+    pub refund_id: String,
+    pub amount: f32,
+    pub payment_status: String,
+}
+impl Response for RefundPaymentResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCreateRefundRequestDto {
+    pub payment_intent: String,
+    pub amount: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub metadata: PaymentProcessorRefundMetadataDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorRefundMetadataDto {
+    pub payment_id: String,
+    pub refund_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorRefundResponseDto {
+    pub id: String,
+    pub status: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CancelPaymentResponseDto {
+    pub payment_id: String,
+    pub payment_status: String,
+}
+impl Response for CancelPaymentResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct SweepStalePaymentsResponseDto {
+    pub checked: u32,
+    pub transitioned: u32,
+    pub resumed_sagas: u32,
+}
+impl Response for SweepStalePaymentsResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct RelayOutboxResponseDto {
+    pub payments: u32,
+    pub published: u32,
+    // Events that could not be published this time, they stay in the outbox for the next relay
+    pub remaining: u32,
+}
+impl Response for RelayOutboxResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCreatedRangeDto {
+    pub gte: u64,
+    pub lte: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorListRequestDto {
+    pub limit: u32,
+    pub created: PaymentProcessorCreatedRangeDto,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starting_after: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorListResponseDto<T> {
+    pub data: Vec<T>,
+    pub has_more: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCardResponseDto {
+    pub brand: String,
+    pub last4: String,
+    pub exp_month: u32,
+    pub exp_year: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorPaymentMethodResponseDto {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub payment_method_type: String,
+    pub card: Option<PaymentProcessorCardResponseDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorDisputeResponseDto {
+    pub id: String,
+    pub amount: i64,
+    pub currency: String,
+    pub reason: String,
+    pub status: String,
+    pub payment_intent: Option<String>,
+    pub evidence_details: Option<PaymentProcessorDisputeEvidenceDetailsDto>,
+    pub created: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorDisputeEvidenceDetailsDto {
+    pub due_by: Option<u64>,
+}
+
+// Evidence fields are the processor's own, files are uploaded first and referenced from the field by their id
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorDisputeUpdateRequestDto {
+    pub evidence: HashMap<String, String>,
+    pub submit: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorDisputeEvidenceFileDto {
+    pub evidence_type: String,
+    pub file_name: String,
+    pub content_type: String,
+    pub content: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorFileResponseDto {
+    pub id: String,
+}
+
+// Setup mode sessions save a payment method to the customer without charging it
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorCreateSetupCheckoutSessionRequestDto {
+    pub ui_mode: String,
+    pub mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub return_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_url: Option<String>,
+    pub customer: String,
+    pub currency: String,
+}
+
+// Charges and refunds share the fields needed for reconciliation
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorTransactionResponseDto {
+    pub id: String,
+    pub amount: i64,
+    pub payment_intent: Option<String>,
+    pub status: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReconcilePaymentsResponseDto {
+    pub report_id: String,
+    pub payment_processor: String,
+    pub created_from: u64,
+    pub created_to: u64,
+    pub discrepancies: Vec<ReconciliationDiscrepancyDto>,
+}
+impl Response for ReconcilePaymentsResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReconciliationDiscrepancyDto {
+    pub kind: String,
+    pub payment_id: String,
+    pub payment_processor_transaction_id: String,
+    pub expected_amount: f32,
+    pub actual_amount: f32,
+}
+
+impl From<&ReconciliationDiscrepancy> for ReconciliationDiscrepancyDto {
+    fn from(discrepancy: &ReconciliationDiscrepancy) -> Self {
+        ReconciliationDiscrepancyDto {
+            kind: discrepancy.kind.clone(),
+            payment_id: discrepancy.payment_id.clone(),
+            payment_processor_transaction_id: discrepancy.payment_processor_transaction_id.clone(),
+            expected_amount: to_decimal_amount(discrepancy.expected_amount),
+            actual_amount: to_decimal_amount(discrepancy.actual_amount),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentSagaResponseDto {
+    pub order_id: String,
+    pub status: String,
+    pub payment_id: String,
+    pub inventory_reservation_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+    pub steps: Vec<PaymentSagaStep>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+impl Response for PaymentSagaResponseDto{}
+
+// Files are sent base64 encoded, evidence_type is the evidence field the file is submitted as
+#[derive(Serialize, Deserialize)]
+pub struct DisputeEvidenceFileRequestDto {
+    pub evidence_type: String,
+    pub file_name: String,
+    pub content_type: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubmitDisputeEvidenceResponseDto {
+    pub dispute_id: String,
+    pub payment_id: String,
+    pub dispute_status: String,
+    pub evidence_submitted: bool,
+}
+impl Response for SubmitDisputeEvidenceResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorWebhookEventDto {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub data: PaymentProcessorWebhookEventDataDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentProcessorWebhookEventDataDto {
+    pub object: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetOrderPaymentResponseDto {
+    pub payment_id: String,
+    pub order_id: String,
+    pub payment_status: String,
+    pub checkout_mode: String,
+    pub checkout_session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkout_session_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+impl Response for GetOrderPaymentResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateSubscriptionResponseDto {
+    pub subscription_id: String,
+    pub checkout_mode: String,
+    pub checkout_session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkout_session_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+impl Response for CreateSubscriptionResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubscriptionResponseDto {
+    pub subscription_id: String,
+    pub subscription_status: String,
+    pub cancel_at_period_end: bool,
+    pub current_period_end: u64,
+}
+impl Response for SubscriptionResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct PaymentMethodResponseDto {
+    pub payment_method_id: String,
+    pub payment_method_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brand: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last4: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp_month: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp_year: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListPaymentMethodsResponseDto {
+    pub payment_methods: Vec<PaymentMethodResponseDto>,
+}
+impl Response for ListPaymentMethodsResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateSetupCheckoutSessionResponseDto {
+    pub checkout_mode: String,
+    pub checkout_session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkout_session_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+impl Response for CreateSetupCheckoutSessionResponseDto{}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalAccessTokenResponseDto {
+    pub access_token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalAmountDto {
+    pub currency_code: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalAmountBreakdownDto {
+    pub item_total: PayPalAmountDto,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shipping: Option<PayPalAmountDto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tax_total: Option<PayPalAmountDto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount: Option<PayPalAmountDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalAmountWithBreakdownDto {
+    pub currency_code: String,
+    pub value: String,
+    pub breakdown: PayPalAmountBreakdownDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalItemDto {
+    pub name: String,
+    pub sku: String,
+    pub quantity: String,
+    pub unit_amount: PayPalAmountDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalPurchaseUnitRequestDto {
+    pub reference_id: String,
+    pub custom_id: String,
+    pub amount: PayPalAmountWithBreakdownDto,
+    pub items: Vec<PayPalItemDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalApplicationContextDto {
+    pub return_url: String,
+    pub cancel_url: String,
+    // GET_FROM_FILE collects the payer's shipping address, NO_SHIPPING skips it
+    pub shipping_preference: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalCreateOrderRequestDto {
+    pub intent: String,
+    pub purchase_units: Vec<PayPalPurchaseUnitRequestDto>,
+    pub application_context: PayPalApplicationContextDto,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalLinkDto {
+    pub href: String,
+    pub rel: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalCaptureDto {
+    pub id: String,
+    pub status: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalPaymentsDto {
+    #[serde(default)]
+    pub captures: Vec<PayPalCaptureDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalNameDto {
+    pub full_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalAddressDto {
+    pub address_line_1: Option<String>,
+    pub address_line_2: Option<String>,
+    pub admin_area_2: Option<String>,
+    pub admin_area_1: Option<String>,
+    pub postal_code: Option<String>,
+    pub country_code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalShippingResponseDto {
+    pub name: Option<PayPalNameDto>,
+    pub address: Option<PayPalAddressDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalPurchaseUnitResponseDto {
+    pub custom_id: Option<String>,
+    pub payments: Option<PayPalPaymentsDto>,
+    #[serde(default)]
+    pub shipping: Option<PayPalShippingResponseDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalPayerDto {
+    pub email_address: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalOrderResponseDto {
+    pub id: String,
+    pub status: String,
+    #[serde(default)]
+    pub links: Vec<PayPalLinkDto>,
+    #[serde(default)]
+    pub purchase_units: Vec<PayPalPurchaseUnitResponseDto>,
+    pub payer: Option<PayPalPayerDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalRefundRequestDto {
+    pub amount: PayPalAmountDto,
+    pub custom_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PayPalRefundResponseDto {
+    pub id: String,
+    pub status: String,
 }
\ No newline at end of file
@@ -1,15 +1,36 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex, RwLock}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use amqprs::{callbacks::{DefaultChannelCallback, DefaultConnectionCallback}, channel::{BasicConsumeArguments, Channel, ExchangeDeclareArguments, ExchangeType, QueueBindArguments, QueueDeclareArguments}, connection::{Connection, OpenConnectionArguments}, consumer::AsyncConsumer, BasicProperties, Deliver};
+use amqprs::{callbacks::{ChannelCallback, DefaultChannelCallback, DefaultConnectionCallback}, channel::{BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, BasicNackArguments, BasicPublishArguments, BasicQosArguments, Channel, ConfirmSelectArguments, ExchangeDeclareArguments, ExchangeType, QueueBindArguments, QueueDeclareArguments}, connection::{Connection, OpenConnectionArguments}, consumer::AsyncConsumer, Ack, BasicProperties, Cancel, CloseChannel, Deliver, FieldName, FieldTable, FieldValue, Nack, Return};
 use async_trait::async_trait;
+use axum_prometheus::metrics::{counter, gauge};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Notify;
+use serde_json::Value;
+use tokio::sync::oneshot;
 use tracing::{event, Level};
 
-use crate::{cqrs::{CommandHandler, CreateProductPricingCommand}, state::AppState};
+use crate::{cqrs::{AdvancePaymentSagaCommand, CommandHandler, CreateCheckoutSessionCommand, CreateProductPricingCommand}, domain::{default_currency, to_decimal_amount, Payment, PaymentSagaEvent, PaymentStatus, ShippingAddress}, dtos::{LineItemDto, LineItemRequestDto, ShippingRateDto}, state::AppState};
 
 pub static PRODUCT_CREATED_QUEUE_NAME: &str = "product.created";
+pub static PAYMENT_REFUNDED_QUEUE_NAME: &str = "payment.refunded";
+pub static PAYMENT_SUCCEEDED_QUEUE_NAME: &str = "payment.succeeded";
+pub static PAYMENT_EXPIRED_QUEUE_NAME: &str = "payment.expired";
+pub static PAYMENT_DISPUTED_QUEUE_NAME: &str = "payment.disputed";
+pub static PAYMENT_CHARGED_BACK_QUEUE_NAME: &str = "payment.charged_back";
+pub static ORDER_CREATED_QUEUE_NAME: &str = "order.created";
+pub static INVENTORY_RESERVATION_REQUESTED_QUEUE_NAME: &str = "inventory.reservation.requested";
+pub static INVENTORY_RESERVATION_REPLIED_QUEUE_NAME: &str = "inventory.reservation.replied";
+pub static INVENTORY_RESERVATION_RELEASED_QUEUE_NAME: &str = "inventory.reservation.released";
+pub static ORDER_CANCELLATION_REQUESTED_QUEUE_NAME: &str = "order.cancellation.requested";
+pub static FULFILLMENT_COMPLETED_QUEUE_NAME: &str = "fulfillment.completed";
+pub static FULFILLMENT_FAILED_QUEUE_NAME: &str = "fulfillment.failed";
 
+pub static EVENT_PRODUCER_NAME: &str = "eshop-payment-service";
+
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const CONNECTION_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDLER_DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
 pub struct RabbitMqInitializationInfo {
     uri: String,
     port: u16,
@@ -32,35 +53,760 @@ impl RabbitMqInitializationInfo {
         }
 }
 
-#[derive(Serialize, Deserialize)]
+// The RabbitMQ objects declared at startup and again after reconnecting. Exchanges and queues not listed keep the
+// default of a fanout exchange per destination and a durable queue of the same name bound to it.
+#[derive(Deserialize, Clone, Default)]
+pub struct RabbitMqTopology {
+    #[serde(default)]
+    pub exchanges: Vec<ExchangeTopology>,
+    #[serde(default)]
+    pub queues: Vec<QueueTopology>,
+    #[serde(default)]
+    pub bindings: Vec<BindingTopology>,
+    // Where the events for a destination are published, by default the destination's own exchange
+    #[serde(default)]
+    pub publications: Vec<PublicationTopology>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ExchangeTopology {
+    pub name: String,
+    // fanout, topic, direct or headers
+    #[serde(default = "default_exchange_type")]
+    pub exchange_type: String,
+    #[serde(default = "default_durable")]
+    pub durable: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct QueueTopology {
+    pub name: String,
+    #[serde(default = "default_durable")]
+    pub durable: bool,
+    // Quorum queues are replicated across the cluster and are always durable
+    #[serde(default)]
+    pub quorum: bool,
+    #[serde(default)]
+    pub message_ttl_milliseconds: Option<u32>,
+    #[serde(default)]
+    pub max_length: Option<u32>,
+    #[serde(default)]
+    pub dead_letter_exchange: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BindingTopology {
+    pub exchange: String,
+    pub queue: String,
+    #[serde(default)]
+    pub routing_key: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PublicationTopology {
+    pub destination: String,
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+fn default_exchange_type() -> String {
+    ExchangeType::Fanout.to_string()
+}
+
+fn default_durable() -> bool {
+    true
+}
+
+impl RabbitMqTopology {
+    pub fn declares_exchange(&self, name: &str) -> bool {
+        self.exchanges.iter().any(|exchange| exchange.name == name)
+    }
+
+    pub fn declares_queue(&self, name: &str) -> bool {
+        self.queues.iter().any(|queue| queue.name == name)
+    }
+
+    // The exchange and routing key events for the destination are published with, the routing key defaults to the
+    // destination so topic exchanges can filter on it
+    pub fn publication(&self, destination: &str) -> (String, String) {
+        match self.publications.iter().find(|publication| publication.destination == destination) {
+            Some(publication) => (publication.exchange.clone(), publication.routing_key.clone()),
+            None => (String::from(destination), String::from(destination))
+        }
+    }
+}
+
+impl QueueTopology {
+    pub fn arguments(&self) -> Result<FieldTable, String> {
+        let mut arguments = FieldTable::new();
+        let mut insert = |name: &str, value: FieldValue| -> Result<(), String> {
+            let name = FieldName::try_from(name).map_err(|e| format!("Invalid queue argument {}: {}", name, e))?;
+            arguments.insert(name, value);
+            Ok(())
+        };
+
+        if self.quorum {
+            insert("x-queue-type", FieldValue::from("quorum"))?;
+        }
+        if let Some(message_ttl_milliseconds) = self.message_ttl_milliseconds {
+            insert("x-message-ttl", FieldValue::I(message_ttl_milliseconds as i32))?;
+        }
+        if let Some(max_length) = self.max_length {
+            insert("x-max-length", FieldValue::I(max_length as i32))?;
+        }
+        if let Some(dead_letter_exchange) = &self.dead_letter_exchange {
+            insert("x-dead-letter-exchange", FieldValue::from(dead_letter_exchange.as_str()))?;
+        }
+
+        Ok(arguments)
+    }
+}
+
+// Variant names are the externally tagged names other services consume, so they keep their Event suffix. Events are
+// only held briefly on their way to or from the broker, so the larger payment events are not boxed.
+#[derive(Serialize, Deserialize, Clone)]
+#[allow(clippy::enum_variant_names, clippy::large_enum_variant)]
 pub enum Event {
     ProductCreatedEvent {
         id: String,
         name: String,
-        price: f32
+        price: f32,
+        // Catalogs announcing a single currency leave it out and get the configured default
+        #[serde(default)]
+        currency: Option<String>,
+        // Only set for recurring products, billed every interval_count intervals
+        #[serde(default)]
+        interval: Option<String>,
+        #[serde(default)]
+        interval_count: Option<u32>,
+        #[serde(default)]
+        tax_code: Option<String>,
+        #[serde(default)]
+        shippable: bool,
+        #[serde(default)]
+        weight_grams: u32,
+    },
+    OrderCreatedEvent {
+        id: String,
+        line_items: Vec<LineItemDto>,
+        #[serde(default)]
+        customer_email: Option<String>,
     },
+    PaymentRefundedEvent {
+        payment_id: String,
+        order_id: String,
+        refund_id: String,
+        amount: f32,
+        line_items: Vec<LineItemDto>,
+        fully_refunded: bool,
+    },
+    PaymentSucceededEvent {
+        payment_id: String,
+        order_id: String,
+        amount: f32,
+        line_items: Vec<LineItemDto>,
+        customer_email: String,
+        #[serde(default)]
+        tax_amount: f32,
+        // Only set when the payment had goods to ship
+        #[serde(default)]
+        shipping_rate: Option<ShippingRateDto>,
+        #[serde(default)]
+        shipping_address: Option<ShippingAddress>,
+    },
+    PaymentExpiredEvent {
+        payment_id: String,
+        order_id: String,
+    },
+    PaymentDisputedEvent {
+        payment_id: String,
+        order_id: String,
+        dispute_id: String,
+        amount: f32,
+        reason: String,
+        evidence_due_by: u64,
+    },
+    // A dispute was lost and the disputed amount taken back by the customer's bank
+    PaymentChargedBackEvent {
+        payment_id: String,
+        order_id: String,
+        dispute_id: String,
+        amount: f32,
+        reason: String,
+    },
+    InventoryReservationRequestedEvent {
+        reservation_id: String,
+        order_id: String,
+        line_items: Vec<LineItemDto>,
+    },
+    // Sent back on the requester's reply queue rather than published to its destination
+    InventoryReservationRepliedEvent {
+        reservation_id: String,
+        reserved: bool,
+        #[serde(default)]
+        unavailable_product_ids: Vec<String>,
+    },
+    InventoryReservationReleasedEvent {
+        reservation_id: String,
+        order_id: String,
+    },
+    OrderCancellationRequestedEvent {
+        order_id: String,
+        reason: String,
+    },
+    FulfillmentCompletedEvent {
+        order_id: String,
+    },
+    FulfillmentFailedEvent {
+        order_id: String,
+        #[serde(default)]
+        reason: String,
+    },
+}
+
+impl Event {
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Event::ProductCreatedEvent { .. } => "ProductCreatedEvent",
+            Event::OrderCreatedEvent { .. } => "OrderCreatedEvent",
+            Event::PaymentRefundedEvent { .. } => "PaymentRefundedEvent",
+            Event::PaymentSucceededEvent { .. } => "PaymentSucceededEvent",
+            Event::PaymentExpiredEvent { .. } => "PaymentExpiredEvent",
+            Event::PaymentDisputedEvent { .. } => "PaymentDisputedEvent",
+            Event::PaymentChargedBackEvent { .. } => "PaymentChargedBackEvent",
+            Event::InventoryReservationRequestedEvent { .. } => "InventoryReservationRequestedEvent",
+            Event::InventoryReservationRepliedEvent { .. } => "InventoryReservationRepliedEvent",
+            Event::InventoryReservationReleasedEvent { .. } => "InventoryReservationReleasedEvent",
+            Event::OrderCancellationRequestedEvent { .. } => "OrderCancellationRequestedEvent",
+            Event::FulfillmentCompletedEvent { .. } => "FulfillmentCompletedEvent",
+            Event::FulfillmentFailedEvent { .. } => "FulfillmentFailedEvent",
+        }
+    }
+
+    // The version of the event type's payload published and understood here, bumped whenever a field is added, removed
+    // or changes meaning. An added field is given #[serde(default)] so earlier versions still read, only a removed or
+    // changed field needs its own step in upcast_event_payload.
+    pub fn current_version(event_type: &str) -> u32 {
+        match event_type {
+            "ProductCreatedEvent" | "OrderCreatedEvent" | "PaymentSucceededEvent" => 2,
+            _ => 1
+        }
+    }
+
+    // Events about an order carry its id as their correlation id, so everything that happened to it can be followed
+    // across services
+    pub fn correlation_id(&self) -> Option<String> {
+        match self {
+            Event::OrderCreatedEvent { id, .. } => Some(id.clone()),
+            Event::PaymentRefundedEvent { order_id, .. }
+            | Event::PaymentSucceededEvent { order_id, .. }
+            | Event::PaymentExpiredEvent { order_id, .. }
+            | Event::PaymentDisputedEvent { order_id, .. }
+            | Event::PaymentChargedBackEvent { order_id, .. }
+            | Event::InventoryReservationRequestedEvent { order_id, .. }
+            | Event::InventoryReservationReleasedEvent { order_id, .. }
+            | Event::OrderCancellationRequestedEvent { order_id, .. }
+            | Event::FulfillmentCompletedEvent { order_id }
+            | Event::FulfillmentFailedEvent { order_id, .. } => Some(order_id.clone()).filter(|order_id| !order_id.is_empty()),
+            Event::ProductCreatedEvent { .. } | Event::InventoryReservationRepliedEvent { .. } => None
+        }
+    }
+
+    pub fn destination(&self) -> &'static str {
+        match self {
+            Event::ProductCreatedEvent { .. } => PRODUCT_CREATED_QUEUE_NAME,
+            Event::OrderCreatedEvent { .. } => ORDER_CREATED_QUEUE_NAME,
+            Event::PaymentRefundedEvent { .. } => PAYMENT_REFUNDED_QUEUE_NAME,
+            Event::PaymentSucceededEvent { .. } => PAYMENT_SUCCEEDED_QUEUE_NAME,
+            Event::PaymentExpiredEvent { .. } => PAYMENT_EXPIRED_QUEUE_NAME,
+            Event::PaymentDisputedEvent { .. } => PAYMENT_DISPUTED_QUEUE_NAME,
+            Event::PaymentChargedBackEvent { .. } => PAYMENT_CHARGED_BACK_QUEUE_NAME,
+            Event::InventoryReservationRequestedEvent { .. } => INVENTORY_RESERVATION_REQUESTED_QUEUE_NAME,
+            Event::InventoryReservationRepliedEvent { .. } => INVENTORY_RESERVATION_REPLIED_QUEUE_NAME,
+            Event::InventoryReservationReleasedEvent { .. } => INVENTORY_RESERVATION_RELEASED_QUEUE_NAME,
+            Event::OrderCancellationRequestedEvent { .. } => ORDER_CANCELLATION_REQUESTED_QUEUE_NAME,
+            Event::FulfillmentCompletedEvent { .. } => FULFILLMENT_COMPLETED_QUEUE_NAME,
+            Event::FulfillmentFailedEvent { .. } => FULFILLMENT_FAILED_QUEUE_NAME,
+        }
+    }
+
+    // The event other services are told about when a payment reaches the given status, if any
+    pub fn for_payment_status(payment: &Payment, status: &PaymentStatus) -> Option<Event> {
+        match status {
+            PaymentStatus::SUCCEEDED => Some(Event::PaymentSucceededEvent {
+                payment_id: payment.id.clone(),
+                order_id: payment.order_id.clone(),
+                amount: to_decimal_amount(payment.total()),
+                line_items: payment.line_items.iter().map(LineItemDto::from).collect(),
+                customer_email: payment.customer_email.clone(),
+                tax_amount: to_decimal_amount(payment.tax_total()),
+                shipping_rate: payment.shipping.as_ref().and_then(|shipping| shipping.selected_rate.as_ref()).map(ShippingRateDto::from),
+                shipping_address: payment.shipping.as_ref().and_then(|shipping| shipping.address.clone()),
+            }),
+            PaymentStatus::EXPIRED => Some(Event::PaymentExpiredEvent {
+                payment_id: payment.id.clone(),
+                order_id: payment.order_id.clone(),
+            }),
+            // Only the dispute that was just opened is announced
+            PaymentStatus::DISPUTED => payment.disputes.last().map(|dispute| Event::PaymentDisputedEvent {
+                payment_id: payment.id.clone(),
+                order_id: payment.order_id.clone(),
+                dispute_id: dispute.id.clone(),
+                amount: to_decimal_amount(dispute.amount),
+                reason: dispute.reason.clone(),
+                evidence_due_by: dispute.evidence_due_by,
+            }),
+            PaymentStatus::CHARGED_BACK => payment.disputes.iter().rfind(|dispute| dispute.is_lost()).map(|dispute| Event::PaymentChargedBackEvent {
+                payment_id: payment.id.clone(),
+                order_id: payment.order_id.clone(),
+                dispute_id: dispute.id.clone(),
+                amount: to_decimal_amount(dispute.amount),
+                reason: dispute.reason.clone(),
+            }),
+            _ => None
+        }
+    }
+
+    // Stock held for a payment goes back to the inventory once the payment can no longer be paid for
+    pub fn for_inventory_release(payment: &Payment, status: &PaymentStatus) -> Option<Event> {
+        match status {
+            PaymentStatus::EXPIRED | PaymentStatus::CANCELLED if !payment.inventory_reservation_id.is_empty() => Some(Event::InventoryReservationReleasedEvent {
+                reservation_id: payment.inventory_reservation_id.clone(),
+                order_id: payment.order_id.clone(),
+            }),
+            _ => None
+        }
+    }
+}
+
+// What goes over the wire, the payload is the event's fields at the given version of its type. Fields a consumer does not
+// know about are ignored so producers can add fields without breaking anyone.
+#[derive(Serialize, Deserialize)]
+pub struct EventEnvelope {
+    pub event_id: String,
+    pub event_type: String,
+    pub version: u32,
+    pub occurred_at: u64,
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    pub producer: String,
+    pub payload: Value,
+}
+
+impl EventEnvelope {
+    pub fn new(event: &Event, correlation_id: Option<String>) -> Result<EventEnvelope, String> {
+        let event_type = event.event_type();
+
+        // The externally tagged event is an object with the event's fields under its type
+        let payload = match serde_json::to_value(event) {
+            Ok(Value::Object(mut tagged_event)) => tagged_event.remove(event_type).unwrap_or(Value::Null),
+            Ok(_) => Value::Null,
+            Err(e) => {
+                event!(Level::WARN, "Failed to serialize {}: {}", event_type, e);
+                return Err(format!("Failed to serialize {}: {}", event_type, e));
+            }
+        };
+
+        Ok(EventEnvelope {
+            event_id: uuid::Uuid::new_v4().to_string(),
+            event_type: String::from(event_type),
+            version: Event::current_version(event_type),
+            occurred_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            correlation_id,
+            producer: String::from(EVENT_PRODUCER_NAME),
+            payload,
+        })
+    }
+
+    // Reads an enveloped event, or a bare externally tagged one from a producer that predates envelopes which is taken
+    // as the first version of its type
+    pub fn decode(content: &[u8]) -> Result<EventEnvelope, String> {
+        let value = match serde_json::from_slice::<Value>(content) {
+            Ok(value) => value,
+            Err(e) => {
+                event!(Level::WARN, "Failed to deserialize message: {}", e);
+                return Err(format!("Failed to deserialize message: {}", e));
+            }
+        };
+
+        if value.get("event_type").is_some() && value.get("payload").is_some() {
+            return match serde_json::from_value::<EventEnvelope>(value) {
+                Ok(envelope) => Ok(envelope),
+                Err(e) => {
+                    event!(Level::WARN, "Failed to deserialize event envelope: {}", e);
+                    Err(format!("Failed to deserialize event envelope: {}", e))
+                }
+            };
+        }
+
+        match value {
+            Value::Object(tagged_event) if tagged_event.len() == 1 => {
+                let (event_type, payload) = tagged_event.into_iter().next().unwrap();
+                Ok(EventEnvelope {
+                    event_id: String::new(),
+                    event_type,
+                    version: 1,
+                    occurred_at: 0,
+                    correlation_id: None,
+                    producer: String::new(),
+                    payload,
+                })
+            },
+            _ => {
+                event!(Level::WARN, "Message is neither an event envelope nor an event");
+                Err(String::from("Message is neither an event envelope nor an event"))
+            }
+        }
+    }
+
+    // Upcasts the payload one version at a time to the current version of its type before reading it. A version newer
+    // than ours may have removed or changed a field we rely on, so it is rejected rather than read as if it were ours.
+    pub fn into_event(self) -> Result<Event, String> {
+        let current_version = Event::current_version(&self.event_type);
+        if self.version > current_version {
+            event!(Level::WARN, "{} {} is version {}, newer than version {}", self.event_type, self.event_id, self.version, current_version);
+            return Err(format!("{} {} is version {}, newer than version {}", self.event_type, self.event_id, self.version, current_version));
+        }
+
+        let mut payload = self.payload;
+        for version in self.version.max(1)..current_version {
+            payload = upcast_event_payload(&self.event_type, version, payload);
+        }
+
+        let mut tagged_event = serde_json::Map::new();
+        tagged_event.insert(self.event_type.clone(), payload);
+        match serde_json::from_value::<Event>(Value::Object(tagged_event)) {
+            Ok(event) => Ok(event),
+            Err(e) => {
+                event!(Level::WARN, "Failed to deserialize {} {} version {}: {}", self.event_type, self.event_id, self.version, e);
+                Err(format!("Failed to deserialize {} {} version {}: {}", self.event_type, self.event_id, self.version, e))
+            }
+        }
+    }
 }
 
+// Brings a payload from the given version of its event type to the next one
+fn upcast_event_payload(event_type: &str, version: u32, payload: Value) -> Value {
+    match (event_type, version) {
+        // Version 2 added recurring pricing, tax codes and shipping details, read as their defaults from version 1
+        ("ProductCreatedEvent", 1) => payload,
+        // Version 2 added the customer's email, read as its default from version 1
+        ("OrderCreatedEvent", 1) => payload,
+        // Version 2 added tax and shipping, read as their defaults from version 1
+        ("PaymentSucceededEvent", 1) => payload,
+        _ => payload
+    }
+}
+
+#[async_trait]
 pub trait MessageBroker {
     async fn publish_message(&self, event: &Event) -> Result<(), String>;
-    async fn consume(&self, source_queue_name: &'static str, state: Arc<AppState>);
+    // Publishes the event under the given id, so consumers can tell a republished event from a new one
+    async fn publish_event(&self, event_id: &str, event: &Event) -> Result<(), String>;
+    // Publishes the event and waits for a single reply correlated to it, for when the other service has to answer
+    // before we can carry on
+    async fn request(&self, event: &Event, timeout: Duration) -> Result<Event, String>;
+    // Declares the topology of every registered handler and starts its consumers, which run until the broker goes away
+    async fn start_consumers(&self, event_handler_registry: &EventHandlerRegistry) -> Result<(), String>;
+    fn is_connected(&self) -> bool;
+    // Stops the consumers, waits up to the timeout for the events being handled and closes the connection
+    async fn shutdown(&self, timeout: Duration);
+}
+
+// Where a handler's events come from and which event types it accepts, anything else arriving on its queue is skipped
+// without reaching it
+pub struct EventSubscription {
+    pub queue_name: &'static str,
+    pub exchange_name: &'static str,
+    pub routing_key: &'static str,
+    pub event_types: Vec<&'static str>,
+}
+
+impl EventSubscription {
+    // Other services publish to a fanout exchange named after the queue
+    pub fn for_queue(queue_name: &'static str, event_types: Vec<&'static str>) -> Self {
+        EventSubscription {
+            queue_name,
+            exchange_name: queue_name,
+            routing_key: "",
+            event_types,
+        }
+    }
+}
+
+#[async_trait]
+pub trait EventHandler {
+    fn subscriptions(&self) -> Vec<EventSubscription>;
+    async fn handle(&self, event: Event) -> Result<(), String>;
+}
+
+#[derive(Clone)]
+pub struct EventHandlerRegistry {
+    event_handlers: Vec<Arc<dyn EventHandler + Send + Sync>>,
+    prefetch_count: u16,
+    concurrency: usize,
+}
+
+impl EventHandlerRegistry {
+    // Each subscription gets concurrency consumers on their own channels, each holding at most prefetch_count
+    // unacknowledged messages
+    pub fn new(prefetch_count: u16, concurrency: usize) -> Self {
+        EventHandlerRegistry {
+            event_handlers: Vec::new(),
+            prefetch_count,
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    pub fn register(&mut self, event_handler: Arc<dyn EventHandler + Send + Sync>) {
+        self.event_handlers.push(event_handler);
+    }
+}
+
+enum PublishConfirmation {
+    Acked,
+    Nacked,
+    Returned(String),
+    ChannelClosed(String),
+}
+
+// Hands the broker's confirmation of the message last published on the channel to whoever is waiting on it. A mandatory
+// message that cannot be routed is returned before it is acked.
+struct PublishConfirmCallback {
+    returned: Option<String>,
+    confirmation_sender: Arc<Mutex<Option<oneshot::Sender<PublishConfirmation>>>>,
+}
+
+impl PublishConfirmCallback {
+    fn confirm(&mut self, confirmation: PublishConfirmation) {
+        if let Some(confirmation_sender) = self.confirmation_sender.lock().unwrap().take() {
+            let _ = confirmation_sender.send(confirmation);
+        }
+    }
+}
+
+// The channel events are published on. Only one event is in flight on it at a time, so the confirmation that comes back
+// is always for the event waiting on it.
+struct PublishChannel {
+    channel: Channel,
+    confirmation_sender: Arc<Mutex<Option<oneshot::Sender<PublishConfirmation>>>>,
+    // Destinations whose default topology was declared, declaring it again on every publish would cost a round trip
+    declared_destinations: HashSet<String>,
+}
+
+#[async_trait]
+impl ChannelCallback for PublishConfirmCallback {
+    async fn close(&mut self, _: &Channel, close: CloseChannel) -> Result<(), amqprs::error::Error> {
+        self.confirm(PublishConfirmation::ChannelClosed(close.to_string()));
+        Ok(())
+    }
+
+    async fn cancel(&mut self, _: &Channel, _: Cancel) -> Result<(), amqprs::error::Error> {
+        Ok(())
+    }
+
+    async fn flow(&mut self, _: &Channel, _: bool) -> Result<bool, amqprs::error::Error> {
+        Ok(true)
+    }
+
+    async fn publish_ack(&mut self, _: &Channel, _: Ack) {
+        let confirmation = match self.returned.take() {
+            Some(reason) => PublishConfirmation::Returned(reason),
+            None => PublishConfirmation::Acked
+        };
+        self.confirm(confirmation);
+    }
+
+    async fn publish_nack(&mut self, _: &Channel, _: Nack) {
+        self.confirm(PublishConfirmation::Nacked);
+    }
+
+    async fn publish_return(&mut self, _: &Channel, ret: Return, _: BasicProperties, _: Vec<u8>) {
+        self.returned = Some(ret.to_string());
+    }
+}
+
+// Counts a handler as in progress for as long as it is held, so it is counted out however consume returns
+struct HandlerInProgress(Arc<AtomicUsize>);
+
+impl HandlerInProgress {
+    fn start(handlers_in_progress: &Arc<AtomicUsize>) -> Self {
+        handlers_in_progress.fetch_add(1, Ordering::SeqCst);
+        HandlerInProgress(handlers_in_progress.clone())
+    }
+}
+
+impl Drop for HandlerInProgress {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// What becomes of a delivery once the consumer is done with it
+enum Settlement {
+    Ack,
+    // Delivered again, to this or another instance
+    Requeue,
+    // Routed to the queue's dead letter exchange when the topology gives it one, dropped otherwise
+    DeadLetter,
+}
+
+struct EventHandlerConsumer {
+    event_handler: Arc<dyn EventHandler + Send + Sync>,
+    queue_name: &'static str,
+    event_types: Vec<&'static str>,
+    handlers_in_progress: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl AsyncConsumer for EventHandlerConsumer {
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        _: BasicProperties,
+        content: Vec<u8>,
+    ){
+        let _handler_in_progress = HandlerInProgress::start(&self.handlers_in_progress);
+        event!(Level::DEBUG, "Received event on {}: {}", self.queue_name, String::from_utf8_lossy(&content));
+
+        // An event that cannot be read will not be readable the next time either, while a failed handler gets one more
+        // attempt in case it failed on something passing, e.g. a lost database connection
+        let settlement = match EventEnvelope::decode(&content) {
+            Ok(envelope) if !self.event_types.contains(&envelope.event_type.as_str()) => {
+                event!(Level::INFO, "Event {} is not supported on {}", envelope.event_type, self.queue_name);
+                Settlement::Ack
+            },
+            Ok(envelope) => match envelope.into_event() {
+                Ok(deserialized_event) => match self.event_handler.handle(deserialized_event).await {
+                    Ok(()) => Settlement::Ack,
+                    Err(e) if deliver.redelivered() => {
+                        event!(Level::WARN, "Failed to handle redelivered event on {}, dead lettering it: {}", self.queue_name, e);
+                        Settlement::DeadLetter
+                    },
+                    Err(e) => {
+                        event!(Level::WARN, "Failed to handle event on {}, requeueing it: {}", self.queue_name, e);
+                        Settlement::Requeue
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Failed to deserialize event on {}: {}", self.queue_name, e);
+                    Settlement::DeadLetter
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Failed to deserialize event on {}: {}", self.queue_name, e);
+                Settlement::DeadLetter
+            }
+        };
+
+        // Settled once handled so prefetch bounds the work in progress
+        let settled = match settlement {
+            Settlement::Ack => channel.basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false)).await,
+            Settlement::Requeue => channel.basic_nack(BasicNackArguments::new(deliver.delivery_tag(), false, true)).await,
+            Settlement::DeadLetter => channel.basic_nack(BasicNackArguments::new(deliver.delivery_tag(), false, false)).await
+        };
+        if let Err(e) = settled {
+            event!(Level::WARN, "Failed to settle event on {}: {}", self.queue_name, e);
+        }
+    }
 }
 
 pub struct RabbitMqMessageBroker {
-    connection: Connection,
+    init_info: RabbitMqInitializationInfo,
+    connection: RwLock<Connection>,
+    // Kept so the consumers can be resumed on a new connection
+    event_handler_registry: Mutex<Option<EventHandlerRegistry>>,
+    initial_reconnect_backoff: Duration,
+    max_reconnect_backoff: Duration,
+    publish_confirm_timeout: Duration,
+    topology: RabbitMqTopology,
+    // The channel and tag of every running consumer, to cancel them on shutdown
+    consumers: Mutex<Vec<(Channel, String)>>,
+    // Opened on first use and replaced once it closes, e.g. along with a lost connection
+    publish_channel: tokio::sync::Mutex<Option<PublishChannel>>,
+    handlers_in_progress: Arc<AtomicUsize>,
+    shutting_down: AtomicBool,
 }
 
 impl RabbitMqMessageBroker {
-    pub async fn new(init_info: RabbitMqInitializationInfo) -> Result<RabbitMqMessageBroker, String>{
+    pub async fn new(init_info: RabbitMqInitializationInfo, initial_reconnect_backoff: Duration, max_reconnect_backoff: Duration, publish_confirm_timeout: Duration, topology: RabbitMqTopology) -> Result<RabbitMqMessageBroker, String>{
+        let connection = Self::open_connection(&init_info).await?;
+
+        let message_broker = RabbitMqMessageBroker{
+            init_info,
+            connection: RwLock::new(connection),
+            event_handler_registry: Mutex::new(None),
+            initial_reconnect_backoff,
+            max_reconnect_backoff,
+            publish_confirm_timeout,
+            topology,
+            consumers: Mutex::new(Vec::new()),
+            publish_channel: tokio::sync::Mutex::new(None),
+            handlers_in_progress: Arc::new(AtomicUsize::new(0)),
+            shutting_down: AtomicBool::new(false),
+        };
+        message_broker.declare_topology().await?;
+
+        Ok(message_broker)
+    }
+
+    async fn declare_topology(&self) -> Result<(), String> {
+        let channel = match self.connection().open_channel(None).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                return Err(format!("Failed to get channel: {}", e));
+            }
+        };
+
+        let declared = async {
+            channel.register_callback(DefaultChannelCallback).await.map_err(|e| e.to_string())?;
+
+            for exchange in &self.topology.exchanges {
+                let exchange_declare_arguments = ExchangeDeclareArguments::new(&exchange.name, &exchange.exchange_type)
+                    .durable(exchange.durable)
+                    .auto_delete(exchange.auto_delete)
+                    .finish();
+                channel.exchange_declare(exchange_declare_arguments).await.map_err(|e| format!("Failed to declare exchange {}: {}", exchange.name, e))?;
+            }
+
+            for queue in &self.topology.queues {
+                let queue_declare_arguments = QueueDeclareArguments::new(&queue.name)
+                    .durable(queue.durable || queue.quorum)
+                    .arguments(queue.arguments()?)
+                    .finish();
+                channel.queue_declare(queue_declare_arguments).await.map_err(|e| format!("Failed to declare queue {}: {}", queue.name, e))?;
+            }
+
+            for binding in &self.topology.bindings {
+                channel.queue_bind(QueueBindArguments::new(&binding.queue, &binding.exchange, &binding.routing_key)).await
+                    .map_err(|e| format!("Failed to bind queue {} to {}: {}", binding.queue, binding.exchange, e))?;
+            }
+
+            Ok::<(), String>(())
+        }.await;
+        let _ = channel.close().await;
+
+        match declared {
+            Ok(()) => {
+                event!(Level::INFO, "Declared {} exchanges, {} queues and {} bindings", self.topology.exchanges.len(), self.topology.queues.len(), self.topology.bindings.len());
+                Ok(())
+            },
+            Err(e) => {
+                event!(Level::WARN, "Failed to declare RabbitMQ topology: {}", e);
+                Err(format!("Failed to declare RabbitMQ topology: {}", e))
+            }
+        }
+    }
+
+    async fn open_connection(init_info: &RabbitMqInitializationInfo) -> Result<Connection, String> {
         match Connection::open(&OpenConnectionArguments::new(&init_info.uri, init_info.port, &init_info.username, &init_info.password)
         ).await {
             Ok(connection) => {
                 match connection.register_callback(DefaultConnectionCallback).await {
-                    Ok(()) => {
-                        Ok(RabbitMqMessageBroker{
-                            connection: connection
-                        })
-                    },
+                    Ok(()) => Ok(connection),
                     Err(e) => {
                         Err(format!("Failed to register connection callback: {}", e))
                     }
@@ -72,15 +818,195 @@ impl RabbitMqMessageBroker {
         }
     }
 
+    fn connection(&self) -> Connection {
+        self.connection.read().unwrap().clone()
+    }
+
+    // Watches the connection and, once it is lost, reopens it with exponential backoff and resumes the registered
+    // consumers on it. Publishing fails with an error while the connection is down.
+    pub fn supervise_connection(self: Arc<Self>) {
+        tokio::spawn(async move {
+            gauge!("rabbitmq_connection_up").set(1.0);
+
+            loop {
+                let connection = self.connection();
+
+                // A network failure is reported right away, a connection closed by the broker is noticed on the next check
+                tokio::select! {
+                    _ = connection.listen_network_io_failure() => {},
+                    _ = async {
+                        while connection.is_open() {
+                            tokio::time::sleep(CONNECTION_CHECK_INTERVAL).await;
+                        }
+                    } => {}
+                }
+
+                // The connection is closed on purpose when shutting down
+                if self.shutting_down.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                event!(Level::WARN, "RabbitMQ connection lost, reconnecting");
+                gauge!("rabbitmq_connection_up").set(0.0);
+                // Closing a connection whose socket is gone can wait on a handshake that never completes
+                let _ = tokio::time::timeout(CONNECTION_CLOSE_TIMEOUT, connection.close()).await;
+
+                let mut backoff = self.initial_reconnect_backoff;
+                loop {
+                    tokio::time::sleep(backoff).await;
+                    if self.shutting_down.load(Ordering::SeqCst) {
+                        return;
+                    }
+
+                    match self.reconnect().await {
+                        Ok(()) => break,
+                        Err(e) => {
+                            event!(Level::WARN, "Failed to reconnect to RabbitMQ, retrying in {:?}: {}", backoff, e);
+                            backoff = (backoff * 2).min(self.max_reconnect_backoff);
+                        }
+                    }
+                }
+
+                event!(Level::INFO, "Reconnected to RabbitMQ");
+                gauge!("rabbitmq_connection_up").set(1.0);
+                counter!("rabbitmq_reconnections_total").increment(1);
+            }
+        });
+    }
+
+    async fn reconnect(&self) -> Result<(), String> {
+        let connection = Self::open_connection(&self.init_info).await?;
+        *self.connection.write().unwrap() = connection.clone();
+
+        if let Err(e) = self.declare_topology().await {
+            let _ = tokio::time::timeout(CONNECTION_CLOSE_TIMEOUT, connection.close()).await;
+            return Err(e);
+        }
+
+        // The consumers of the lost connection went with it
+        self.consumers.lock().unwrap().clear();
+
+        let event_handler_registry = self.event_handler_registry.lock().unwrap().clone();
+        if let Some(event_handler_registry) = event_handler_registry {
+            if let Err(e) = self.consume(&event_handler_registry).await {
+                let _ = tokio::time::timeout(CONNECTION_CLOSE_TIMEOUT, connection.close()).await;
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn consume(&self, event_handler_registry: &EventHandlerRegistry) -> Result<(), String> {
+        for event_handler in &event_handler_registry.event_handlers {
+            for subscription in event_handler.subscriptions() {
+                for consumer_number in 0..event_handler_registry.concurrency {
+                    let channel = self.get_subscription_channel(subscription.exchange_name, subscription.queue_name, subscription.routing_key).await?;
+
+                    if let Err(e) = channel.basic_qos(BasicQosArguments::new(0, event_handler_registry.prefetch_count, false)).await {
+                        event!(Level::WARN, "Failed to set prefetch on {}: {}", subscription.queue_name, e);
+                        return Err(format!("Failed to set prefetch on {}: {}", subscription.queue_name, e));
+                    }
+
+                    let consumer = EventHandlerConsumer {
+                        event_handler: event_handler.clone(),
+                        queue_name: subscription.queue_name,
+                        event_types: subscription.event_types.clone(),
+                        handlers_in_progress: self.handlers_in_progress.clone(),
+                    };
+                    let consume_arguments = BasicConsumeArguments::new(subscription.queue_name, &format!("{}-{}", EVENT_PRODUCER_NAME, consumer_number))
+                        .manual_ack(true)
+                        .finish();
+
+                    match channel.basic_consume(consumer, consume_arguments).await {
+                        Ok(consumer_tag) => self.consumers.lock().unwrap().push((channel.clone(), consumer_tag)),
+                        Err(e) => {
+                            event!(Level::WARN, "Failed to consume {}: {}", subscription.queue_name, e);
+                            return Err(format!("Failed to consume {}: {}", subscription.queue_name, e));
+                        }
+                    }
+
+                    event!(Level::INFO, "Consumer {} started on {}", consumer_number, subscription.queue_name);
+
+                    // The channel closes when dropped, so it is held until it is closed along with its connection
+                    tokio::spawn(async move {
+                        while channel.is_open() {
+                            tokio::time::sleep(CONNECTION_CHECK_INTERVAL).await;
+                        }
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn open_publish_channel(&self) -> Result<PublishChannel, String> {
+        let channel = match self.connection().open_channel(None).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                event!(Level::WARN, "Failed to get publish channel: {}", e);
+                return Err(format!("Failed to get publish channel: {}", e));
+            }
+        };
+
+        let confirmation_sender = Arc::new(Mutex::new(None));
+        let confirm_mode = async {
+            channel.register_callback(PublishConfirmCallback { returned: None, confirmation_sender: confirmation_sender.clone() }).await?;
+            channel.confirm_select(ConfirmSelectArguments::default()).await
+        }.await;
+        if let Err(e) = confirm_mode {
+            let _ = channel.close().await;
+            event!(Level::WARN, "Failed to enable publisher confirms: {}", e);
+            return Err(format!("Failed to enable publisher confirms: {}", e));
+        }
+
+        Ok(PublishChannel {
+            channel,
+            confirmation_sender,
+            declared_destinations: HashSet::new(),
+        })
+    }
+
+    async fn discard_publish_channel(publish_channel_slot: &mut Option<PublishChannel>) {
+        if let Some(publish_channel) = publish_channel_slot.take() {
+            let _ = publish_channel.channel.close().await;
+        }
+    }
+
     pub async fn get_channel(&self, destination: &str) -> Result<Channel, String>{
-        match self.connection.open_channel(None).await{
+        let (exchange_name, routing_key) = self.topology.publication(destination);
+        self.get_subscription_channel(&exchange_name, destination, &routing_key).await
+    }
+
+    // Declares the default topology for whatever the configured topology leaves out, a queue listed there is bound
+    // by the configured bindings only
+    async fn declare_default_topology(&self, channel: &Channel, exchange_name: &str, queue_name: &str, routing_key: &str) -> Result<(), amqprs::error::Error> {
+        if !self.topology.declares_exchange(exchange_name) {
+            channel.exchange_declare(ExchangeDeclareArguments::new(exchange_name, &ExchangeType::Fanout.to_string())).await?;
+        }
+        if !self.topology.declares_queue(queue_name) {
+            channel.queue_declare(QueueDeclareArguments::durable_client_named(queue_name)).await?;
+            channel.queue_bind(QueueBindArguments::new(queue_name, exchange_name, routing_key)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get_subscription_channel(&self, exchange_name: &str, queue_name: &str, routing_key: &str) -> Result<Channel, String>{
+        match self.connection().open_channel(None).await{
             Ok(channel) => {
-                channel.register_callback(DefaultChannelCallback).await.unwrap();
-                channel.exchange_declare(ExchangeDeclareArguments::new(destination, &ExchangeType::Fanout.to_string())).await.unwrap();
-                channel.queue_declare(QueueDeclareArguments::durable_client_named(destination)).await.unwrap();
-                channel.queue_bind(QueueBindArguments::new(destination, destination, "")).await.unwrap();
+                let declared = async {
+                    channel.register_callback(DefaultChannelCallback).await?;
+                    self.declare_default_topology(&channel, exchange_name, queue_name, routing_key).await
+                }.await;
 
-                Ok(channel)
+                match declared {
+                    Ok(()) => Ok(channel),
+                    Err(e) => {
+                        event!(Level::WARN, "Failed to declare {}: {}", queue_name, e);
+                        Err(format!("Failed to declare {}: {}", queue_name, e))
+                    }
+                }
             },
             Err(e) => {
                 Err(format!("Failed to get channel: {}", e))
@@ -89,32 +1015,222 @@ impl RabbitMqMessageBroker {
     }
 }
 
+#[async_trait]
 impl MessageBroker for RabbitMqMessageBroker {
     async fn publish_message(&self, event: &Event) -> Result<(), String> {
-        todo!();
+        self.publish_event(&uuid::Uuid::new_v4().to_string(), event).await
     }
 
-    async fn consume(&self, source_queue_name: &'static str, state: Arc<AppState>) {
-        match self.get_channel(source_queue_name).await {
-            Ok(channel) => {
-                let consume_arguments = BasicConsumeArguments::new(source_queue_name, "eshop-payment-service")
-                    .manual_ack(false)
-                    .finish();
-                
-                match source_queue_name {
-                    queue_name if queue_name == PRODUCT_CREATED_QUEUE_NAME => {
-                        channel.basic_consume(ProductCreatedEventHandler::new(state.clone()), consume_arguments).await.unwrap();
-                    },
-                    x => event!(Level::INFO, "event {} is not valid to subscribe to", x)
+    async fn publish_event(&self, event_id: &str, event: &Event) -> Result<(), String> {
+        let destination = event.destination();
+
+        let mut envelope = EventEnvelope::new(event, event.correlation_id())?;
+        envelope.event_id = String::from(event_id);
+        let content = match serde_json::to_vec(&envelope) {
+            Ok(content) => content,
+            Err(e) => {
+                event!(Level::WARN, "Failed to serialize event for {}: {}", destination, e);
+                return Err(format!("Failed to serialize event for {}: {}", destination, e));
+            }
+        };
+
+        // Held until the event is confirmed, publishes wait for the one before them
+        let mut publish_channel_slot = self.publish_channel.lock().await;
+        if !publish_channel_slot.as_ref().is_some_and(|publish_channel| publish_channel.channel.is_open()) {
+            *publish_channel_slot = Some(self.open_publish_channel().await?);
+        }
+        let publish_channel = publish_channel_slot.as_mut().unwrap();
+
+        let (exchange_name, routing_key) = self.topology.publication(destination);
+        if !publish_channel.declared_destinations.contains(destination) {
+            if let Err(e) = self.declare_default_topology(&publish_channel.channel, &exchange_name, destination, &routing_key).await {
+                // A failed declaration closes the channel on the broker's side
+                Self::discard_publish_channel(&mut publish_channel_slot).await;
+                event!(Level::WARN, "Failed to declare {}: {}", destination, e);
+                return Err(format!("Failed to declare {}: {}", destination, e));
+            }
+            publish_channel.declared_destinations.insert(String::from(destination));
+        }
+
+        let (confirmation_sender, confirmation_receiver) = oneshot::channel();
+        *publish_channel.confirmation_sender.lock().unwrap() = Some(confirmation_sender);
+
+        let properties = BasicProperties::default()
+            .with_content_type("application/json")
+            .with_persistence(true)
+            .with_message_id(&envelope.event_id)
+            .with_message_type(&envelope.event_type)
+            .with_timestamp(envelope.occurred_at)
+            .with_app_id(EVENT_PRODUCER_NAME)
+            .finish();
+
+        // Mandatory so an event no queue is bound to receive comes back instead of being dropped by the exchange
+        let publish_arguments = BasicPublishArguments::new(&exchange_name, &routing_key)
+            .mandatory(true)
+            .finish();
+        if let Err(e) = publish_channel.channel.basic_publish(properties, content, publish_arguments).await {
+            Self::discard_publish_channel(&mut publish_channel_slot).await;
+            event!(Level::WARN, "Failed to publish event to {}: {}", destination, e);
+            return Err(format!("Failed to publish event to {}: {}", destination, e));
+        }
+
+        match tokio::time::timeout(self.publish_confirm_timeout, confirmation_receiver).await {
+            Ok(Ok(PublishConfirmation::Acked)) => {
+                event!(Level::DEBUG, "Published event {} to {}", envelope.event_id, destination);
+                Ok(())
+            },
+            Ok(Ok(PublishConfirmation::Nacked)) => {
+                event!(Level::WARN, "RabbitMQ rejected event {} published to {}", envelope.event_id, destination);
+                Err(format!("RabbitMQ rejected event {} published to {}", envelope.event_id, destination))
+            },
+            Ok(Ok(PublishConfirmation::Returned(reason))) => {
+                event!(Level::WARN, "Event {} published to {} was not routed to a queue: {}", envelope.event_id, destination, reason);
+                Err(format!("Event {} published to {} was not routed to a queue: {}", envelope.event_id, destination, reason))
+            },
+            Ok(Ok(PublishConfirmation::ChannelClosed(reason))) => {
+                Self::discard_publish_channel(&mut publish_channel_slot).await;
+                event!(Level::WARN, "Channel closed before event {} published to {} was confirmed: {}", envelope.event_id, destination, reason);
+                Err(format!("Channel closed before event {} published to {} was confirmed: {}", envelope.event_id, destination, reason))
+            },
+            Ok(Err(_)) | Err(_) => {
+                // A late confirmation would be taken for the next event's, so the channel is not used again
+                Self::discard_publish_channel(&mut publish_channel_slot).await;
+                event!(Level::WARN, "Event {} published to {} was not confirmed within {:?}", envelope.event_id, destination, self.publish_confirm_timeout);
+                Err(format!("Event {} published to {} was not confirmed within {:?}", envelope.event_id, destination, self.publish_confirm_timeout))
+            }
+        }
+    }
+
+    async fn request(&self, event: &Event, timeout: Duration) -> Result<Event, String> {
+        let destination = event.destination();
+
+        // The reply is matched to the request by the envelope's id
+        let envelope = EventEnvelope::new(event, event.correlation_id())?;
+        let content = match serde_json::to_vec(&envelope) {
+            Ok(content) => content,
+            Err(e) => {
+                event!(Level::WARN, "Failed to serialize request for {}: {}", destination, e);
+                return Err(format!("Failed to serialize request for {}: {}", destination, e));
+            }
+        };
+
+        // Replies come back on a queue of our own which is deleted along with the channel
+        let channel = self.get_channel(destination).await?;
+        let reply_queue_name = match channel.queue_declare(QueueDeclareArguments::exclusive_server_named().auto_delete(true).finish()).await {
+            Ok(Some((reply_queue_name, _, _))) => reply_queue_name,
+            Ok(None) => {
+                let _ = channel.close().await;
+                event!(Level::WARN, "Failed to declare reply queue for {}: no queue name returned", destination);
+                return Err(format!("Failed to declare reply queue for {}: no queue name returned", destination));
+            },
+            Err(e) => {
+                let _ = channel.close().await;
+                event!(Level::WARN, "Failed to declare reply queue for {}: {}", destination, e);
+                return Err(format!("Failed to declare reply queue for {}: {}", destination, e));
+            }
+        };
+
+        let mut replies = match channel.basic_consume_rx(BasicConsumeArguments::new(&reply_queue_name, "").manual_ack(false).finish()).await {
+            Ok((_, replies)) => replies,
+            Err(e) => {
+                let _ = channel.close().await;
+                event!(Level::WARN, "Failed to consume reply queue for {}: {}", destination, e);
+                return Err(format!("Failed to consume reply queue for {}: {}", destination, e));
+            }
+        };
+
+        let correlation_id = envelope.event_id.clone();
+        let properties = BasicProperties::default()
+            .with_content_type("application/json")
+            .with_persistence(true)
+            .with_message_id(&envelope.event_id)
+            .with_message_type(&envelope.event_type)
+            .with_timestamp(envelope.occurred_at)
+            .with_app_id(EVENT_PRODUCER_NAME)
+            .with_correlation_id(&correlation_id)
+            .with_reply_to(&reply_queue_name)
+            .finish();
+
+        let (exchange_name, routing_key) = self.topology.publication(destination);
+        if let Err(e) = channel.basic_publish(properties, content, BasicPublishArguments::new(&exchange_name, &routing_key)).await {
+            let _ = channel.close().await;
+            event!(Level::WARN, "Failed to publish request to {}: {}", destination, e);
+            return Err(format!("Failed to publish request to {}: {}", destination, e));
+        }
+
+        // Anything else on the queue answers a request this channel never made
+        let reply = tokio::time::timeout(timeout, async {
+            while let Some(message) = replies.recv().await {
+                if message.basic_properties.as_ref().and_then(|properties| properties.correlation_id()) == Some(&correlation_id) {
+                    return message.content;
                 }
+            }
+            None
+        }).await;
+        let _ = channel.close().await;
+
+        let content = match reply {
+            Ok(Some(content)) => content,
+            Ok(None) => {
+                event!(Level::WARN, "Reply queue for {} closed before a reply arrived", destination);
+                return Err(format!("Reply queue for {} closed before a reply arrived", destination));
+            },
+            Err(_) => {
+                event!(Level::WARN, "No reply to request {} on {} within {:?}", correlation_id, destination, timeout);
+                return Err(format!("No reply to request on {} within {:?}", destination, timeout));
+            }
+        };
 
-                let guard = Notify::new();
-                guard.notified().await;
+        match EventEnvelope::decode(&content).and_then(|reply_envelope| reply_envelope.into_event()) {
+            Ok(reply_event) => {
+                event!(Level::DEBUG, "Received reply to request {} on {}", correlation_id, destination);
+                Ok(reply_event)
             },
             Err(e) => {
-                panic!();
+                event!(Level::WARN, "Failed to deserialize reply from {}: {}", destination, e);
+                Err(format!("Failed to deserialize reply from {}: {}", destination, e))
+            }
+        }
+    }
+
+    async fn start_consumers(&self, event_handler_registry: &EventHandlerRegistry) -> Result<(), String> {
+        *self.event_handler_registry.lock().unwrap() = Some(event_handler_registry.clone());
+
+        self.consume(event_handler_registry).await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection().is_open()
+    }
+
+    // Unacknowledged events left when the timeout runs out are redelivered to another instance once the channels close
+    async fn shutdown(&self, timeout: Duration) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        let consumers = std::mem::take(&mut *self.consumers.lock().unwrap());
+        for (channel, consumer_tag) in &consumers {
+            if let Err(e) = channel.basic_cancel(BasicCancelArguments::new(consumer_tag)).await {
+                event!(Level::WARN, "Failed to cancel consumer {}: {}", consumer_tag, e);
             }
         }
+        event!(Level::INFO, "Cancelled {} consumers", consumers.len());
+
+        let deadline = Instant::now() + timeout;
+        while self.handlers_in_progress.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            tokio::time::sleep(HANDLER_DRAIN_CHECK_INTERVAL).await;
+        }
+
+        let handlers_in_progress = self.handlers_in_progress.load(Ordering::SeqCst);
+        if handlers_in_progress > 0 {
+            event!(Level::WARN, "{} event handlers were still in progress after {:?}", handlers_in_progress, timeout);
+        }
+
+        for (channel, _) in consumers {
+            let _ = channel.close().await;
+        }
+        let _ = tokio::time::timeout(CONNECTION_CLOSE_TIMEOUT, self.connection().close()).await;
+        gauge!("rabbitmq_connection_up").set(0.0);
+        event!(Level::INFO, "RabbitMQ connection closed");
     }
 }
 
@@ -131,35 +1247,191 @@ impl ProductCreatedEventHandler {
 }
 
 #[async_trait]
-impl AsyncConsumer for ProductCreatedEventHandler {
-    async fn consume(
-        &mut self,
-        _: &Channel,
-        _: Deliver,
-        _: BasicProperties,
-        content: Vec<u8>,
-    ){
-        let raw_event = String::from_utf8(content).unwrap();
-        event!(Level::DEBUG, "Received event: {}", raw_event);
-
-        match serde_json::from_str::<Event>(&raw_event) {
-            Ok(deserialized_event) => {
-                match deserialized_event {
-                    Event::ProductCreatedEvent { id, name, price } => {
-                        let create_product_pricing_command = CreateProductPricingCommand {
-                            product_id: id,
-                            product_name: name,
-                            product_price: price,
-                        };
-
-                        let _ = self.state.create_product_pricing_command_handler.handle(&create_product_pricing_command).await;
-                    },
-                    _ => event!(Level::INFO, "Event not supported")
-                }
+impl EventHandler for ProductCreatedEventHandler {
+    fn subscriptions(&self) -> Vec<EventSubscription> {
+        vec![EventSubscription::for_queue(PRODUCT_CREATED_QUEUE_NAME, vec!["ProductCreatedEvent"])]
+    }
+
+    async fn handle(&self, event: Event) -> Result<(), String> {
+        match event {
+            Event::ProductCreatedEvent { id, name, price, currency, interval, interval_count, tax_code, shippable, weight_grams } => {
+                let create_product_pricing_command = CreateProductPricingCommand {
+                    product_id: id,
+                    product_name: name,
+                    product_price: price,
+                    currency,
+                    interval,
+                    interval_count,
+                    tax_code,
+                    shippable,
+                    weight_grams,
+                };
+
+                self.state.create_product_pricing_command_handler.handle(&create_product_pricing_command).await.map(|_| ())
             },
-            Err(e) => {
-                event!(Level::WARN, "Failed to deserialize event {}: {}", raw_event, e);
-            }
+            _ => Err(String::from("Event not supported"))
+        }
+    }
+}
+
+pub struct OrderCreatedEventHandler {
+    state: Arc<AppState>,
+}
+
+impl OrderCreatedEventHandler {
+    pub fn new(state: Arc<AppState>) -> Self {
+        OrderCreatedEventHandler {
+            state,
         }
     }
-}
\ No newline at end of file
+}
+
+#[async_trait]
+impl EventHandler for OrderCreatedEventHandler {
+    fn subscriptions(&self) -> Vec<EventSubscription> {
+        vec![EventSubscription::for_queue(ORDER_CREATED_QUEUE_NAME, vec!["OrderCreatedEvent"])]
+    }
+
+    async fn handle(&self, event: Event) -> Result<(), String> {
+        match event {
+            Event::OrderCreatedEvent { id, line_items, customer_email } => {
+                let advance_payment_saga_command = AdvancePaymentSagaCommand {
+                    order_id: id.clone(),
+                    payment_id: None,
+                    event: PaymentSagaEvent::ORDER_CREATED,
+                    detail: String::new(),
+                };
+
+                let _ = self.state.advance_payment_saga_command_handler.handle(&advance_payment_saga_command).await;
+
+                // Pre-creates the order's payment so the frontend can fetch its checkout session by order id,
+                // a redelivered event resolves to the payment already created for the order
+                let create_checkout_session_command = CreateCheckoutSessionCommand {
+                    line_items: line_items.into_iter().map(|line_item| LineItemRequestDto {
+                        product_id: line_item.product_id,
+                        quantity: line_item.quantity,
+                    }).collect(),
+                    customer_email,
+                    order_id: Some(id),
+                    currency: default_currency(),
+                    payment_processor: None,
+                    checkout_mode: None,
+                    promotion_code: None,
+                    shipping_country: None,
+                    customer: None,
+                };
+
+                self.state.create_checkout_session_command_handler.handle(&create_checkout_session_command).await.map(|_| ()).map_err(|e| e.to_string())
+            },
+            _ => Err(String::from("Event not supported"))
+        }
+    }
+}
+
+pub struct FulfillmentEventHandler {
+    state: Arc<AppState>,
+}
+
+impl FulfillmentEventHandler {
+    pub fn new(state: Arc<AppState>) -> Self {
+        FulfillmentEventHandler {
+            state,
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for FulfillmentEventHandler {
+    fn subscriptions(&self) -> Vec<EventSubscription> {
+        vec![
+            EventSubscription::for_queue(FULFILLMENT_COMPLETED_QUEUE_NAME, vec!["FulfillmentCompletedEvent"]),
+            EventSubscription::for_queue(FULFILLMENT_FAILED_QUEUE_NAME, vec!["FulfillmentFailedEvent"]),
+        ]
+    }
+
+    async fn handle(&self, event: Event) -> Result<(), String> {
+        let advance_payment_saga_command = match event {
+            Event::FulfillmentCompletedEvent { order_id } => AdvancePaymentSagaCommand {
+                order_id,
+                payment_id: None,
+                event: PaymentSagaEvent::FULFILLMENT_COMPLETED,
+                detail: String::new(),
+            },
+            Event::FulfillmentFailedEvent { order_id, reason } => AdvancePaymentSagaCommand {
+                order_id,
+                payment_id: None,
+                event: PaymentSagaEvent::FULFILLMENT_FAILED,
+                detail: reason,
+            },
+            _ => return Err(String::from("Event not supported"))
+        };
+
+        self.state.advance_payment_saga_command_handler.handle(&advance_payment_saga_command).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_first_version_product_created_event() {
+        let content = br#"{"event_id": "1", "event_type": "ProductCreatedEvent", "version": 1, "occurred_at": 0, "producer": "catalog",
+            "payload": {"id": "product-1", "name": "Mug", "price": 12.5}}"#;
+
+        match EventEnvelope::decode(content).unwrap().into_event().unwrap() {
+            Event::ProductCreatedEvent { id, price, currency, interval, interval_count, tax_code, shippable, weight_grams, .. } => {
+                assert_eq!(id, "product-1");
+                assert_eq!(price, 12.5);
+                assert_eq!(currency, None);
+                assert_eq!(interval, None);
+                assert_eq!(interval_count, None);
+                assert_eq!(tax_code, None);
+                assert!(!shippable);
+                assert_eq!(weight_grams, 0);
+            },
+            _ => panic!("Expected a ProductCreatedEvent")
+        }
+    }
+
+    #[test]
+    fn reads_first_version_payment_succeeded_event() {
+        let content = br#"{"event_id": "1", "event_type": "PaymentSucceededEvent", "version": 1, "occurred_at": 0, "producer": "payments",
+            "payload": {"payment_id": "payment-1", "order_id": "order-1", "amount": 20.0, "customer_email": "a@example.com",
+            "line_items": [{"product_id": "product-1", "quantity": 2, "price": 10.0}]}}"#;
+
+        match EventEnvelope::decode(content).unwrap().into_event().unwrap() {
+            Event::PaymentSucceededEvent { line_items, tax_amount, shipping_rate, shipping_address, .. } => {
+                assert_eq!(line_items.len(), 1);
+                assert_eq!(line_items[0].tax_amount, 0.0);
+                assert_eq!(tax_amount, 0.0);
+                assert!(shipping_rate.is_none());
+                assert!(shipping_address.is_none());
+            },
+            _ => panic!("Expected a PaymentSucceededEvent")
+        }
+    }
+
+    #[test]
+    fn rejects_event_newer_than_current_version() {
+        let content = br#"{"event_id": "1", "event_type": "OrderCreatedEvent", "version": 3, "occurred_at": 0, "producer": "orders",
+            "payload": {"id": "order-1", "line_items": []}}"#;
+
+        assert!(EventEnvelope::decode(content).unwrap().into_event().is_err());
+    }
+
+    #[test]
+    fn reads_bare_event_as_first_version() {
+        let content = br#"{"OrderCreatedEvent": {"id": "order-1", "line_items": []}}"#;
+
+        let envelope = EventEnvelope::decode(content).unwrap();
+        assert_eq!(envelope.version, 1);
+        match envelope.into_event().unwrap() {
+            Event::OrderCreatedEvent { id, customer_email, .. } => {
+                assert_eq!(id, "order-1");
+                assert_eq!(customer_email, None);
+            },
+            _ => panic!("Expected an OrderCreatedEvent")
+        }
+    }
+}
@@ -6,19 +6,27 @@ mod routes;
 mod state;
 mod auth;
 mod events;
+mod repositories;
+mod taxcalculators;
 
-use std::{env, sync::Arc};
+use std::{collections::HashMap, env, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use axum_prometheus::PrometheusMetricLayer;
-use cqrs::{CreateCheckoutSessionCommandHandler, CreateProductPricingCommandHandler};
+use cqrs::{AdvancePaymentSagaCommandHandler, CancelPaymentCommandHandler, CancelSubscriptionCommandHandler, CommandHandler, CreateCheckoutSessionCommandHandler, CreateCheckoutSessionDependencies, CreatePromotionCommandHandler, CreateProductPricingCommandHandler, CreateSetupCheckoutSessionCommandHandler, CreateSubscriptionCommandHandler, DetachPaymentMethodCommandHandler, GetCheckoutSessionQueryHandler, GetOrderPaymentQueryHandler, GetPaymentSagaQueryHandler, HandlePaymentProcessorWebhookCommandHandler, ListPaymentMethodsQueryHandler, PauseSubscriptionCommandHandler, ReconcilePaymentsCommand, ReconcilePaymentsCommandHandler, RefundPaymentCommandHandler, RelayOutboxCommand, RelayOutboxCommandHandler, ResumeSubscriptionCommandHandler, SubmitDisputeEvidenceCommandHandler, SweepStalePaymentsCommand, SweepStalePaymentsCommandHandler};
 use dotenv::dotenv;
-use axum::{middleware::from_fn_with_state, routing::{get, post}, Router};
-use events::{MessageBroker, RabbitMqInitializationInfo, RabbitMqMessageBroker};
-use paymentprocessors::StripePaymentProcessor;
-use routes::{create_checkout_session, index};
+use domain::{default_currency, ShippingConfiguration, ShippingRateType};
+use axum::{middleware::from_fn_with_state, routing::{delete, get, post}, Router};
+use events::{EventHandlerRegistry, FulfillmentEventHandler, MessageBroker, OrderCreatedEventHandler, ProductCreatedEventHandler, RabbitMqInitializationInfo, RabbitMqMessageBroker, RabbitMqTopology};
+use paymentprocessors::{FakePaymentProcessor, PayPalPaymentProcessor, PaymentProcessorRegistry, StripePaymentProcessor, FAKE_PAYMENT_PROCESSOR_NAME, PAYPAL_PAYMENT_PROCESSOR_NAME, STRIPE_PAYMENT_PROCESSOR_NAME};
+use repositories::{MongoDbCustomerRepository, MongoDbIdempotencyKeyRepository, MongoDbPaymentRepository, MongoDbPaymentSagaRepository, MongoDbProductRepository, MongoDbPromotionRepository, MongoDbReconciliationReportRepository, MongoDbSubscriptionRepository};
+use routes::{cancel_payment, cancel_subscription, create_checkout_session, create_promotion, create_setup_checkout_session, create_subscription, detach_payment_method, get_checkout_session, get_order_payment, get_payment_saga, handle_payment_processor_webhook, health, index, list_payment_methods, pause_subscription, reconcile_payments, refund_payment, resume_subscription, submit_dispute_evidence};
 use state::AppState;
+use taxcalculators::{LocalTaxCalculator, NoTaxCalculator, StripeTaxCalculator, TaxCalculator, LOCAL_TAX_CALCULATOR_NAME, NO_TAX_CALCULATOR_NAME, STRIPE_TAX_CALCULATOR_NAME};
+use futures_util::future::join_all;
+use tokio::{signal::{self, unix::SignalKind}, sync::watch};
 use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tracing::{event, Level};
 
 #[tokio::main]
 async fn main() {
@@ -36,43 +44,350 @@ async fn main() {
     .with_writer(std::fs::File::create(String::from(env::var("LOG_PATH").unwrap())).unwrap())
     .init();
 
-    let message_broker = Arc::new(RabbitMqMessageBroker::new(RabbitMqInitializationInfo::new(String::from(env::var("RABBITMQ_URI").unwrap()), env::var("RABBITMQ_PORT").unwrap().parse().unwrap(), String::from(env::var("RABBITMQ_USER").unwrap()), String::from(env::var("RABBITMQ_PASS").unwrap()))).await.unwrap());
-    let payment_processor = Arc::new(StripePaymentProcessor::new(String::from(env::var("PAYMENT_REDIRECT_BASE_URL").unwrap())));
-    let create_checkout_session_command_handler = Arc::new(CreateCheckoutSessionCommandHandler::new(payment_processor.clone()));
-    let create_product_pricing_command_handler = Arc::new(CreateProductPricingCommandHandler::new(payment_processor.clone()));
+    let initial_reconnect_backoff = Duration::from_millis(env::var("RABBITMQ_RECONNECT_INITIAL_BACKOFF_MILLISECONDS").unwrap_or(String::from("500")).parse().unwrap());
+    let max_reconnect_backoff = Duration::from_millis(env::var("RABBITMQ_RECONNECT_MAX_BACKOFF_MILLISECONDS").unwrap_or(String::from("30000")).parse().unwrap());
+    let publish_confirm_timeout = Duration::from_millis(env::var("RABBITMQ_PUBLISH_CONFIRM_TIMEOUT_MILLISECONDS").unwrap_or(String::from("5000")).parse().unwrap());
+    // RABBITMQ_TOPOLOGY is a JSON object of exchanges, queues, bindings and publications to declare, e.g. '{"exchanges":
+    // [{"name": "payments", "exchange_type": "topic"}], "publications": [{"destination": "payment.succeeded", "exchange":
+    // "payments", "routing_key": "payment.succeeded"}]}', anything not listed gets a fanout exchange and queue per destination.
+    // Events a consumed queue cannot handle are only kept when it is listed with a dead_letter_exchange.
+    let rabbitmq_topology: RabbitMqTopology = serde_json::from_str(&env::var("RABBITMQ_TOPOLOGY").unwrap_or(String::from("{}"))).unwrap();
+    let message_broker = Arc::new(RabbitMqMessageBroker::new(RabbitMqInitializationInfo::new(String::from(env::var("RABBITMQ_URI").unwrap()), env::var("RABBITMQ_PORT").unwrap().parse().unwrap(), String::from(env::var("RABBITMQ_USER").unwrap()), String::from(env::var("RABBITMQ_PASS").unwrap())), initial_reconnect_backoff, max_reconnect_backoff, publish_confirm_timeout, rabbitmq_topology).await.unwrap());
+    let mongo_client = mongodb::Client::with_uri_str(env::var("MONGODB_URI").unwrap()).await.unwrap();
+    let mongo_database = mongo_client.database(&env::var("MONGODB_DATABASE").unwrap());
+    let payment_repository = Arc::new(MongoDbPaymentRepository::new(&mongo_database));
+    let reconciliation_report_repository = Arc::new(MongoDbReconciliationReportRepository::new(&mongo_database));
+    let idempotency_key_repository = Arc::new(MongoDbIdempotencyKeyRepository::new(&mongo_database));
+    let subscription_repository = Arc::new(MongoDbSubscriptionRepository::new(&mongo_database));
+    let customer_repository = Arc::new(MongoDbCustomerRepository::new(&mongo_database));
+    customer_repository.create_indexes().await.unwrap();
+    let promotion_repository = Arc::new(MongoDbPromotionRepository::new(&mongo_database));
+    promotion_repository.create_indexes().await.unwrap();
+    let payment_saga_repository = Arc::new(MongoDbPaymentSagaRepository::new(&mongo_database));
+    payment_saga_repository.create_indexes().await.unwrap();
+    let product_repository = Arc::new(MongoDbProductRepository::new(&mongo_database));
+    idempotency_key_repository.create_indexes().await.unwrap();
+    // Stripe accepts checkout session expiries between 30 minutes and 24 hours, 24 hours being its own default
+    let checkout_session_expiry_minutes: u64 = env::var("CHECKOUT_SESSION_EXPIRY_MINUTES").unwrap_or(String::from("1440")).parse().unwrap();
+    // PAYMENT_PROCESSORS lists the processors to enable, e.g. 'stripe,paypal', and PAYMENT_PROCESSORS_BY_CURRENCY routes
+    // currencies to one of them, e.g. 'eur=paypal,gbp=paypal', anything else goes to DEFAULT_PAYMENT_PROCESSOR
+    let payment_processors_by_currency: HashMap<String, String> = env::var("PAYMENT_PROCESSORS_BY_CURRENCY").unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .map(|(currency, name)| (currency.trim().to_lowercase(), String::from(name.trim())))
+        .collect();
+    let mut payment_processor_registry = PaymentProcessorRegistry::new(env::var("DEFAULT_PAYMENT_PROCESSOR").unwrap_or(String::from(STRIPE_PAYMENT_PROCESSOR_NAME)), payment_processors_by_currency);
+    for payment_processor_name in env::var("PAYMENT_PROCESSORS").unwrap_or(String::from(STRIPE_PAYMENT_PROCESSOR_NAME)).split(',').map(|name| name.trim()) {
+        match payment_processor_name {
+            name if name == STRIPE_PAYMENT_PROCESSOR_NAME => payment_processor_registry.register(name, Arc::new(StripePaymentProcessor::new(String::from(env::var("PAYMENT_REDIRECT_BASE_URL").unwrap()), checkout_session_expiry_minutes.clamp(30, 1440) * 60))),
+            name if name == PAYPAL_PAYMENT_PROCESSOR_NAME => payment_processor_registry.register(name, Arc::new(PayPalPaymentProcessor::new())),
+            name if name == FAKE_PAYMENT_PROCESSOR_NAME => payment_processor_registry.register(name, Arc::new(FakePaymentProcessor::new())),
+            name => panic!("Unknown payment processor {}", name)
+        }
+    }
+    let payment_processors = Arc::new(payment_processor_registry);
+    // TAX_CALCULATOR is none to charge line items tax-free, stripe to use Stripe Tax, or local to apply TAX_RATES, e.g.
+    // 'txcd_99999999=0.2,txcd_20030000=0.05', with DEFAULT_TAX_RATE for products whose tax code is not listed
+    let tax_calculator: Arc<dyn TaxCalculator + Send + Sync> = match env::var("TAX_CALCULATOR").unwrap_or(String::from(NO_TAX_CALCULATOR_NAME)).trim() {
+        name if name == NO_TAX_CALCULATOR_NAME => Arc::new(NoTaxCalculator::new()),
+        name if name == STRIPE_TAX_CALCULATOR_NAME => Arc::new(StripeTaxCalculator::new()),
+        name if name == LOCAL_TAX_CALCULATOR_NAME => {
+            let tax_rates_by_tax_code: HashMap<String, f32> = env::var("TAX_RATES").unwrap_or_default()
+                .split(',')
+                .filter_map(|entry| entry.split_once('='))
+                .map(|(tax_code, rate)| (String::from(tax_code.trim()), rate.trim().parse().unwrap()))
+                .collect();
+            Arc::new(LocalTaxCalculator::new(tax_rates_by_tax_code, env::var("DEFAULT_TAX_RATE").unwrap_or(String::from("0")).parse().unwrap()))
+        },
+        name => panic!("Unknown tax calculator {}", name)
+    };
+    // SHIPPING_OPTIONS is a JSON list of shipping options, e.g. '[{"id": "standard", "display_name": "Standard", "rate_type":
+    // "FreeOverThreshold", "amount": 5.0, "free_over_amount": 50.0}]', offered for addresses in SHIPPING_ALLOWED_COUNTRIES
+    let shipping_configuration = ShippingConfiguration {
+        allowed_countries: env::var("SHIPPING_ALLOWED_COUNTRIES").unwrap_or_default().split(',').map(|country| country.trim().to_uppercase()).filter(|country| !country.is_empty()).collect(),
+        options: serde_json::from_str(&env::var("SHIPPING_OPTIONS").unwrap_or(String::from("[]"))).unwrap(),
+    };
+    let shipping_rate_types = [ShippingRateType::FLAT.to_string(), ShippingRateType::WEIGHT_BASED.to_string(), ShippingRateType::FREE_OVER_THRESHOLD.to_string()];
+    for shipping_option in &shipping_configuration.options {
+        if !shipping_rate_types.contains(&shipping_option.rate_type) {
+            panic!("Shipping option {} has unknown rate type {}", shipping_option.id, shipping_option.rate_type);
+        }
+    }
+    // Setting the timeout to 0 turns inventory reservations off, e.g. where no inventory service is running
+    let inventory_reservation_timeout_seconds: u64 = env::var("INVENTORY_RESERVATION_TIMEOUT_SECONDS").unwrap_or(String::from("5")).parse().unwrap();
+    let inventory_reservation_timeout = Some(Duration::from_secs(inventory_reservation_timeout_seconds)).filter(|timeout| !timeout.is_zero());
+
+    let refund_payment_command_handler = Arc::new(RefundPaymentCommandHandler::new(payment_processors.clone(), payment_repository.clone(), message_broker.clone()));
+    let advance_payment_saga_command_handler = Arc::new(AdvancePaymentSagaCommandHandler::new(payment_saga_repository.clone(), payment_repository.clone(), refund_payment_command_handler.clone(), message_broker.clone()));
+    let get_payment_saga_query_handler = Arc::new(GetPaymentSagaQueryHandler::new(payment_saga_repository.clone()));
+    let create_checkout_session_command_handler = Arc::new(CreateCheckoutSessionCommandHandler::new(CreateCheckoutSessionDependencies {
+        payment_processors: payment_processors.clone(),
+        payment_repository: payment_repository.clone(),
+        customer_repository: customer_repository.clone(),
+        promotion_repository: promotion_repository.clone(),
+        product_repository: product_repository.clone(),
+        tax_calculator: tax_calculator.clone(),
+        shipping_configuration,
+        message_broker: message_broker.clone(),
+        inventory_reservation_timeout,
+        advance_payment_saga_command_handler: advance_payment_saga_command_handler.clone(),
+    }));
+    let create_product_pricing_command_handler = Arc::new(CreateProductPricingCommandHandler::new(payment_processors.clone(), product_repository.clone(), env::var("DEFAULT_PRODUCT_CURRENCY").unwrap_or(default_currency())));
+    let get_checkout_session_query_handler = Arc::new(GetCheckoutSessionQueryHandler::new(payment_processors.clone(), payment_repository.clone(), message_broker.clone(), advance_payment_saga_command_handler.clone()));
+    let get_order_payment_query_handler = Arc::new(GetOrderPaymentQueryHandler::new(payment_repository.clone()));
+    let cancel_payment_command_handler = Arc::new(CancelPaymentCommandHandler::new(payment_processors.clone(), payment_repository.clone(), message_broker.clone(), advance_payment_saga_command_handler.clone()));
+    let sweep_stale_payments_command_handler = Arc::new(SweepStalePaymentsCommandHandler::new(payment_processors.clone(), payment_repository.clone(), message_broker.clone(), advance_payment_saga_command_handler.clone()));
+    let relay_outbox_command_handler = Arc::new(RelayOutboxCommandHandler::new(payment_repository.clone(), message_broker.clone()));
+    let final_relay_outbox_command_handler = relay_outbox_command_handler.clone();
+    let handle_payment_processor_webhook_command_handler = Arc::new(HandlePaymentProcessorWebhookCommandHandler::new(payment_processors.clone(), payment_repository.clone(), subscription_repository.clone(), message_broker.clone(), advance_payment_saga_command_handler.clone()));
+    let create_subscription_command_handler = Arc::new(CreateSubscriptionCommandHandler::new(payment_processors.clone(), subscription_repository.clone(), customer_repository.clone(), product_repository.clone()));
+    let cancel_subscription_command_handler = Arc::new(CancelSubscriptionCommandHandler::new(payment_processors.clone(), subscription_repository.clone()));
+    let pause_subscription_command_handler = Arc::new(PauseSubscriptionCommandHandler::new(payment_processors.clone(), subscription_repository.clone()));
+    let resume_subscription_command_handler = Arc::new(ResumeSubscriptionCommandHandler::new(payment_processors.clone(), subscription_repository.clone()));
+    let list_payment_methods_query_handler = Arc::new(ListPaymentMethodsQueryHandler::new(payment_processors.clone(), customer_repository.clone()));
+    let detach_payment_method_command_handler = Arc::new(DetachPaymentMethodCommandHandler::new(payment_processors.clone(), customer_repository.clone()));
+    let create_setup_checkout_session_command_handler = Arc::new(CreateSetupCheckoutSessionCommandHandler::new(payment_processors.clone(), customer_repository.clone()));
+    let create_promotion_command_handler = Arc::new(CreatePromotionCommandHandler::new(promotion_repository.clone()));
+    let submit_dispute_evidence_command_handler = Arc::new(SubmitDisputeEvidenceCommandHandler::new(payment_processors.clone(), payment_repository.clone()));
+    let reconcile_payments_command_handler = Arc::new(ReconcilePaymentsCommandHandler::new(payment_processors.clone(), payment_repository.clone(), reconciliation_report_repository.clone()));
 
     let state = Arc::new(AppState {
         create_checkout_session_command_handler: create_checkout_session_command_handler,
         create_product_pricing_command_handler: create_product_pricing_command_handler,
+        get_checkout_session_query_handler,
+        get_order_payment_query_handler,
+        refund_payment_command_handler,
+        cancel_payment_command_handler,
+        reconcile_payments_command_handler: reconcile_payments_command_handler.clone(),
+        handle_payment_processor_webhook_command_handler,
+        create_subscription_command_handler,
+        cancel_subscription_command_handler,
+        pause_subscription_command_handler,
+        resume_subscription_command_handler,
+        list_payment_methods_query_handler,
+        detach_payment_method_command_handler,
+        create_setup_checkout_session_command_handler,
+        create_promotion_command_handler,
+        advance_payment_saga_command_handler,
+        get_payment_saga_query_handler,
+        submit_dispute_evidence_command_handler,
+        message_broker: message_broker.clone(),
+        idempotency_key_repository,
+        idempotency_key_ttl_seconds: env::var("IDEMPOTENCY_KEY_TTL_SECONDS").unwrap_or(String::from("86400")).parse().unwrap(),
         auth0_domain: String::from(env::var("AUTH0_DOMAIN").unwrap()),
         auth0_audience: String::from(env::var("AUTH0_AUDIENCE").unwrap()),
+        auth0_admin_scope: env::var("AUTH0_ADMIN_SCOPE").unwrap_or(String::from("admin:payments")),
     });
 
     let (prometheus_layer, metrics_handle) = PrometheusMetricLayer::pair();
 
+    let shutdown_timeout = Duration::from_secs(env::var("SHUTDOWN_TIMEOUT_SECONDS").unwrap_or(String::from("20")).parse().unwrap());
+
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", env::var("AXUM_PORT").unwrap())).await.unwrap();
 
-    let state_clone1 = state.clone();
-    let message_broker_clone1 = message_broker.clone();
-    tokio::spawn(async move {
-        message_broker_clone1.consume(events::PRODUCT_CREATED_QUEUE_NAME, state_clone1).await;
-    });
+    let mut event_handler_registry = EventHandlerRegistry::new(env::var("RABBITMQ_PREFETCH_COUNT").unwrap_or(String::from("10")).parse().unwrap(), env::var("RABBITMQ_CONSUMER_CONCURRENCY").unwrap_or(String::from("1")).parse().unwrap());
+    event_handler_registry.register(Arc::new(ProductCreatedEventHandler::new(state.clone())));
+    event_handler_registry.register(Arc::new(OrderCreatedEventHandler::new(state.clone())));
+    event_handler_registry.register(Arc::new(FulfillmentEventHandler::new(state.clone())));
+    message_broker.start_consumers(&event_handler_registry).await.unwrap();
+    message_broker.clone().supervise_connection();
+
+    // The scheduled loops stop once this is set, a run already in progress is finished first
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let mut scheduled_loops = Vec::new();
+
+    let sweep_interval_seconds: u64 = env::var("STALE_PAYMENT_SWEEP_INTERVAL_SECONDS").unwrap_or(String::from("300")).parse().unwrap();
+    let stale_payment_threshold_seconds: u64 = env::var("STALE_PAYMENT_THRESHOLD_SECONDS").unwrap_or(String::from("3600")).parse().unwrap();
+    let mut sweep_shutdown_receiver = shutdown_receiver.clone();
+    scheduled_loops.push(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(sweep_interval_seconds));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = sweep_shutdown_receiver.changed() => break
+            }
+
+            match sweep_stale_payments_command_handler.handle(&SweepStalePaymentsCommand { older_than_seconds: stale_payment_threshold_seconds }).await {
+                Ok(response) => event!(Level::INFO, "Stale payment sweep checked {} payments, transitioned {}, resumed {} compensating sagas", response.checked, response.transitioned, response.resumed_sagas),
+                Err(e) => event!(Level::WARN, "Stale payment sweep failed: {}", e)
+            }
+        }
+    }));
+
+    // Events are only relayed once they are older than one interval, so the relay does not race the handler that just
+    // recorded them
+    let outbox_relay_interval_seconds: u64 = env::var("OUTBOX_RELAY_INTERVAL_SECONDS").unwrap_or(String::from("10")).parse().unwrap();
+    let mut outbox_relay_shutdown_receiver = shutdown_receiver.clone();
+    scheduled_loops.push(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(outbox_relay_interval_seconds));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = outbox_relay_shutdown_receiver.changed() => break
+            }
+
+            match relay_outbox_command_handler.handle(&RelayOutboxCommand { older_than_seconds: outbox_relay_interval_seconds }).await {
+                Ok(response) if response.payments > 0 => event!(Level::INFO, "Outbox relay published {} events of {} payments", response.published, response.payments),
+                Ok(_) => {},
+                Err(e) => event!(Level::WARN, "Outbox relay failed: {}", e)
+            }
+        }
+    }));
+
+    // Each scheduled run reconciles the window since the previous run, the first tick is skipped so a restart does not
+    // immediately produce a report for a window that was already covered
+    let reconciliation_interval_seconds: u64 = env::var("RECONCILIATION_INTERVAL_SECONDS").unwrap_or(String::from("86400")).parse().unwrap();
+    let reconciled_payment_processor_names = payment_processors.names();
+    let mut reconciliation_shutdown_receiver = shutdown_receiver;
+    scheduled_loops.push(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(reconciliation_interval_seconds));
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = reconciliation_shutdown_receiver.changed() => break
+            }
+
+            let created_to = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            for payment_processor_name in &reconciled_payment_processor_names {
+                let reconcile_payments_command = ReconcilePaymentsCommand {
+                    created_from: created_to.saturating_sub(reconciliation_interval_seconds),
+                    created_to,
+                    payment_processor: Some(payment_processor_name.clone()),
+                };
+
+                match reconcile_payments_command_handler.handle(&reconcile_payments_command).await {
+                    Ok(response) => event!(Level::INFO, "Scheduled reconciliation {} of {} found {} discrepancies", response.report_id, payment_processor_name, response.discrepancies.len()),
+                    Err(e) => event!(Level::WARN, "Scheduled reconciliation of {} failed: {}", payment_processor_name, e)
+                }
+            }
+        }
+    }));
 
     axum::serve(listener, Router::new()
         .route("/", 
             get(index))
 
+        .route("/health", 
+            get(health))
+
         .route("/metrics", 
             get(|| async move {metrics_handle.render()}))
         
         .route("/payments/checkout", 
             post(create_checkout_session)
             .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/checkout/session/{session_id}", 
+            get(get_checkout_session)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/orders/{order_id}", 
+            get(get_order_payment)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/{id}/refunds", 
+            post(refund_payment)
+            .route_layer(from_fn_with_state(state.clone(), auth::admin_authorization_middleware))
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/{id}/cancel", 
+            post(cancel_payment)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/subscriptions", 
+            post(create_subscription)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/subscriptions/{id}/cancel", 
+            post(cancel_subscription)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/subscriptions/{id}/pause", 
+            post(pause_subscription)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/subscriptions/{id}/resume", 
+            post(resume_subscription)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/methods", 
+            get(list_payment_methods)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/methods/setup", 
+            post(create_setup_checkout_session)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/methods/{id}", 
+            delete(detach_payment_method)
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/webhooks/{payment_processor}", 
+            post(handle_payment_processor_webhook))
+
+        .route("/payments/admin/reconciliations", 
+            post(reconcile_payments)
+            .route_layer(from_fn_with_state(state.clone(), auth::admin_authorization_middleware))
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/admin/promotions", 
+            post(create_promotion)
+            .route_layer(from_fn_with_state(state.clone(), auth::admin_authorization_middleware))
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/admin/disputes/{id}/evidence", 
+            post(submit_dispute_evidence)
+            .route_layer(from_fn_with_state(state.clone(), auth::admin_authorization_middleware))
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
+
+        .route("/payments/admin/sagas/{order_id}", 
+            get(get_payment_saga)
+            .route_layer(from_fn_with_state(state.clone(), auth::admin_authorization_middleware))
+            .route_layer(from_fn_with_state(state.clone(), auth::authentication_middleware)))
     
         .with_state(state)
         .layer(prometheus_layer)
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
-                .layer(CorsLayer::permissive()))).await.unwrap();
+                .layer(CorsLayer::permissive())))
+        .with_graceful_shutdown(shutdown_signal())
+        .await.unwrap();
+
+    // Requests in flight have finished. SHUTDOWN_TIMEOUT_SECONDS bounds the wait for scheduled runs and for event handlers
+    // and is kept below the pod's termination grace period so the connections are still closed cleanly.
+    event!(Level::INFO, "HTTP server stopped, stopping scheduled loops");
+    let _ = shutdown_sender.send(true);
+    if tokio::time::timeout(shutdown_timeout, join_all(scheduled_loops)).await.is_err() {
+        event!(Level::WARN, "Scheduled runs were still in progress after {:?}", shutdown_timeout);
+    }
+
+    // Events recorded by the last requests and runs would otherwise wait in the outbox until another instance relays them
+    match final_relay_outbox_command_handler.handle(&RelayOutboxCommand { older_than_seconds: 0 }).await {
+        Ok(response) if response.remaining > 0 => event!(Level::WARN, "Final outbox relay published {} events, {} are left in the outbox of {} payments", response.published, response.remaining, response.payments),
+        Ok(response) => event!(Level::INFO, "Final outbox relay published {} events", response.published),
+        Err(e) => event!(Level::WARN, "Final outbox relay failed, events are left in the outbox: {}", e)
+    }
+
+    event!(Level::INFO, "Scheduled loops stopped, shutting down consumers");
+    message_broker.shutdown(shutdown_timeout).await;
+
+    if tokio::time::timeout(Duration::from_secs(5), mongo_client.shutdown()).await.is_err() {
+        event!(Level::WARN, "Timed out closing the MongoDB client");
+    }
+    event!(Level::INFO, "Shutdown complete");
+}
+
+async fn shutdown_signal() {
+    let terminate = async {
+        signal::unix::signal(SignalKind::terminate()).unwrap().recv().await;
+    };
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {},
+        _ = terminate => {}
+    }
+
+    event!(Level::INFO, "Shutdown signal received, draining HTTP requests");
 }
@@ -1,43 +1,437 @@
-use std::{env, str::FromStr};
+use std::{collections::HashMap, env, str::FromStr, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 
 use async_trait::async_trait;
+use hmac::{Hmac, Mac};
 use reqwest::Url;
+use sha2::Sha256;
 use tracing::{event, Level};
 
-use crate::{domain::Payment, dtos::{PaymentProcessorCreateCheckoutSessionRequestDto, PaymentProcessorCreatePricingRequestDto, PaymentProcessorCreateProductRequestDto, PaymentProcessorLineItemRequestDto}};
+use crate::{domain::{CheckoutMode, Customer, Discount, Payment, Refund, Subscription}, dtos::{PaymentProcessorAddressDto, PaymentProcessorAutomaticTaxDto, PaymentProcessorCancelSubscriptionAtPeriodEndRequestDto, PaymentProcessorCheckoutSessionLineItemResponseDto, PaymentProcessorCheckoutSessionResponseDto, PaymentProcessorCouponMetadataDto, PaymentProcessorCouponRequestDto, PaymentProcessorCouponResponseDto, PaymentProcessorCreateCheckoutSessionRequestDto, PaymentProcessorCreateCheckoutSessionResponseDto, PaymentProcessorCreatedRangeDto, PaymentProcessorCreatePricingRequestDto, PaymentProcessorCreateProductRequestDto, PaymentProcessorCreateRefundRequestDto, PaymentProcessorCreateSetupCheckoutSessionRequestDto, PaymentProcessorCreateSubscriptionCheckoutSessionRequestDto, PaymentProcessorCustomerDetailsDto, PaymentProcessorCustomerMetadataDto, PaymentProcessorCustomerRequestDto, PaymentProcessorCustomerResponseDto, PaymentProcessorCustomerUpdateRequestDto, PaymentProcessorDiscountRequestDto, PaymentProcessorDisputeEvidenceFileDto, PaymentProcessorDisputeResponseDto, PaymentProcessorDisputeUpdateRequestDto, PaymentProcessorFileResponseDto, PaymentProcessorFixedAmountDto, PaymentProcessorLineItemRequestDto, PaymentProcessorListRequestDto, PaymentProcessorListResponseDto, PaymentProcessorPauseCollectionRequestDto, PaymentProcessorPauseSubscriptionRequestDto, PaymentProcessorPaymentIntentDataRequestDto, PaymentProcessorPaymentMetadataDto, PaymentProcessorPaymentMethodResponseDto, PaymentProcessorPriceDataRequestDto, PaymentProcessorProductDataRequestDto, PaymentProcessorProductMetadataDto, PaymentProcessorRecurringDto, PaymentProcessorRefundMetadataDto, PaymentProcessorRefundResponseDto, PaymentProcessorShippingAddressCollectionDto, PaymentProcessorShippingDetailsDto, PaymentProcessorShippingOptionRequestDto, PaymentProcessorShippingRateDataRequestDto, PaymentProcessorShippingRateMetadataDto, PaymentProcessorSubscriptionDataRequestDto, PaymentProcessorSubscriptionMetadataDto, PaymentProcessorSubscriptionResponseDto, PaymentProcessorTransactionResponseDto, PaymentProcessorWebhookEventDto, PayPalAccessTokenResponseDto, PayPalAmountBreakdownDto, PayPalAmountDto, PayPalAmountWithBreakdownDto, PayPalApplicationContextDto, PayPalCreateOrderRequestDto, PayPalItemDto, PayPalOrderResponseDto, PayPalPurchaseUnitRequestDto, PayPalRefundRequestDto, PayPalRefundResponseDto}};
+
+pub static STRIPE_PAYMENT_PROCESSOR_NAME: &str = "stripe";
+pub static PAYPAL_PAYMENT_PROCESSOR_NAME: &str = "paypal";
+pub static FAKE_PAYMENT_PROCESSOR_NAME: &str = "fake";
+
+pub static STRIPE_SIGNATURE_HEADER_NAME: &str = "stripe-signature";
+
+// Stripe's recommended tolerance between a webhook's signature timestamp and now, limiting replayed deliveries
+static STRIPE_WEBHOOK_TOLERANCE_SECONDS: u64 = 300;
+
+// PayPal orders that are never approved lapse after three hours
+static PAYPAL_ORDER_EXPIRY_SECONDS: u64 = 10800;
 
 #[async_trait]
 pub trait PaymentProcessor {
+    // Creates the customer when it has no payment processor customer id yet, otherwise brings its email and name up to
+    // date. Processors without customers hand it back untouched.
+    async fn ensure_customer(&self, customer: Customer) -> Result<Customer, String>;
     async fn create_checkout_session(&self, payment: Payment) -> Result<Payment, String>;
-    async fn create_product(&self, product_id: String, name: String) -> Result<(), String>;
-    async fn create_product_pricing(&self, product_id: String, currency: String, unit_amount: i32) -> Result<(), String>;
+    async fn get_checkout_session(&self, session_id: String) -> Result<PaymentProcessorCheckoutSessionResponseDto, String>;
+    // Finalizes a session the customer has finished with where the processor needs to be told to take the money, and
+    // reports its state. Only called for payments still awaiting checkout so a cancelled payment is never charged.
+    async fn complete_checkout_session(&self, session_id: String) -> Result<PaymentProcessorCheckoutSessionResponseDto, String>;
+    async fn expire_checkout_session(&self, session_id: String) -> Result<(), String>;
+    async fn create_refund(&self, payment: &Payment, refund: Refund) -> Result<Refund, String>;
+    async fn list_charges(&self, created_from: u64, created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String>;
+    async fn list_refunds(&self, created_from: u64, created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String>;
+    fn parse_webhook_event(&self, payload: &str, headers: &HashMap<String, String>) -> Result<PaymentProcessorWebhookEventDto, String>;
+    // Processors that calculate tax themselves use the tax code to pick the product's rate
+    async fn create_product(&self, product_id: String, name: String, tax_code: Option<String>) -> Result<(), String>;
+    // The unit amount is in the currency's minor unit. Recurring pricing is billed every interval_count intervals, one-off pricing has none
+    async fn create_product_pricing(&self, product_id: String, currency: String, unit_amount: i64, recurring: Option<PaymentProcessorRecurringDto>) -> Result<(), String>;
+    async fn create_subscription_checkout_session(&self, subscription: Subscription) -> Result<Subscription, String>;
+    async fn cancel_subscription(&self, subscription_id: String, at_period_end: bool) -> Result<PaymentProcessorSubscriptionResponseDto, String>;
+    async fn pause_subscription(&self, subscription_id: String) -> Result<PaymentProcessorSubscriptionResponseDto, String>;
+    async fn resume_subscription(&self, subscription_id: String) -> Result<PaymentProcessorSubscriptionResponseDto, String>;
+    async fn list_payment_methods(&self, customer_id: String) -> Result<Vec<PaymentProcessorPaymentMethodResponseDto>, String>;
+    async fn detach_payment_method(&self, payment_method_id: String) -> Result<(), String>;
+    // Collects a payment method to save to the customer without charging anything
+    async fn create_setup_checkout_session(&self, customer_id: String, currency: String, checkout_mode: String) -> Result<PaymentProcessorCreateCheckoutSessionResponseDto, String>;
+    // Adds the evidence to the dispute, submitting it for review unless more is still to come
+    async fn submit_dispute_evidence(&self, dispute_id: String, evidence: HashMap<String, String>, files: Vec<PaymentProcessorDisputeEvidenceFileDto>, submit: bool) -> Result<PaymentProcessorDisputeResponseDto, String>;
+}
+
+// Payment processors by name, a payment is created with the processor asked for, else the one configured for its
+// currency, else the default. Every later operation on the payment goes to the processor recorded on it.
+pub struct PaymentProcessorRegistry {
+    payment_processors: HashMap<String, Arc<dyn PaymentProcessor + Send + Sync>>,
+    default_payment_processor_name: String,
+    payment_processor_names_by_currency: HashMap<String, String>,
+}
+
+impl PaymentProcessorRegistry {
+    pub fn new(default_payment_processor_name: String, payment_processor_names_by_currency: HashMap<String, String>) -> Self {
+        PaymentProcessorRegistry {
+            payment_processors: HashMap::new(),
+            default_payment_processor_name,
+            payment_processor_names_by_currency,
+        }
+    }
+
+    pub fn register(&mut self, name: &str, payment_processor: Arc<dyn PaymentProcessor + Send + Sync>) {
+        self.payment_processors.insert(String::from(name), payment_processor);
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.payment_processors.keys().cloned().collect()
+    }
+
+    // Payments recorded before processors were selectable have no name and belong to the default processor
+    pub fn resolve_name(&self, name: &str) -> String {
+        if name.is_empty() {
+            self.default_payment_processor_name.clone()
+        } else {
+            String::from(name)
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<dyn PaymentProcessor + Send + Sync>, String> {
+        let name = self.resolve_name(name);
+
+        match self.payment_processors.get(&name) {
+            Some(payment_processor) => Ok(payment_processor.clone()),
+            None => {
+                event!(Level::WARN, "Payment processor {} is not configured", name);
+                Err(format!("Payment processor {} is not configured", name))
+            }
+        }
+    }
+
+    pub fn select(&self, requested_name: Option<&str>, currency: &str) -> Result<(String, Arc<dyn PaymentProcessor + Send + Sync>), String> {
+        let name = match requested_name {
+            Some(requested_name) => String::from(requested_name),
+            None => self.payment_processor_names_by_currency.get(&currency.to_lowercase()).cloned().unwrap_or(self.default_payment_processor_name.clone())
+        };
+
+        self.get(&name).map(|payment_processor| (name, payment_processor))
+    }
 }
 
 pub struct StripePaymentProcessor {
     base_redirect_url: String,
+    checkout_session_expiry_seconds: u64,
 }
 
 impl StripePaymentProcessor {
-    pub fn new(base_redirect_url: String) -> Self {
+    pub fn new(base_redirect_url: String, checkout_session_expiry_seconds: u64) -> Self {
         StripePaymentProcessor { 
-            base_redirect_url: base_redirect_url
+            base_redirect_url: base_redirect_url,
+            checkout_session_expiry_seconds,
         }
     }
+
+    async fn send_subscription_request(&self, request: reqwest::RequestBuilder, operation: &str) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        match request
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorSubscriptionResponseDto>().await {
+                        Ok(subscription_response_dto) => Ok(subscription_response_dto),
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing SubscriptionResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing SubscriptionResponseDto: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending {} to Stripe: {}", operation, e);
+                    Err(format!("Error occurred when sending {} to Stripe: {}", operation, e))
+                }
+            }
+    }
+
+    // Pages through a Stripe list endpoint until every object created in the range has been read
+    async fn list_transactions(&self, resource: &str, created_from: u64, created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String> {
+        let http_client = reqwest::Client::new();
+        let mut transactions = Vec::new();
+        let mut starting_after = None;
+
+        loop {
+            let list_request_dto = PaymentProcessorListRequestDto {
+                limit: 100,
+                created: PaymentProcessorCreatedRangeDto {
+                    gte: created_from,
+                    lte: created_to,
+                },
+                starting_after: starting_after.clone(),
+            };
+
+            let url = Url::from_str(&format!("{}/v1/{}?{}", env::var("STRIPE_API_BASE_URL").unwrap(), resource, serde_qs::to_string(&list_request_dto).unwrap())).unwrap();
+
+            let page = match http_client.get(url)
+                .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+                .send()
+                .await {
+                    Ok(response) => {
+                        match response.json::<PaymentProcessorListResponseDto<PaymentProcessorTransactionResponseDto>>().await {
+                            Ok(page) => page,
+                            Err(e) => {
+                                event!(Level::WARN, "Error occurred when deserializing {} list: {}", resource, e);
+                                return Err(format!("Error occurred when deserializing {} list: {}", resource, e));
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when listing {} from Stripe: {}", resource, e);
+                        return Err(format!("Error occurred when listing {} from Stripe: {}", resource, e));
+                    }
+                };
+
+            starting_after = page.data.last().map(|transaction| transaction.id.clone());
+            transactions.extend(page.data);
+
+            if !page.has_more || starting_after.is_none() {
+                return Ok(transactions);
+            }
+        }
+    }
+
+    async fn list_checkout_session_line_items(&self, session_id: &str) -> Result<Vec<PaymentProcessorCheckoutSessionLineItemResponseDto>, String> {
+        let url = Url::from_str(&format!("{}/v1/checkout/sessions/{}/line_items?limit=100", env::var("STRIPE_API_BASE_URL").unwrap(), session_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.get(url)
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorListResponseDto<PaymentProcessorCheckoutSessionLineItemResponseDto>>().await {
+                        Ok(line_items) => Ok(line_items.data),
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing line items of checkout session {}: {}", session_id, e);
+                            Err(format!("Error occurred when deserializing line items of checkout session {}: {}", session_id, e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when listing line items of checkout session {} from Stripe: {}", session_id, e);
+                    Err(format!("Error occurred when listing line items of checkout session {} from Stripe: {}", session_id, e))
+                }
+            }
+    }
+
+    // Promotions are validated and priced on our side, Stripe is handed a single-use coupon for exactly the discount
+    // worked out for the payment so both always agree on what is charged
+    async fn create_coupon(&self, payment: &Payment, discount: &Discount) -> Result<String, String> {
+        let coupon_request_dto = PaymentProcessorCouponRequestDto {
+            name: discount.promotion_code.clone(),
+            amount_off: discount.amount,
+            currency: payment.currency.to_lowercase(),
+            duration: String::from("once"),
+            max_redemptions: 1,
+            metadata: PaymentProcessorCouponMetadataDto {
+                payment_id: payment.id.clone(),
+                promotion_code: discount.promotion_code.clone(),
+            },
+        };
+
+        let url = Url::from_str(&format!("{}/v1/coupons", env::var("STRIPE_API_BASE_URL").unwrap())).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .body(serde_qs::to_string(&coupon_request_dto).unwrap())
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorCouponResponseDto>().await {
+                        Ok(coupon_response_dto) => Ok(coupon_response_dto.id),
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing CouponResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing CouponResponseDto: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending CouponRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending CouponRequest to Stripe: {}", e))
+                }
+            }
+    }
+
+    // Files are uploaded to Stripe's separate files API and referenced from the dispute's evidence by their id
+    async fn upload_dispute_evidence_file(&self, file: PaymentProcessorDisputeEvidenceFileDto) -> Result<String, String> {
+        let file_part = match reqwest::multipart::Part::bytes(file.content).file_name(file.file_name.clone()).mime_str(&file.content_type) {
+            Ok(file_part) => file_part,
+            Err(e) => {
+                event!(Level::WARN, "File {} has an invalid content type {}: {}", file.file_name, file.content_type, e);
+                return Err(format!("File {} has an invalid content type {}: {}", file.file_name, file.content_type, e));
+            }
+        };
+        let form = reqwest::multipart::Form::new()
+            .text("purpose", "dispute_evidence")
+            .part("file", file_part);
+
+        let url = Url::from_str(&format!("{}/v1/files", env::var("STRIPE_FILES_API_BASE_URL").unwrap_or(String::from("https://files.stripe.com")))).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .multipart(form)
+            .send()
+            .await {
+                Ok(response) if response.status().is_success() => {
+                    match response.json::<PaymentProcessorFileResponseDto>().await {
+                        Ok(file_response_dto) => Ok(file_response_dto.id),
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing FileResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing FileResponseDto: {}", e))
+                        }
+                    }
+                },
+                Ok(response) => {
+                    let status = response.status();
+                    let body = response.text().await.unwrap_or_default();
+                    event!(Level::WARN, "Stripe rejected FileUploadRequest for {} with {}: {}", file.file_name, status, body);
+                    Err(format!("Stripe rejected FileUploadRequest for {} with {}: {}", file.file_name, status, body))
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending FileUploadRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending FileUploadRequest to Stripe: {}", e))
+                }
+            }
+    }
+}
+
+// Stripe rejects return_url for hosted checkout, and success_url or cancel_url for embedded and custom checkout
+fn stripe_redirect_urls(checkout_mode: &str) -> (Option<String>, Option<String>, Option<String>) {
+    let redirect_base_url = env::var("PAYMENT_REDIRECT_BASE_URL").unwrap();
+
+    if checkout_mode == CheckoutMode::HOSTED.to_string() {
+        (None,
+        Some(format!("{}/success?session_id={{CHECKOUT_SESSION_ID}}", redirect_base_url)),
+        Some(format!("{}/cancel?session_id={{CHECKOUT_SESSION_ID}}", redirect_base_url)))
+    } else {
+        (Some(format!("{}/return?session_id={{CHECKOUT_SESSION_ID}}", redirect_base_url)), None, None)
+    }
 }
 
 #[async_trait]
 impl PaymentProcessor for StripePaymentProcessor{
+    async fn ensure_customer(&self, mut customer: Customer) -> Result<Customer, String> {
+        let customer_request_dto = PaymentProcessorCustomerRequestDto {
+            email: if customer.email.is_empty() { None } else { Some(customer.email.clone()) },
+            name: if customer.name.is_empty() { None } else { Some(customer.name.clone()) },
+            metadata: PaymentProcessorCustomerMetadataDto {
+                auth0_subject: customer.subject.clone(),
+            },
+        };
+
+        let url = if customer.payment_processor_customer_id.is_empty() {
+            Url::from_str(&format!("{}/v1/customers", env::var("STRIPE_API_BASE_URL").unwrap())).unwrap()
+        } else {
+            Url::from_str(&format!("{}/v1/customers/{}", env::var("STRIPE_API_BASE_URL").unwrap(), customer.payment_processor_customer_id)).unwrap()
+        };
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .body(serde_qs::to_string(&customer_request_dto).unwrap())
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorCustomerResponseDto>().await {
+                        Ok(customer_response_dto) => {
+                            customer.payment_processor_customer_id = customer_response_dto.id;
+                            Ok(customer)
+                        },
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing CustomerResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing CustomerResponseDto: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending CustomerRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending CustomerRequest to Stripe: {}", e))
+                }
+            }
+    }
+
     async fn create_checkout_session(&self, mut payment: Payment) -> Result<Payment, String> {
+        let (return_url, success_url, cancel_url) = stripe_redirect_urls(&payment.checkout_mode);
+
+        let mut discounts = None;
+        if let Some(discount) = payment.discount.as_ref().filter(|discount| discount.amount > 0) {
+            let coupon_id = self.create_coupon(&payment, discount).await?;
+            discounts = Some(vec![PaymentProcessorDiscountRequestDto { coupon: coupon_id.clone() }]);
+            if let Some(discount) = payment.discount.as_mut() {
+                discount.payment_processor_coupon_id = coupon_id;
+            }
+        }
+
+        let mut line_items: Vec<PaymentProcessorLineItemRequestDto> = payment.line_items.iter().map(|line_item| PaymentProcessorLineItemRequestDto {
+            price_data: PaymentProcessorPriceDataRequestDto {
+                currency: payment.currency.to_lowercase(),
+                unit_amount: line_item.price,
+                product: Some(line_item.product_id.clone()),
+                product_data: None,
+                recurring: None,
+            },
+            quantity: line_item.quantity,
+        }).collect();
+
+        // Tax worked out on our side is charged as a line of its own
+        let tax_total = payment.tax_total();
+        if !payment.automatic_tax && tax_total > 0 {
+            line_items.push(PaymentProcessorLineItemRequestDto {
+                price_data: PaymentProcessorPriceDataRequestDto {
+                    currency: payment.currency.to_lowercase(),
+                    unit_amount: tax_total,
+                    product: None,
+                    product_data: Some(PaymentProcessorProductDataRequestDto {
+                        name: String::from("Tax"),
+                    }),
+                    recurring: None,
+                },
+                quantity: 1,
+            });
+        }
+
         let create_checkout_session_request_dto = PaymentProcessorCreateCheckoutSessionRequestDto {
-            ui_mode: String::from("custom"),
+            ui_mode: payment.checkout_mode.clone(),
             mode: String::from("payment"),
-            return_url: String::from(format!("{}/return?session_id={{CHECKOUT_SESSION_ID}}", String::from(env::var("PAYMENT_REDIRECT_BASE_URL").unwrap()))),
-            line_items: vec![
-                PaymentProcessorLineItemRequestDto{
-                    price: String::from("10000"),
-                    quantity: 10
-                }
-            ],
+            return_url,
+            success_url,
+            cancel_url,
+            line_items,
+            expires_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + self.checkout_session_expiry_seconds,
+            client_reference_id: payment.id.clone(),
+            customer: if payment.payment_processor_customer_id.is_empty() { None } else { Some(payment.payment_processor_customer_id.clone()) },
+            customer_email: if payment.customer_email.is_empty() || !payment.payment_processor_customer_id.is_empty() { None } else { Some(payment.customer_email.clone()) },
+            metadata: PaymentProcessorPaymentMetadataDto {
+                payment_id: payment.id.clone(),
+                order_id: if payment.order_id.is_empty() { None } else { Some(payment.order_id.clone()) },
+            },
+            payment_intent_data: PaymentProcessorPaymentIntentDataRequestDto {
+                metadata: PaymentProcessorPaymentMetadataDto {
+                    payment_id: payment.id.clone(),
+                    order_id: if payment.order_id.is_empty() { None } else { Some(payment.order_id.clone()) },
+                },
+            },
+            discounts,
+            automatic_tax: if payment.automatic_tax { Some(PaymentProcessorAutomaticTaxDto { enabled: true }) } else { None },
+            billing_address_collection: if payment.automatic_tax { Some(String::from("required")) } else { None },
+            customer_update: if payment.automatic_tax && !payment.payment_processor_customer_id.is_empty() { Some(PaymentProcessorCustomerUpdateRequestDto { address: String::from("auto") }) } else { None },
+            shipping_address_collection: payment.shipping.as_ref().map(|shipping| PaymentProcessorShippingAddressCollectionDto {
+                allowed_countries: shipping.allowed_countries.clone(),
+            }),
+            shipping_options: payment.shipping.as_ref().map(|shipping| shipping.offered_rates.iter().map(|shipping_rate| PaymentProcessorShippingOptionRequestDto {
+                shipping_rate_data: PaymentProcessorShippingRateDataRequestDto {
+                    shipping_rate_type: String::from("fixed_amount"),
+                    display_name: shipping_rate.display_name.clone(),
+                    fixed_amount: PaymentProcessorFixedAmountDto {
+                        amount: shipping_rate.amount,
+                        currency: payment.currency.to_lowercase(),
+                    },
+                    metadata: PaymentProcessorShippingRateMetadataDto {
+                        shipping_option_id: shipping_rate.shipping_option_id.clone(),
+                    },
+                },
+            }).collect()),
         };
 
         // serde_qs (query string) must be used to manually serialize the object before passing to reqwest
@@ -55,20 +449,20 @@ impl PaymentProcessor for StripePaymentProcessor{
             .send()
             .await {
                 Ok(response) => {
-                    println!("{}", &response.text().await.unwrap());
-                    // match response.json::<PaymentProcessorCreateCheckoutSessionResponseDto>().await {
-                    //     Ok(create_checkout_session_response_dto) => {
-                    //         payment.payment_processor_checkout_session_id = create_checkout_session_response_dto.session_id;
-                    //         payment.payment_processor_checkout_session_url = create_checkout_session_response_dto.session_url;
+                    match response.json::<PaymentProcessorCreateCheckoutSessionResponseDto>().await {
+                        Ok(create_checkout_session_response_dto) => {
+                            payment.payment_processor_checkout_session_id = create_checkout_session_response_dto.session_id;
+                            payment.payment_processor_checkout_session_url = create_checkout_session_response_dto.session_url.unwrap_or_default();
+                            payment.payment_processor_checkout_session_client_secret = create_checkout_session_response_dto.client_secret.unwrap_or_default();
+                            payment.payment_processor_checkout_session_expires_at = create_checkout_session_response_dto.expires_at;
 
-                    //         return Ok(payment);
-                    //     },
-                    //     Err(e) => {
-                    //         event!(Level::WARN, "Error occurred when deserializing CreateCheckoutResponseDto: {}", e);
-                    //         return Err(format!("Error occurred when deserializing CreateCheckoutResponseDto: {}", e));
-                    //     }
-                    // }
-                    return Ok(payment);
+                            return Ok(payment);
+                        },
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing CreateCheckoutResponseDto: {}", e);
+                            return Err(format!("Error occurred when deserializing CreateCheckoutResponseDto: {}", e));
+                        }
+                    }
                 },
                 Err(e) => {
                     event!(Level::WARN, "Error occurred when sending CreateCheckoutRequest to Stripe: {}", e);
@@ -77,10 +471,191 @@ impl PaymentProcessor for StripePaymentProcessor{
             };
     }
 
-    async fn create_product(&self, product_id: String, name: String) -> Result<(), String> {
+    async fn get_checkout_session(&self, session_id: String) -> Result<PaymentProcessorCheckoutSessionResponseDto, String> {
+        // The shipping rate is expanded so the rate picked can be traced back to our shipping option
+        let url = Url::from_str(&format!("{}/v1/checkout/sessions/{}?expand[]=shipping_cost.shipping_rate", env::var("STRIPE_API_BASE_URL").unwrap(), session_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.get(url)
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorCheckoutSessionResponseDto>().await {
+                        Ok(mut checkout_session_response_dto) => {
+                            // Stripe Tax only settles each line's tax once checkout completes
+                            let automatic_tax = checkout_session_response_dto.automatic_tax.as_ref().is_some_and(|automatic_tax| automatic_tax.enabled);
+                            if automatic_tax && checkout_session_response_dto.status.as_deref() == Some("complete") {
+                                checkout_session_response_dto.line_items = Some(self.list_checkout_session_line_items(&checkout_session_response_dto.session_id).await?);
+                            }
+
+                            Ok(checkout_session_response_dto)
+                        },
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing CheckoutSessionResponseDto: {}", e);
+                            return Err(format!("Error occurred when deserializing CheckoutSessionResponseDto: {}", e));
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending GetCheckoutSessionRequest to Stripe: {}", e);
+                    return Err(format!("Error occurred when sending GetCheckoutSessionRequest to Stripe: {}", e));
+                }
+            }
+    }
+
+    // Stripe captures the payment itself once checkout completes
+    async fn complete_checkout_session(&self, session_id: String) -> Result<PaymentProcessorCheckoutSessionResponseDto, String> {
+        self.get_checkout_session(session_id).await
+    }
+
+    async fn expire_checkout_session(&self, session_id: String) -> Result<(), String> {
+        let url = Url::from_str(&format!("{}/v1/checkout/sessions/{}/expire", env::var("STRIPE_API_BASE_URL").unwrap(), session_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .send()
+            .await {
+                Ok(response) => {
+                    if response.status().is_success() {
+                        Ok(())
+                    } else {
+                        let status = response.status();
+                        let body = response.text().await.unwrap_or_default();
+                        event!(Level::WARN, "Stripe rejected ExpireCheckoutSessionRequest for {} with {}: {}", session_id, status, body);
+                        Err(format!("Stripe rejected ExpireCheckoutSessionRequest for {} with {}: {}", session_id, status, body))
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending ExpireCheckoutSessionRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending ExpireCheckoutSessionRequest to Stripe: {}", e))
+                }
+            }
+    }
+
+    async fn create_refund(&self, payment: &Payment, mut refund: Refund) -> Result<Refund, String> {
+        let payment_processor_create_refund_request_dto = PaymentProcessorCreateRefundRequestDto {
+            payment_intent: payment.payment_processor_id.clone(),
+            amount: refund.amount,
+            reason: match refund.reason.as_str() {
+                "duplicate" | "fraudulent" | "requested_by_customer" => Some(refund.reason.clone()),
+                _ => None
+            },
+            metadata: PaymentProcessorRefundMetadataDto {
+                payment_id: payment.id.clone(),
+                refund_id: refund.id.clone(),
+            },
+        };
+
+        let form_url_encoded_request = serde_qs::to_string(&payment_processor_create_refund_request_dto).unwrap();
+
+        let url = Url::from_str(&format!("{}/v1/refunds", env::var("STRIPE_API_BASE_URL").unwrap())).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            // Stripe deduplicates retried refund requests carrying the same key
+            .header("Idempotency-Key", refund.id.clone())
+            .body(form_url_encoded_request)
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorRefundResponseDto>().await {
+                        Ok(refund_response_dto) => {
+                            refund.payment_processor_refund_id = refund_response_dto.id;
+                            refund.payment_processor_status = refund_response_dto.status.unwrap_or_default();
+
+                            Ok(refund)
+                        },
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing RefundResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing RefundResponseDto: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending CreateRefundRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending CreateRefundRequest to Stripe: {}", e))
+                }
+            }
+    }
+
+    async fn list_charges(&self, created_from: u64, created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String> {
+        self.list_transactions("charges", created_from, created_to).await
+    }
+
+    async fn list_refunds(&self, created_from: u64, created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String> {
+        self.list_transactions("refunds", created_from, created_to).await
+    }
+
+    fn parse_webhook_event(&self, payload: &str, headers: &HashMap<String, String>) -> Result<PaymentProcessorWebhookEventDto, String> {
+        let signature = match headers.get(STRIPE_SIGNATURE_HEADER_NAME) {
+            Some(signature) => signature,
+            None => {
+                event!(Level::WARN, "Stripe webhook has no {} header", STRIPE_SIGNATURE_HEADER_NAME);
+                return Err(format!("Stripe webhook has no {} header", STRIPE_SIGNATURE_HEADER_NAME));
+            }
+        };
+
+        // The Stripe-Signature header looks like 't=<timestamp>,v1=<signature>,v1=<signature>', with more than one v1 while
+        // the signing secret is being rolled
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for part in signature.split(',') {
+            match part.split_once('=') {
+                Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+                Some(("v1", value)) => signatures.push(value),
+                _ => {}
+            }
+        }
+
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp,
+            None => {
+                event!(Level::WARN, "Stripe webhook signature has no timestamp");
+                return Err(String::from("Stripe webhook signature has no timestamp"));
+            }
+        };
+
+        if SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().abs_diff(timestamp) > STRIPE_WEBHOOK_TOLERANCE_SECONDS {
+            event!(Level::WARN, "Stripe webhook timestamp {} is outside the tolerance", timestamp);
+            return Err(format!("Stripe webhook timestamp {} is outside the tolerance", timestamp));
+        }
+
+        let signature_matches = signatures.iter().any(|expected_signature| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(env::var("STRIPE_WEBHOOK_SECRET").unwrap().as_bytes()).unwrap();
+            mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+
+            match hex::decode(expected_signature) {
+                Ok(expected_signature) => mac.verify_slice(&expected_signature).is_ok(),
+                Err(_) => false
+            }
+        });
+
+        if !signature_matches {
+            event!(Level::WARN, "Stripe webhook signature does not match");
+            return Err(String::from("Stripe webhook signature does not match"));
+        }
+
+        match serde_json::from_str::<PaymentProcessorWebhookEventDto>(payload) {
+            Ok(webhook_event) => Ok(webhook_event),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when deserializing WebhookEventDto: {}", e);
+                Err(format!("Error occurred when deserializing WebhookEventDto: {}", e))
+            }
+        }
+    }
+
+    async fn create_product(&self, product_id: String, name: String, tax_code: Option<String>) -> Result<(), String> {
         let payment_processor_create_product_request_dto = PaymentProcessorCreateProductRequestDto {
-            id: product_id,
+            id: product_id.clone(),
             name: name,
+            tax_code,
+            metadata: PaymentProcessorProductMetadataDto {
+                catalog_product_id: product_id,
+            },
         };
 
         let form_url_encoded_request = serde_qs::to_string(&payment_processor_create_product_request_dto).unwrap();
@@ -104,11 +679,12 @@ impl PaymentProcessor for StripePaymentProcessor{
             }
     }
 
-    async fn create_product_pricing(&self, product_id: String, currency: String, unit_amount: i32) -> Result<(), String> {
+    async fn create_product_pricing(&self, product_id: String, currency: String, unit_amount: i64, recurring: Option<PaymentProcessorRecurringDto>) -> Result<(), String> {
         let payment_processor_create_pricing_request_dto = PaymentProcessorCreatePricingRequestDto {
             product: product_id,
             currency: currency,
-            unit_amount: unit_amount * 100, // Stripe's unit amount is in cents
+            unit_amount,
+            recurring,
         };
 
         let form_url_encoded_request = serde_qs::to_string(&payment_processor_create_pricing_request_dto).unwrap();
@@ -131,4 +707,715 @@ impl PaymentProcessor for StripePaymentProcessor{
                 }
             }
     }
+
+    async fn create_subscription_checkout_session(&self, mut subscription: Subscription) -> Result<Subscription, String> {
+        let (return_url, success_url, cancel_url) = stripe_redirect_urls(&subscription.checkout_mode);
+
+        let create_checkout_session_request_dto = PaymentProcessorCreateSubscriptionCheckoutSessionRequestDto {
+            ui_mode: subscription.checkout_mode.clone(),
+            mode: String::from("subscription"),
+            return_url,
+            success_url,
+            cancel_url,
+            line_items: subscription.line_items.iter().map(|line_item| PaymentProcessorLineItemRequestDto {
+                price_data: PaymentProcessorPriceDataRequestDto {
+                    currency: subscription.currency.to_lowercase(),
+                    unit_amount: line_item.price,
+                    product: Some(line_item.product_id.clone()),
+                    product_data: None,
+                    recurring: Some(PaymentProcessorRecurringDto {
+                        interval: line_item.interval.clone(),
+                        interval_count: line_item.interval_count,
+                    }),
+                },
+                quantity: line_item.quantity,
+            }).collect(),
+            expires_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + self.checkout_session_expiry_seconds,
+            client_reference_id: subscription.id.clone(),
+            customer: if subscription.payment_processor_customer_id.is_empty() { None } else { Some(subscription.payment_processor_customer_id.clone()) },
+            customer_email: if subscription.customer_email.is_empty() || !subscription.payment_processor_customer_id.is_empty() { None } else { Some(subscription.customer_email.clone()) },
+            metadata: PaymentProcessorSubscriptionMetadataDto {
+                subscription_id: subscription.id.clone(),
+            },
+            subscription_data: PaymentProcessorSubscriptionDataRequestDto {
+                metadata: PaymentProcessorSubscriptionMetadataDto {
+                    subscription_id: subscription.id.clone(),
+                },
+            },
+        };
+
+        let form_url_encoded_request = serde_qs::to_string(&create_checkout_session_request_dto).unwrap();
+
+        let url = Url::from_str(&format!("{}/v1/checkout/sessions", env::var("STRIPE_API_BASE_URL").unwrap())).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .body(form_url_encoded_request)
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorCreateCheckoutSessionResponseDto>().await {
+                        Ok(create_checkout_session_response_dto) => {
+                            subscription.payment_processor_checkout_session_id = create_checkout_session_response_dto.session_id;
+                            subscription.payment_processor_checkout_session_url = create_checkout_session_response_dto.session_url.unwrap_or_default();
+                            subscription.payment_processor_checkout_session_client_secret = create_checkout_session_response_dto.client_secret.unwrap_or_default();
+                            subscription.payment_processor_checkout_session_expires_at = create_checkout_session_response_dto.expires_at;
+
+                            Ok(subscription)
+                        },
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing CreateCheckoutResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing CreateCheckoutResponseDto: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending CreateSubscriptionCheckoutRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending CreateSubscriptionCheckoutRequest to Stripe: {}", e))
+                }
+            }
+    }
+
+    async fn cancel_subscription(&self, subscription_id: String, at_period_end: bool) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        let url = Url::from_str(&format!("{}/v1/subscriptions/{}", env::var("STRIPE_API_BASE_URL").unwrap(), subscription_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        let request = if at_period_end {
+            let cancel_request_dto = PaymentProcessorCancelSubscriptionAtPeriodEndRequestDto {
+                cancel_at_period_end: true,
+            };
+
+            http_client.post(url)
+                .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+                .body(serde_qs::to_string(&cancel_request_dto).unwrap())
+        } else {
+            http_client.delete(url)
+        };
+
+        self.send_subscription_request(request, "CancelSubscriptionRequest").await
+    }
+
+    // Pausing collection voids the invoices raised while paused, the customer is not charged for that time
+    async fn pause_subscription(&self, subscription_id: String) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        let pause_request_dto = PaymentProcessorPauseSubscriptionRequestDto {
+            pause_collection: PaymentProcessorPauseCollectionRequestDto {
+                behavior: String::from("void"),
+            },
+        };
+
+        let url = Url::from_str(&format!("{}/v1/subscriptions/{}", env::var("STRIPE_API_BASE_URL").unwrap(), subscription_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        let request = http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .body(serde_qs::to_string(&pause_request_dto).unwrap());
+
+        self.send_subscription_request(request, "PauseSubscriptionRequest").await
+    }
+
+    async fn resume_subscription(&self, subscription_id: String) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        let url = Url::from_str(&format!("{}/v1/subscriptions/{}", env::var("STRIPE_API_BASE_URL").unwrap(), subscription_id)).unwrap();
+
+        // An empty pause_collection clears it
+        let http_client = reqwest::Client::new();
+        let request = http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .body("pause_collection=");
+
+        self.send_subscription_request(request, "ResumeSubscriptionRequest").await
+    }
+
+    async fn list_payment_methods(&self, customer_id: String) -> Result<Vec<PaymentProcessorPaymentMethodResponseDto>, String> {
+        // A single page is plenty, nobody saves more than a hundred payment methods
+        let url = Url::from_str(&format!("{}/v1/customers/{}/payment_methods?limit=100", env::var("STRIPE_API_BASE_URL").unwrap(), customer_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.get(url)
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorListResponseDto<PaymentProcessorPaymentMethodResponseDto>>().await {
+                        Ok(page) => Ok(page.data),
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing payment_methods list: {}", e);
+                            Err(format!("Error occurred when deserializing payment_methods list: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when listing payment_methods from Stripe: {}", e);
+                    Err(format!("Error occurred when listing payment_methods from Stripe: {}", e))
+                }
+            }
+    }
+
+    async fn detach_payment_method(&self, payment_method_id: String) -> Result<(), String> {
+        let url = Url::from_str(&format!("{}/v1/payment_methods/{}/detach", env::var("STRIPE_API_BASE_URL").unwrap(), payment_method_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .send()
+            .await {
+                Ok(response) => {
+                    if response.status().is_success() {
+                        Ok(())
+                    } else {
+                        let status = response.status();
+                        let body = response.text().await.unwrap_or_default();
+                        event!(Level::WARN, "Stripe rejected DetachPaymentMethodRequest for {} with {}: {}", payment_method_id, status, body);
+                        Err(format!("Stripe rejected DetachPaymentMethodRequest for {} with {}: {}", payment_method_id, status, body))
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending DetachPaymentMethodRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending DetachPaymentMethodRequest to Stripe: {}", e))
+                }
+            }
+    }
+
+    async fn create_setup_checkout_session(&self, customer_id: String, currency: String, checkout_mode: String) -> Result<PaymentProcessorCreateCheckoutSessionResponseDto, String> {
+        let (return_url, success_url, cancel_url) = stripe_redirect_urls(&checkout_mode);
+
+        let create_setup_checkout_session_request_dto = PaymentProcessorCreateSetupCheckoutSessionRequestDto {
+            ui_mode: checkout_mode,
+            mode: String::from("setup"),
+            return_url,
+            success_url,
+            cancel_url,
+            customer: customer_id,
+            currency: currency.to_lowercase(),
+        };
+
+        let url = Url::from_str(&format!("{}/v1/checkout/sessions", env::var("STRIPE_API_BASE_URL").unwrap())).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .body(serde_qs::to_string(&create_setup_checkout_session_request_dto).unwrap())
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PaymentProcessorCreateCheckoutSessionResponseDto>().await {
+                        Ok(create_checkout_session_response_dto) => Ok(create_checkout_session_response_dto),
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing CreateCheckoutResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing CreateCheckoutResponseDto: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending CreateSetupCheckoutRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending CreateSetupCheckoutRequest to Stripe: {}", e))
+                }
+            }
+    }
+
+    async fn submit_dispute_evidence(&self, dispute_id: String, mut evidence: HashMap<String, String>, files: Vec<PaymentProcessorDisputeEvidenceFileDto>, submit: bool) -> Result<PaymentProcessorDisputeResponseDto, String> {
+        for file in files {
+            let evidence_type = file.evidence_type.clone();
+            let file_id = self.upload_dispute_evidence_file(file).await?;
+            evidence.insert(evidence_type, file_id);
+        }
+
+        let dispute_update_request_dto = PaymentProcessorDisputeUpdateRequestDto {
+            evidence,
+            submit,
+        };
+
+        let url = Url::from_str(&format!("{}/v1/disputes/{}", env::var("STRIPE_API_BASE_URL").unwrap(), dispute_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", env::var("STRIPE_API_KEY").unwrap()))
+            .body(serde_qs::to_string(&dispute_update_request_dto).unwrap())
+            .send()
+            .await {
+                Ok(response) if response.status().is_success() => {
+                    match response.json::<PaymentProcessorDisputeResponseDto>().await {
+                        Ok(dispute_response_dto) => Ok(dispute_response_dto),
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing DisputeResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing DisputeResponseDto: {}", e))
+                        }
+                    }
+                },
+                Ok(response) => {
+                    let status = response.status();
+                    let body = response.text().await.unwrap_or_default();
+                    event!(Level::WARN, "Stripe rejected DisputeUpdateRequest for {} with {}: {}", dispute_id, status, body);
+                    Err(format!("Stripe rejected DisputeUpdateRequest for {} with {}: {}", dispute_id, status, body))
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending DisputeUpdateRequest to Stripe: {}", e);
+                    Err(format!("Error occurred when sending DisputeUpdateRequest to Stripe: {}", e))
+                }
+            }
+    }
+}
+
+pub struct PayPalPaymentProcessor {}
+
+impl PayPalPaymentProcessor {
+    pub fn new() -> Self {
+        PayPalPaymentProcessor {}
+    }
+
+    // PayPal's REST API only accepts short-lived bearer tokens obtained with the client credentials grant
+    async fn get_access_token(&self) -> Result<String, String> {
+        let url = Url::from_str(&format!("{}/v1/oauth2/token", env::var("PAYPAL_API_BASE_URL").unwrap())).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/x-www-form-urlencoded"))
+            .basic_auth(env::var("PAYPAL_CLIENT_ID").unwrap(), Some(env::var("PAYPAL_CLIENT_SECRET").unwrap()))
+            .body("grant_type=client_credentials")
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PayPalAccessTokenResponseDto>().await {
+                        Ok(access_token_response_dto) => Ok(access_token_response_dto.access_token),
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing PayPalAccessTokenResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing PayPalAccessTokenResponseDto: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending AccessTokenRequest to PayPal: {}", e);
+                    Err(format!("Error occurred when sending AccessTokenRequest to PayPal: {}", e))
+                }
+            }
+    }
+
+    async fn send_order_request(&self, request: reqwest::RequestBuilder, operation: &str) -> Result<PayPalOrderResponseDto, String> {
+        match request.send().await {
+            Ok(response) => {
+                match response.json::<PayPalOrderResponseDto>().await {
+                    Ok(order_response_dto) => Ok(order_response_dto),
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when deserializing PayPalOrderResponseDto: {}", e);
+                        Err(format!("Error occurred when deserializing PayPalOrderResponseDto: {}", e))
+                    }
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when sending {} to PayPal: {}", operation, e);
+                Err(format!("Error occurred when sending {} to PayPal: {}", operation, e))
+            }
+        }
+    }
+
+    // Maps a PayPal order onto the Stripe checkout session shape the handlers reconcile against
+    fn to_checkout_session(order: PayPalOrderResponseDto) -> PaymentProcessorCheckoutSessionResponseDto {
+        let (status, payment_status) = match order.status.as_str() {
+            "COMPLETED" => ("complete", "paid"),
+            "VOIDED" => ("expired", "unpaid"),
+            _ => ("open", "unpaid")
+        };
+
+        let mut purchase_unit = order.purchase_units.into_iter().next();
+        let client_reference_id = purchase_unit.as_ref().and_then(|purchase_unit| purchase_unit.custom_id.clone());
+        let shipping_details = purchase_unit.as_mut().and_then(|purchase_unit| purchase_unit.shipping.take()).map(|shipping| PaymentProcessorShippingDetailsDto {
+            name: shipping.name.and_then(|name| name.full_name),
+            address: shipping.address.map(|address| PaymentProcessorAddressDto {
+                line1: address.address_line_1,
+                line2: address.address_line_2,
+                city: address.admin_area_2,
+                state: address.admin_area_1,
+                postal_code: address.postal_code,
+                country: address.country_code,
+            }),
+        });
+        let capture_id = purchase_unit
+            .and_then(|purchase_unit| purchase_unit.payments)
+            .and_then(|payments| payments.captures.into_iter().next())
+            .map(|capture| capture.id);
+
+        let mut metadata = HashMap::new();
+        if let Some(payment_id) = &client_reference_id {
+            metadata.insert(String::from("payment_id"), payment_id.clone());
+        }
+
+        PaymentProcessorCheckoutSessionResponseDto {
+            session_id: order.id,
+            client_reference_id,
+            metadata,
+            status: Some(String::from(status)),
+            payment_status: String::from(payment_status),
+            payment_intent: capture_id,
+            customer_details: order.payer.map(|payer| PaymentProcessorCustomerDetailsDto {
+                email: payer.email_address,
+            }),
+            mode: None,
+            subscription: None,
+            customer: None,
+            automatic_tax: None,
+            line_items: None,
+            shipping_cost: None,
+            shipping_details,
+            collected_information: None,
+        }
+    }
+}
+
+// Processors that cannot let the customer pick between rates at checkout charge the cheapest one offered
+fn select_cheapest_shipping_rate(payment: &mut Payment) {
+    if let Some(shipping) = payment.shipping.as_mut() {
+        let cheapest_rate = shipping.offered_rates.iter().min_by_key(|rate| rate.amount).cloned();
+        shipping.offered_rates = cheapest_rate.iter().cloned().collect();
+        shipping.selected_rate = cheapest_rate;
+    }
+}
+
+// PayPal amounts are decimal strings in the major currency unit with an uppercase ISO currency code
+fn to_paypal_amount(currency: &str, amount: i64) -> PayPalAmountDto {
+    PayPalAmountDto {
+        currency_code: currency.to_uppercase(),
+        value: format!("{}.{:02}", amount / 100, amount % 100),
+    }
+}
+
+#[async_trait]
+impl PaymentProcessor for PayPalPaymentProcessor {
+    // PayPal payers sign in to PayPal itself, there is no customer to keep on our side
+    async fn ensure_customer(&self, customer: Customer) -> Result<Customer, String> {
+        Ok(customer)
+    }
+
+    // PayPal only offers its own approval page, so every checkout mode is handed the url to redirect to
+    async fn create_checkout_session(&self, mut payment: Payment) -> Result<Payment, String> {
+        let access_token = self.get_access_token().await?;
+
+        select_cheapest_shipping_rate(&mut payment);
+        let total = to_paypal_amount(&payment.currency, payment.total());
+        let shipping = payment.shipping.as_ref().map(|_| to_paypal_amount(&payment.currency, payment.shipping_total()));
+        let item_total = to_paypal_amount(&payment.currency, payment.subtotal());
+        let tax_total = Some(payment.tax_total()).filter(|tax_total| *tax_total > 0).map(|tax_total| to_paypal_amount(&payment.currency, tax_total));
+        let discount = payment.discount.as_ref().filter(|discount| discount.amount > 0).map(|discount| to_paypal_amount(&payment.currency, discount.amount));
+        let create_order_request_dto = PayPalCreateOrderRequestDto {
+            intent: String::from("CAPTURE"),
+            purchase_units: vec![PayPalPurchaseUnitRequestDto {
+                reference_id: payment.id.clone(),
+                custom_id: payment.id.clone(),
+                amount: PayPalAmountWithBreakdownDto {
+                    currency_code: total.currency_code.clone(),
+                    value: total.value.clone(),
+                    breakdown: PayPalAmountBreakdownDto {
+                        item_total,
+                        shipping,
+                        tax_total,
+                        discount,
+                    },
+                },
+                items: payment.line_items.iter().map(|line_item| PayPalItemDto {
+                    name: line_item.product_id.clone(),
+                    sku: line_item.product_id.clone(),
+                    quantity: line_item.quantity.to_string(),
+                    unit_amount: to_paypal_amount(&payment.currency, line_item.price),
+                }).collect(),
+            }],
+            application_context: PayPalApplicationContextDto {
+                return_url: format!("{}/return?payment_id={}", env::var("PAYMENT_REDIRECT_BASE_URL").unwrap(), payment.id),
+                cancel_url: format!("{}/cancel?payment_id={}", env::var("PAYMENT_REDIRECT_BASE_URL").unwrap(), payment.id),
+                shipping_preference: String::from(if payment.shipping.is_some() { "GET_FROM_FILE" } else { "NO_SHIPPING" }),
+            },
+        };
+
+        let url = Url::from_str(&format!("{}/v2/checkout/orders", env::var("PAYPAL_API_BASE_URL").unwrap())).unwrap();
+
+        let http_client = reqwest::Client::new();
+        let request = http_client.post(url)
+            .bearer_auth(access_token)
+            // PayPal deduplicates retried order requests carrying the same id
+            .header("PayPal-Request-Id", payment.id.clone())
+            .json(&create_order_request_dto);
+
+        let order = self.send_order_request(request, "CreateOrderRequest").await?;
+
+        payment.payment_processor_checkout_session_url = order.links.iter()
+            .find(|link| link.rel == "approve" || link.rel == "payer-action")
+            .map(|link| link.href.clone())
+            .unwrap_or_default();
+        payment.payment_processor_checkout_session_id = order.id;
+        payment.payment_processor_checkout_session_expires_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + PAYPAL_ORDER_EXPIRY_SECONDS;
+
+        Ok(payment)
+    }
+
+    async fn get_checkout_session(&self, session_id: String) -> Result<PaymentProcessorCheckoutSessionResponseDto, String> {
+        let access_token = self.get_access_token().await?;
+
+        let url = Url::from_str(&format!("{}/v2/checkout/orders/{}", env::var("PAYPAL_API_BASE_URL").unwrap(), session_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        let order = self.send_order_request(http_client.get(url).bearer_auth(access_token), "GetOrderRequest").await?;
+
+        Ok(PayPalPaymentProcessor::to_checkout_session(order))
+    }
+
+    // An approved PayPal order holds no money until it is captured
+    async fn complete_checkout_session(&self, session_id: String) -> Result<PaymentProcessorCheckoutSessionResponseDto, String> {
+        let access_token = self.get_access_token().await?;
+
+        let url = Url::from_str(&format!("{}/v2/checkout/orders/{}", env::var("PAYPAL_API_BASE_URL").unwrap(), session_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        let order = self.send_order_request(http_client.get(url).bearer_auth(&access_token), "GetOrderRequest").await?;
+
+        if order.status != "APPROVED" {
+            return Ok(PayPalPaymentProcessor::to_checkout_session(order));
+        }
+
+        let url = Url::from_str(&format!("{}/v2/checkout/orders/{}/capture", env::var("PAYPAL_API_BASE_URL").unwrap(), session_id)).unwrap();
+
+        let request = http_client.post(url)
+            .bearer_auth(access_token)
+            .header(reqwest::header::CONTENT_TYPE, String::from("application/json"))
+            .header("PayPal-Request-Id", format!("capture-{}", session_id));
+
+        let order = self.send_order_request(request, "CaptureOrderRequest").await?;
+
+        Ok(PayPalPaymentProcessor::to_checkout_session(order))
+    }
+
+    // Uncaptured PayPal orders simply lapse, there is nothing to expire
+    async fn expire_checkout_session(&self, _session_id: String) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn create_refund(&self, payment: &Payment, mut refund: Refund) -> Result<Refund, String> {
+        let access_token = self.get_access_token().await?;
+
+        let refund_request_dto = PayPalRefundRequestDto {
+            amount: to_paypal_amount(&payment.currency, refund.amount),
+            custom_id: refund.id.clone(),
+        };
+
+        let url = Url::from_str(&format!("{}/v2/payments/captures/{}/refund", env::var("PAYPAL_API_BASE_URL").unwrap(), payment.payment_processor_id)).unwrap();
+
+        let http_client = reqwest::Client::new();
+        match http_client.post(url)
+            .bearer_auth(access_token)
+            // PayPal deduplicates retried refund requests carrying the same id
+            .header("PayPal-Request-Id", refund.id.clone())
+            .json(&refund_request_dto)
+            .send()
+            .await {
+                Ok(response) => {
+                    match response.json::<PayPalRefundResponseDto>().await {
+                        Ok(refund_response_dto) => {
+                            refund.payment_processor_refund_id = refund_response_dto.id;
+                            refund.payment_processor_status = refund_response_dto.status.to_lowercase();
+
+                            Ok(refund)
+                        },
+                        Err(e) => {
+                            event!(Level::WARN, "Error occurred when deserializing PayPalRefundResponseDto: {}", e);
+                            Err(format!("Error occurred when deserializing PayPalRefundResponseDto: {}", e))
+                        }
+                    }
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred when sending CreateRefundRequest to PayPal: {}", e);
+                    Err(format!("Error occurred when sending CreateRefundRequest to PayPal: {}", e))
+                }
+            }
+    }
+
+    async fn list_charges(&self, _created_from: u64, _created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String> {
+        event!(Level::WARN, "Listing charges is not supported by PayPal");
+        Err(String::from("Listing charges is not supported by PayPal"))
+    }
+
+    async fn list_refunds(&self, _created_from: u64, _created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String> {
+        event!(Level::WARN, "Listing refunds is not supported by PayPal");
+        Err(String::from("Listing refunds is not supported by PayPal"))
+    }
+
+    fn parse_webhook_event(&self, _payload: &str, _headers: &HashMap<String, String>) -> Result<PaymentProcessorWebhookEventDto, String> {
+        event!(Level::WARN, "Webhooks are not supported for PayPal");
+        Err(String::from("Webhooks are not supported for PayPal"))
+    }
+
+    // PayPal orders carry their items inline, there is no catalog to keep in sync
+    async fn create_product(&self, _product_id: String, _name: String, _tax_code: Option<String>) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn create_product_pricing(&self, _product_id: String, _currency: String, _unit_amount: i64, _recurring: Option<PaymentProcessorRecurringDto>) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn create_subscription_checkout_session(&self, _subscription: Subscription) -> Result<Subscription, String> {
+        event!(Level::WARN, "Subscriptions are not supported by PayPal");
+        Err(String::from("Subscriptions are not supported by PayPal"))
+    }
+
+    async fn cancel_subscription(&self, _subscription_id: String, _at_period_end: bool) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        event!(Level::WARN, "Subscriptions are not supported by PayPal");
+        Err(String::from("Subscriptions are not supported by PayPal"))
+    }
+
+    async fn pause_subscription(&self, _subscription_id: String) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        event!(Level::WARN, "Subscriptions are not supported by PayPal");
+        Err(String::from("Subscriptions are not supported by PayPal"))
+    }
+
+    async fn resume_subscription(&self, _subscription_id: String) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        event!(Level::WARN, "Subscriptions are not supported by PayPal");
+        Err(String::from("Subscriptions are not supported by PayPal"))
+    }
+
+    async fn list_payment_methods(&self, _customer_id: String) -> Result<Vec<PaymentProcessorPaymentMethodResponseDto>, String> {
+        Ok(Vec::new())
+    }
+
+    async fn detach_payment_method(&self, _payment_method_id: String) -> Result<(), String> {
+        event!(Level::WARN, "Saved payment methods are not supported by PayPal");
+        Err(String::from("Saved payment methods are not supported by PayPal"))
+    }
+
+    async fn create_setup_checkout_session(&self, _customer_id: String, _currency: String, _checkout_mode: String) -> Result<PaymentProcessorCreateCheckoutSessionResponseDto, String> {
+        event!(Level::WARN, "Saved payment methods are not supported by PayPal");
+        Err(String::from("Saved payment methods are not supported by PayPal"))
+    }
+
+    async fn submit_dispute_evidence(&self, _dispute_id: String, _evidence: HashMap<String, String>, _files: Vec<PaymentProcessorDisputeEvidenceFileDto>, _submit: bool) -> Result<PaymentProcessorDisputeResponseDto, String> {
+        event!(Level::WARN, "Disputes are not supported by PayPal");
+        Err(String::from("Disputes are not supported by PayPal"))
+    }
+}
+
+// Completes every checkout immediately without calling out anywhere, for local development and end-to-end testing
+pub struct FakePaymentProcessor {}
+
+impl FakePaymentProcessor {
+    pub fn new() -> Self {
+        FakePaymentProcessor {}
+    }
+}
+
+#[async_trait]
+impl PaymentProcessor for FakePaymentProcessor {
+    async fn ensure_customer(&self, mut customer: Customer) -> Result<Customer, String> {
+        if customer.payment_processor_customer_id.is_empty() {
+            customer.payment_processor_customer_id = format!("fake_cus_{}", uuid::Uuid::new_v4());
+        }
+
+        Ok(customer)
+    }
+
+    async fn create_checkout_session(&self, mut payment: Payment) -> Result<Payment, String> {
+        select_cheapest_shipping_rate(&mut payment);
+        payment.payment_processor_checkout_session_id = format!("fake_cs_{}", uuid::Uuid::new_v4());
+        if payment.checkout_mode == CheckoutMode::HOSTED.to_string() {
+            payment.payment_processor_checkout_session_url = format!("{}/success?session_id={}", env::var("PAYMENT_REDIRECT_BASE_URL").unwrap_or_default(), payment.payment_processor_checkout_session_id);
+        } else {
+            payment.payment_processor_checkout_session_client_secret = format!("{}_secret", payment.payment_processor_checkout_session_id);
+        }
+        payment.payment_processor_checkout_session_expires_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 3600;
+
+        Ok(payment)
+    }
+
+    async fn get_checkout_session(&self, session_id: String) -> Result<PaymentProcessorCheckoutSessionResponseDto, String> {
+        Ok(PaymentProcessorCheckoutSessionResponseDto {
+            payment_intent: Some(format!("fake_pi_{}", session_id)),
+            session_id,
+            client_reference_id: None,
+            metadata: HashMap::new(),
+            status: Some(String::from("complete")),
+            payment_status: String::from("paid"),
+            customer_details: None,
+            mode: None,
+            subscription: None,
+            customer: None,
+            automatic_tax: None,
+            line_items: None,
+            shipping_cost: None,
+            shipping_details: None,
+            collected_information: None,
+        })
+    }
+
+    async fn complete_checkout_session(&self, session_id: String) -> Result<PaymentProcessorCheckoutSessionResponseDto, String> {
+        self.get_checkout_session(session_id).await
+    }
+
+    async fn expire_checkout_session(&self, _session_id: String) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn create_refund(&self, _payment: &Payment, mut refund: Refund) -> Result<Refund, String> {
+        refund.payment_processor_refund_id = format!("fake_re_{}", refund.id);
+        refund.payment_processor_status = String::from("succeeded");
+
+        Ok(refund)
+    }
+
+    async fn list_charges(&self, _created_from: u64, _created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String> {
+        Ok(Vec::new())
+    }
+
+    async fn list_refunds(&self, _created_from: u64, _created_to: u64) -> Result<Vec<PaymentProcessorTransactionResponseDto>, String> {
+        Ok(Vec::new())
+    }
+
+    fn parse_webhook_event(&self, _payload: &str, _headers: &HashMap<String, String>) -> Result<PaymentProcessorWebhookEventDto, String> {
+        event!(Level::WARN, "Webhooks are not supported for the fake payment processor");
+        Err(String::from("Webhooks are not supported for the fake payment processor"))
+    }
+
+    async fn create_product(&self, _product_id: String, _name: String, _tax_code: Option<String>) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn create_product_pricing(&self, _product_id: String, _currency: String, _unit_amount: i64, _recurring: Option<PaymentProcessorRecurringDto>) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn create_subscription_checkout_session(&self, _subscription: Subscription) -> Result<Subscription, String> {
+        event!(Level::WARN, "Subscriptions are not supported by the fake payment processor");
+        Err(String::from("Subscriptions are not supported by the fake payment processor"))
+    }
+
+    async fn cancel_subscription(&self, _subscription_id: String, _at_period_end: bool) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        event!(Level::WARN, "Subscriptions are not supported by the fake payment processor");
+        Err(String::from("Subscriptions are not supported by the fake payment processor"))
+    }
+
+    async fn pause_subscription(&self, _subscription_id: String) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        event!(Level::WARN, "Subscriptions are not supported by the fake payment processor");
+        Err(String::from("Subscriptions are not supported by the fake payment processor"))
+    }
+
+    async fn resume_subscription(&self, _subscription_id: String) -> Result<PaymentProcessorSubscriptionResponseDto, String> {
+        event!(Level::WARN, "Subscriptions are not supported by the fake payment processor");
+        Err(String::from("Subscriptions are not supported by the fake payment processor"))
+    }
+
+    async fn list_payment_methods(&self, _customer_id: String) -> Result<Vec<PaymentProcessorPaymentMethodResponseDto>, String> {
+        Ok(Vec::new())
+    }
+
+    async fn detach_payment_method(&self, _payment_method_id: String) -> Result<(), String> {
+        event!(Level::WARN, "Saved payment methods are not supported by the fake payment processor");
+        Err(String::from("Saved payment methods are not supported by the fake payment processor"))
+    }
+
+    async fn create_setup_checkout_session(&self, _customer_id: String, _currency: String, _checkout_mode: String) -> Result<PaymentProcessorCreateCheckoutSessionResponseDto, String> {
+        event!(Level::WARN, "Saved payment methods are not supported by the fake payment processor");
+        Err(String::from("Saved payment methods are not supported by the fake payment processor"))
+    }
+
+    async fn submit_dispute_evidence(&self, _dispute_id: String, _evidence: HashMap<String, String>, _files: Vec<PaymentProcessorDisputeEvidenceFileDto>, _submit: bool) -> Result<PaymentProcessorDisputeResponseDto, String> {
+        event!(Level::WARN, "Disputes are not supported by the fake payment processor");
+        Err(String::from("Disputes are not supported by the fake payment processor"))
+    }
 }
@@ -0,0 +1,748 @@
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use std::time::Duration;
+
+use mongodb::{bson::{doc, DateTime}, error::{ErrorKind, WriteFailure}, options::IndexOptions, Collection, Database, IndexModel};
+use tracing::{event, Level};
+
+use crate::domain::{Customer, IdempotencyKey, Payment, PaymentSaga, PaymentSagaStatus, PaymentStatus, Product, Promotion, PromotionRedemption, ReconciliationReport, Subscription};
+
+pub static PAYMENTS_COLLECTION_NAME: &str = "payments";
+pub static RECONCILIATION_REPORTS_COLLECTION_NAME: &str = "reconciliation_reports";
+pub static IDEMPOTENCY_KEYS_COLLECTION_NAME: &str = "idempotency_keys";
+pub static SUBSCRIPTIONS_COLLECTION_NAME: &str = "subscriptions";
+pub static CUSTOMERS_COLLECTION_NAME: &str = "customers";
+pub static PROMOTIONS_COLLECTION_NAME: &str = "promotions";
+pub static PRODUCTS_COLLECTION_NAME: &str = "products";
+pub static PAYMENT_SAGAS_COLLECTION_NAME: &str = "payment_sagas";
+
+#[async_trait]
+pub trait PaymentRepository {
+    async fn create(&self, payment: &Payment) -> Result<(), String>;
+    async fn read(&self, id: &str) -> Result<Option<Payment>, String>;
+    async fn read_by_checkout_session_id(&self, checkout_session_id: &str) -> Result<Option<Payment>, String>;
+    async fn read_by_payment_processor_id(&self, payment_processor_id: &str) -> Result<Option<Payment>, String>;
+    async fn read_by_dispute_id(&self, dispute_id: &str) -> Result<Option<Payment>, String>;
+    async fn read_active_by_order_id(&self, order_id: &str) -> Result<Option<Payment>, String>;
+    // Payments paid before the time they were paid at was recorded are read by when they were created instead
+    async fn read_paid_between(&self, paid_from: u64, paid_to: u64) -> Result<Vec<Payment>, String>;
+    async fn read_with_refunds_created_between(&self, created_from: u64, created_to: u64) -> Result<Vec<Payment>, String>;
+    async fn read_by_statuses_created_before(&self, statuses: Vec<String>, created_before: u64) -> Result<Vec<Payment>, String>;
+    // Only saves over the payment when it is still at the expected version, returns false when it has moved on since it
+    // was read
+    async fn update(&self, payment: &Payment, expected_version: u32) -> Result<bool, String>;
+    // Payments holding events recorded before the given time that are still to be published
+    async fn read_with_outbox_events_created_before(&self, created_before: u64) -> Result<Vec<Payment>, String>;
+    // Takes a published event out of the payment's outbox. The version is bumped so a writer that read the payment while
+    // it still held the event saves over it again rather than putting the event back.
+    async fn remove_outbox_event(&self, payment_id: &str, event_id: &str) -> Result<(), String>;
+}
+
+pub struct MongoDbPaymentRepository {
+    collection: Collection<Payment>,
+}
+
+impl MongoDbPaymentRepository {
+    pub fn new(database: &Database) -> Self {
+        MongoDbPaymentRepository {
+            collection: database.collection::<Payment>(PAYMENTS_COLLECTION_NAME)
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentRepository for MongoDbPaymentRepository {
+    async fn create(&self, payment: &Payment) -> Result<(), String> {
+        match self.collection.insert_one(payment).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when inserting Payment {}: {}", payment.id, e);
+                Err(format!("Error occurred when inserting Payment {}: {}", payment.id, e))
+            }
+        }
+    }
+
+    async fn read(&self, id: &str) -> Result<Option<Payment>, String> {
+        match self.collection.find_one(doc! {"id": id}).await {
+            Ok(payment) => Ok(payment),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Payment {}: {}", id, e);
+                Err(format!("Error occurred when reading Payment {}: {}", id, e))
+            }
+        }
+    }
+
+    async fn read_by_checkout_session_id(&self, checkout_session_id: &str) -> Result<Option<Payment>, String> {
+        match self.collection.find_one(doc! {"payment_processor_checkout_session_id": checkout_session_id}).await {
+            Ok(payment) => Ok(payment),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Payment for checkout session {}: {}", checkout_session_id, e);
+                Err(format!("Error occurred when reading Payment for checkout session {}: {}", checkout_session_id, e))
+            }
+        }
+    }
+
+    async fn read_by_payment_processor_id(&self, payment_processor_id: &str) -> Result<Option<Payment>, String> {
+        match self.collection.find_one(doc! {"payment_processor_id": payment_processor_id}).await {
+            Ok(payment) => Ok(payment),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Payment for payment processor id {}: {}", payment_processor_id, e);
+                Err(format!("Error occurred when reading Payment for payment processor id {}: {}", payment_processor_id, e))
+            }
+        }
+    }
+
+    async fn read_by_dispute_id(&self, dispute_id: &str) -> Result<Option<Payment>, String> {
+        match self.collection.find_one(doc! {"disputes.id": dispute_id}).await {
+            Ok(payment) => Ok(payment),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Payment for dispute {}: {}", dispute_id, e);
+                Err(format!("Error occurred when reading Payment for dispute {}: {}", dispute_id, e))
+            }
+        }
+    }
+
+    async fn read_active_by_order_id(&self, order_id: &str) -> Result<Option<Payment>, String> {
+        let inactive_statuses = vec![PaymentStatus::EXPIRED.to_string(), PaymentStatus::CANCELLED.to_string()];
+
+        match self.collection.find_one(doc! {"order_id": order_id, "status": {"$nin": inactive_statuses}}).sort(doc! {"created_at": -1}).await {
+            Ok(payment) => Ok(payment),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading active Payment for order {}: {}", order_id, e);
+                Err(format!("Error occurred when reading active Payment for order {}: {}", order_id, e))
+            }
+        }
+    }
+
+    async fn read_paid_between(&self, paid_from: u64, paid_to: u64) -> Result<Vec<Payment>, String> {
+        let filter = doc! {"$or": [
+            {"paid_at": {"$gte": paid_from as i64, "$lte": paid_to as i64}},
+            {"paid_at": {"$exists": false}, "created_at": {"$gte": paid_from as i64, "$lte": paid_to as i64}},
+        ]};
+
+        match self.collection.find(filter).await {
+            Ok(cursor) => {
+                match cursor.try_collect().await {
+                    Ok(payments) => Ok(payments),
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when iterating Payments paid between {} and {}: {}", paid_from, paid_to, e);
+                        Err(format!("Error occurred when iterating Payments paid between {} and {}: {}", paid_from, paid_to, e))
+                    }
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Payments paid between {} and {}: {}", paid_from, paid_to, e);
+                Err(format!("Error occurred when reading Payments paid between {} and {}: {}", paid_from, paid_to, e))
+            }
+        }
+    }
+
+    async fn read_with_refunds_created_between(&self, created_from: u64, created_to: u64) -> Result<Vec<Payment>, String> {
+        match self.collection.find(doc! {"refunds": {"$elemMatch": {"created_at": {"$gte": created_from as i64, "$lte": created_to as i64}}}}).await {
+            Ok(cursor) => {
+                match cursor.try_collect().await {
+                    Ok(payments) => Ok(payments),
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when iterating Payments refunded between {} and {}: {}", created_from, created_to, e);
+                        Err(format!("Error occurred when iterating Payments refunded between {} and {}: {}", created_from, created_to, e))
+                    }
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Payments refunded between {} and {}: {}", created_from, created_to, e);
+                Err(format!("Error occurred when reading Payments refunded between {} and {}: {}", created_from, created_to, e))
+            }
+        }
+    }
+
+    async fn read_by_statuses_created_before(&self, statuses: Vec<String>, created_before: u64) -> Result<Vec<Payment>, String> {
+        match self.collection.find(doc! {"status": {"$in": &statuses}, "created_at": {"$lt": created_before as i64}}).await {
+            Ok(cursor) => {
+                match cursor.try_collect().await {
+                    Ok(payments) => Ok(payments),
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when iterating Payments with statuses {:?}: {}", statuses, e);
+                        Err(format!("Error occurred when iterating Payments with statuses {:?}: {}", statuses, e))
+                    }
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Payments with statuses {:?}: {}", statuses, e);
+                Err(format!("Error occurred when reading Payments with statuses {:?}: {}", statuses, e))
+            }
+        }
+    }
+
+    async fn read_with_outbox_events_created_before(&self, created_before: u64) -> Result<Vec<Payment>, String> {
+        match self.collection.find(doc! {"outbox": {"$elemMatch": {"created_at": {"$lt": created_before as i64}}}}).await {
+            Ok(cursor) => {
+                match cursor.try_collect().await {
+                    Ok(payments) => Ok(payments),
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when iterating Payments with unpublished events: {}", e);
+                        Err(format!("Error occurred when iterating Payments with unpublished events: {}", e))
+                    }
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Payments with unpublished events: {}", e);
+                Err(format!("Error occurred when reading Payments with unpublished events: {}", e))
+            }
+        }
+    }
+
+    async fn remove_outbox_event(&self, payment_id: &str, event_id: &str) -> Result<(), String> {
+        match self.collection.update_one(doc! {"id": payment_id, "outbox.event_id": event_id}, doc! {"$pull": {"outbox": {"event_id": event_id}}, "$inc": {"version": 1}}).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when removing published event {} from Payment {}: {}", event_id, payment_id, e);
+                Err(format!("Error occurred when removing published event {} from Payment {}: {}", event_id, payment_id, e))
+            }
+        }
+    }
+
+    async fn update(&self, payment: &Payment, expected_version: u32) -> Result<bool, String> {
+        // Payments saved before versions were kept have none, which counts as version 0
+        let version_filter = if expected_version == 0 {
+            doc! {"$or": [{"version": 0}, {"version": {"$exists": false}}]}
+        } else {
+            doc! {"version": expected_version}
+        };
+
+        match self.collection.replace_one(doc! {"$and": [{"id": &payment.id}, version_filter]}, payment).await {
+            Ok(result) => Ok(result.matched_count > 0),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when updating Payment {}: {}", payment.id, e);
+                Err(format!("Error occurred when updating Payment {}: {}", payment.id, e))
+            }
+        }
+    }
+}
+
+
+#[async_trait]
+pub trait ReconciliationReportRepository {
+    async fn create(&self, reconciliation_report: &ReconciliationReport) -> Result<(), String>;
+}
+
+pub struct MongoDbReconciliationReportRepository {
+    collection: Collection<ReconciliationReport>,
+}
+
+impl MongoDbReconciliationReportRepository {
+    pub fn new(database: &Database) -> Self {
+        MongoDbReconciliationReportRepository {
+            collection: database.collection::<ReconciliationReport>(RECONCILIATION_REPORTS_COLLECTION_NAME)
+        }
+    }
+}
+
+#[async_trait]
+impl ReconciliationReportRepository for MongoDbReconciliationReportRepository {
+    async fn create(&self, reconciliation_report: &ReconciliationReport) -> Result<(), String> {
+        match self.collection.insert_one(reconciliation_report).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when inserting ReconciliationReport {}: {}", reconciliation_report.id, e);
+                Err(format!("Error occurred when inserting ReconciliationReport {}: {}", reconciliation_report.id, e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait IdempotencyKeyRepository {
+    // Returns false when a key that has not expired yet already exists for the subject
+    async fn create(&self, idempotency_key: &IdempotencyKey) -> Result<bool, String>;
+    async fn read(&self, subject: &str, key: &str) -> Result<Option<IdempotencyKey>, String>;
+    async fn update(&self, idempotency_key: &IdempotencyKey) -> Result<(), String>;
+    async fn delete(&self, subject: &str, key: &str) -> Result<(), String>;
+}
+
+pub struct MongoDbIdempotencyKeyRepository {
+    collection: Collection<IdempotencyKey>,
+}
+
+impl MongoDbIdempotencyKeyRepository {
+    pub fn new(database: &Database) -> Self {
+        MongoDbIdempotencyKeyRepository {
+            collection: database.collection::<IdempotencyKey>(IDEMPOTENCY_KEYS_COLLECTION_NAME)
+        }
+    }
+
+    // Keys are unique per subject, and MongoDB's TTL monitor removes them once expires_at has passed
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        let indexes = vec![
+            IndexModel::builder()
+                .keys(doc! {"subject": 1, "key": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! {"expires_at": 1})
+                .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+                .build(),
+        ];
+
+        match self.collection.create_indexes(indexes).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when creating IdempotencyKey indexes: {}", e);
+                Err(format!("Error occurred when creating IdempotencyKey indexes: {}", e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl IdempotencyKeyRepository for MongoDbIdempotencyKeyRepository {
+    async fn create(&self, idempotency_key: &IdempotencyKey) -> Result<bool, String> {
+        // The TTL monitor only runs periodically, so an expired key may still be present and is cleared here first
+        if let Err(e) = self.collection.delete_one(doc! {"subject": &idempotency_key.subject, "key": &idempotency_key.key, "expires_at": {"$lte": DateTime::now()}}).await {
+            event!(Level::WARN, "Error occurred when clearing expired IdempotencyKey {}: {}", idempotency_key.key, e);
+            return Err(format!("Error occurred when clearing expired IdempotencyKey {}: {}", idempotency_key.key, e));
+        }
+
+        match self.collection.insert_one(idempotency_key).await {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                match *e.kind {
+                    ErrorKind::Write(WriteFailure::WriteError(ref write_error)) if write_error.code == 11000 => Ok(false),
+                    _ => {
+                        event!(Level::WARN, "Error occurred when inserting IdempotencyKey {}: {}", idempotency_key.key, e);
+                        Err(format!("Error occurred when inserting IdempotencyKey {}: {}", idempotency_key.key, e))
+                    }
+                }
+            }
+        }
+    }
+
+    async fn read(&self, subject: &str, key: &str) -> Result<Option<IdempotencyKey>, String> {
+        match self.collection.find_one(doc! {"subject": subject, "key": key}).await {
+            Ok(idempotency_key) => Ok(idempotency_key),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading IdempotencyKey {}: {}", key, e);
+                Err(format!("Error occurred when reading IdempotencyKey {}: {}", key, e))
+            }
+        }
+    }
+
+    async fn update(&self, idempotency_key: &IdempotencyKey) -> Result<(), String> {
+        match self.collection.replace_one(doc! {"subject": &idempotency_key.subject, "key": &idempotency_key.key}, idempotency_key).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when updating IdempotencyKey {}: {}", idempotency_key.key, e);
+                Err(format!("Error occurred when updating IdempotencyKey {}: {}", idempotency_key.key, e))
+            }
+        }
+    }
+
+    async fn delete(&self, subject: &str, key: &str) -> Result<(), String> {
+        match self.collection.delete_one(doc! {"subject": subject, "key": key}).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when deleting IdempotencyKey {}: {}", key, e);
+                Err(format!("Error occurred when deleting IdempotencyKey {}: {}", key, e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait SubscriptionRepository {
+    async fn create(&self, subscription: &Subscription) -> Result<(), String>;
+    async fn read(&self, id: &str) -> Result<Option<Subscription>, String>;
+    async fn read_by_checkout_session_id(&self, checkout_session_id: &str) -> Result<Option<Subscription>, String>;
+    async fn read_by_payment_processor_subscription_id(&self, payment_processor_subscription_id: &str) -> Result<Option<Subscription>, String>;
+    async fn update(&self, subscription: &Subscription) -> Result<(), String>;
+}
+
+pub struct MongoDbSubscriptionRepository {
+    collection: Collection<Subscription>,
+}
+
+impl MongoDbSubscriptionRepository {
+    pub fn new(database: &Database) -> Self {
+        MongoDbSubscriptionRepository {
+            collection: database.collection::<Subscription>(SUBSCRIPTIONS_COLLECTION_NAME)
+        }
+    }
+}
+
+#[async_trait]
+impl SubscriptionRepository for MongoDbSubscriptionRepository {
+    async fn create(&self, subscription: &Subscription) -> Result<(), String> {
+        match self.collection.insert_one(subscription).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when inserting Subscription {}: {}", subscription.id, e);
+                Err(format!("Error occurred when inserting Subscription {}: {}", subscription.id, e))
+            }
+        }
+    }
+
+    async fn read(&self, id: &str) -> Result<Option<Subscription>, String> {
+        match self.collection.find_one(doc! {"id": id}).await {
+            Ok(subscription) => Ok(subscription),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Subscription {}: {}", id, e);
+                Err(format!("Error occurred when reading Subscription {}: {}", id, e))
+            }
+        }
+    }
+
+    async fn read_by_checkout_session_id(&self, checkout_session_id: &str) -> Result<Option<Subscription>, String> {
+        match self.collection.find_one(doc! {"payment_processor_checkout_session_id": checkout_session_id}).await {
+            Ok(subscription) => Ok(subscription),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Subscription for checkout session {}: {}", checkout_session_id, e);
+                Err(format!("Error occurred when reading Subscription for checkout session {}: {}", checkout_session_id, e))
+            }
+        }
+    }
+
+    async fn read_by_payment_processor_subscription_id(&self, payment_processor_subscription_id: &str) -> Result<Option<Subscription>, String> {
+        match self.collection.find_one(doc! {"payment_processor_subscription_id": payment_processor_subscription_id}).await {
+            Ok(subscription) => Ok(subscription),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Subscription for payment processor subscription {}: {}", payment_processor_subscription_id, e);
+                Err(format!("Error occurred when reading Subscription for payment processor subscription {}: {}", payment_processor_subscription_id, e))
+            }
+        }
+    }
+
+    async fn update(&self, subscription: &Subscription) -> Result<(), String> {
+        match self.collection.replace_one(doc! {"id": &subscription.id}, subscription).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when updating Subscription {}: {}", subscription.id, e);
+                Err(format!("Error occurred when updating Subscription {}: {}", subscription.id, e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait CustomerRepository {
+    // Returns false when the subject already has a customer with the payment processor
+    async fn create(&self, customer: &Customer) -> Result<bool, String>;
+    async fn read(&self, subject: &str, payment_processor: &str) -> Result<Option<Customer>, String>;
+    async fn update(&self, customer: &Customer) -> Result<(), String>;
+}
+
+pub struct MongoDbCustomerRepository {
+    collection: Collection<Customer>,
+}
+
+impl MongoDbCustomerRepository {
+    pub fn new(database: &Database) -> Self {
+        MongoDbCustomerRepository {
+            collection: database.collection::<Customer>(CUSTOMERS_COLLECTION_NAME)
+        }
+    }
+
+    // A subject has at most one customer per payment processor
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        let index = IndexModel::builder()
+            .keys(doc! {"subject": 1, "payment_processor": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+
+        match self.collection.create_index(index).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when creating Customer indexes: {}", e);
+                Err(format!("Error occurred when creating Customer indexes: {}", e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CustomerRepository for MongoDbCustomerRepository {
+    async fn create(&self, customer: &Customer) -> Result<bool, String> {
+        match self.collection.insert_one(customer).await {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                match *e.kind {
+                    ErrorKind::Write(WriteFailure::WriteError(ref write_error)) if write_error.code == 11000 => Ok(false),
+                    _ => {
+                        event!(Level::WARN, "Error occurred when inserting Customer for {}: {}", customer.subject, e);
+                        Err(format!("Error occurred when inserting Customer for {}: {}", customer.subject, e))
+                    }
+                }
+            }
+        }
+    }
+
+    async fn read(&self, subject: &str, payment_processor: &str) -> Result<Option<Customer>, String> {
+        match self.collection.find_one(doc! {"subject": subject, "payment_processor": payment_processor}).await {
+            Ok(customer) => Ok(customer),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Customer for {}: {}", subject, e);
+                Err(format!("Error occurred when reading Customer for {}: {}", subject, e))
+            }
+        }
+    }
+
+    async fn update(&self, customer: &Customer) -> Result<(), String> {
+        match self.collection.replace_one(doc! {"subject": &customer.subject, "payment_processor": &customer.payment_processor}, customer).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when updating Customer for {}: {}", customer.subject, e);
+                Err(format!("Error occurred when updating Customer for {}: {}", customer.subject, e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait PromotionRepository {
+    // Returns false when a promotion with the code already exists
+    async fn create(&self, promotion: &Promotion) -> Result<bool, String>;
+    async fn read(&self, code: &str) -> Result<Option<Promotion>, String>;
+    // Takes a redemption only while the promotion is below both of its limits, returns false when either is reached
+    async fn add_redemption(&self, code: &str, redemption: &PromotionRedemption, max_redemptions: u32, max_redemptions_per_customer: u32) -> Result<bool, String>;
+    // Hands the redemption held by one payment to another, returns false when the first holds none
+    async fn transfer_redemption(&self, code: &str, from_payment_id: &str, to_payment_id: &str) -> Result<bool, String>;
+    async fn remove_redemptions(&self, code: &str, payment_ids: &[String]) -> Result<(), String>;
+}
+
+pub struct MongoDbPromotionRepository {
+    collection: Collection<Promotion>,
+}
+
+impl MongoDbPromotionRepository {
+    pub fn new(database: &Database) -> Self {
+        MongoDbPromotionRepository {
+            collection: database.collection::<Promotion>(PROMOTIONS_COLLECTION_NAME)
+        }
+    }
+
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        let index = IndexModel::builder()
+            .keys(doc! {"code": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+
+        match self.collection.create_index(index).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when creating Promotion indexes: {}", e);
+                Err(format!("Error occurred when creating Promotion indexes: {}", e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PromotionRepository for MongoDbPromotionRepository {
+    async fn create(&self, promotion: &Promotion) -> Result<bool, String> {
+        match self.collection.insert_one(promotion).await {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                match *e.kind {
+                    ErrorKind::Write(WriteFailure::WriteError(ref write_error)) if write_error.code == 11000 => Ok(false),
+                    _ => {
+                        event!(Level::WARN, "Error occurred when inserting Promotion {}: {}", promotion.code, e);
+                        Err(format!("Error occurred when inserting Promotion {}: {}", promotion.code, e))
+                    }
+                }
+            }
+        }
+    }
+
+    async fn read(&self, code: &str) -> Result<Option<Promotion>, String> {
+        match self.collection.find_one(doc! {"code": code}).await {
+            Ok(promotion) => Ok(promotion),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Promotion {}: {}", code, e);
+                Err(format!("Error occurred when reading Promotion {}: {}", code, e))
+            }
+        }
+    }
+
+    async fn add_redemption(&self, code: &str, redemption: &PromotionRedemption, max_redemptions: u32, max_redemptions_per_customer: u32) -> Result<bool, String> {
+        // Promotions created before redemptions were kept have none
+        let mut limits = vec![doc! {"$literal": true}];
+        if max_redemptions > 0 {
+            limits.push(doc! {"$lt": [{"$size": {"$ifNull": ["$redemptions", []]}}, max_redemptions as i64]});
+        }
+        if max_redemptions_per_customer > 0 {
+            let customer_redemptions = doc! {"$filter": {"input": {"$ifNull": ["$redemptions", []]}, "cond": {"$eq": ["$$this.redeemed_by", &redemption.redeemed_by]}}};
+            limits.push(doc! {"$lt": [{"$size": customer_redemptions}, max_redemptions_per_customer as i64]});
+        }
+
+        let pushed_redemption = doc! {"payment_id": &redemption.payment_id, "redeemed_by": &redemption.redeemed_by, "redeemed_at": redemption.redeemed_at as i64};
+        match self.collection.update_one(doc! {"code": code, "$expr": {"$and": limits}}, doc! {"$push": {"redemptions": pushed_redemption}}).await {
+            Ok(result) => Ok(result.matched_count > 0),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when redeeming Promotion {} for Payment {}: {}", code, redemption.payment_id, e);
+                Err(format!("Error occurred when redeeming Promotion {} for Payment {}: {}", code, redemption.payment_id, e))
+            }
+        }
+    }
+
+    async fn transfer_redemption(&self, code: &str, from_payment_id: &str, to_payment_id: &str) -> Result<bool, String> {
+        match self.collection.update_one(doc! {"code": code, "redemptions.payment_id": from_payment_id}, doc! {"$set": {"redemptions.$.payment_id": to_payment_id}}).await {
+            Ok(result) => Ok(result.matched_count > 0),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when moving redemption of Promotion {} from Payment {} to Payment {}: {}", code, from_payment_id, to_payment_id, e);
+                Err(format!("Error occurred when moving redemption of Promotion {} from Payment {} to Payment {}: {}", code, from_payment_id, to_payment_id, e))
+            }
+        }
+    }
+
+    async fn remove_redemptions(&self, code: &str, payment_ids: &[String]) -> Result<(), String> {
+        match self.collection.update_one(doc! {"code": code}, doc! {"$pull": {"redemptions": {"payment_id": {"$in": payment_ids}}}}).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when releasing redemptions of Promotion {}: {}", code, e);
+                Err(format!("Error occurred when releasing redemptions of Promotion {}: {}", code, e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait ProductRepository {
+    // A redelivered ProductCreated event overwrites the product with the same details
+    async fn upsert(&self, product: &Product) -> Result<(), String>;
+    async fn read_by_ids(&self, ids: Vec<String>) -> Result<Vec<Product>, String>;
+}
+
+pub struct MongoDbProductRepository {
+    collection: Collection<Product>,
+}
+
+impl MongoDbProductRepository {
+    pub fn new(database: &Database) -> Self {
+        MongoDbProductRepository {
+            collection: database.collection::<Product>(PRODUCTS_COLLECTION_NAME)
+        }
+    }
+}
+
+#[async_trait]
+impl ProductRepository for MongoDbProductRepository {
+    async fn upsert(&self, product: &Product) -> Result<(), String> {
+        match self.collection.replace_one(doc! {"id": &product.id}, product).upsert(true).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when saving Product {}: {}", product.id, e);
+                Err(format!("Error occurred when saving Product {}: {}", product.id, e))
+            }
+        }
+    }
+
+    async fn read_by_ids(&self, ids: Vec<String>) -> Result<Vec<Product>, String> {
+        match self.collection.find(doc! {"id": {"$in": &ids}}).await {
+            Ok(cursor) => {
+                match cursor.try_collect().await {
+                    Ok(products) => Ok(products),
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when iterating Products {:?}: {}", ids, e);
+                        Err(format!("Error occurred when iterating Products {:?}: {}", ids, e))
+                    }
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading Products {:?}: {}", ids, e);
+                Err(format!("Error occurred when reading Products {:?}: {}", ids, e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+pub trait PaymentSagaRepository {
+    // Returns false when the order already has a saga
+    async fn create(&self, saga: &PaymentSaga) -> Result<bool, String>;
+    async fn read(&self, order_id: &str) -> Result<Option<PaymentSaga>, String>;
+    // Only saves over the saga when it is still at the expected version, returns false when it has moved on since it
+    // was read
+    async fn update(&self, saga: &PaymentSaga, expected_version: u32) -> Result<bool, String>;
+    async fn read_compensating_updated_before(&self, updated_before: u64) -> Result<Vec<PaymentSaga>, String>;
+}
+
+pub struct MongoDbPaymentSagaRepository {
+    collection: Collection<PaymentSaga>,
+}
+
+impl MongoDbPaymentSagaRepository {
+    pub fn new(database: &Database) -> Self {
+        MongoDbPaymentSagaRepository {
+            collection: database.collection::<PaymentSaga>(PAYMENT_SAGAS_COLLECTION_NAME)
+        }
+    }
+
+    pub async fn create_indexes(&self) -> Result<(), String> {
+        let index = IndexModel::builder()
+            .keys(doc! {"order_id": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+
+        match self.collection.create_index(index).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when creating PaymentSaga indexes: {}", e);
+                Err(format!("Error occurred when creating PaymentSaga indexes: {}", e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentSagaRepository for MongoDbPaymentSagaRepository {
+    async fn create(&self, saga: &PaymentSaga) -> Result<bool, String> {
+        match self.collection.insert_one(saga).await {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                match *e.kind {
+                    ErrorKind::Write(WriteFailure::WriteError(ref write_error)) if write_error.code == 11000 => Ok(false),
+                    _ => {
+                        event!(Level::WARN, "Error occurred when inserting PaymentSaga for order {}: {}", saga.order_id, e);
+                        Err(format!("Error occurred when inserting PaymentSaga for order {}: {}", saga.order_id, e))
+                    }
+                }
+            }
+        }
+    }
+
+    async fn read(&self, order_id: &str) -> Result<Option<PaymentSaga>, String> {
+        match self.collection.find_one(doc! {"order_id": order_id}).await {
+            Ok(saga) => Ok(saga),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading PaymentSaga for order {}: {}", order_id, e);
+                Err(format!("Error occurred when reading PaymentSaga for order {}: {}", order_id, e))
+            }
+        }
+    }
+
+    async fn update(&self, saga: &PaymentSaga, expected_version: u32) -> Result<bool, String> {
+        match self.collection.replace_one(doc! {"order_id": &saga.order_id, "version": expected_version}, saga).await {
+            Ok(result) => Ok(result.matched_count > 0),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when updating PaymentSaga for order {}: {}", saga.order_id, e);
+                Err(format!("Error occurred when updating PaymentSaga for order {}: {}", saga.order_id, e))
+            }
+        }
+    }
+
+    async fn read_compensating_updated_before(&self, updated_before: u64) -> Result<Vec<PaymentSaga>, String> {
+        match self.collection.find(doc! {"status": PaymentSagaStatus::COMPENSATING.to_string(), "updated_at": {"$lt": updated_before as i64}}).await {
+            Ok(cursor) => {
+                match cursor.try_collect().await {
+                    Ok(sagas) => Ok(sagas),
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred when iterating compensating PaymentSagas: {}", e);
+                        Err(format!("Error occurred when iterating compensating PaymentSagas: {}", e))
+                    }
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Error occurred when reading compensating PaymentSagas: {}", e);
+                Err(format!("Error occurred when reading compensating PaymentSagas: {}", e))
+            }
+        }
+    }
+}
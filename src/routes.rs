@@ -1,18 +1,315 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use axum::{extract::State, Json};
+use axum::{extract::{Path, State}, http::HeaderMap, Extension, Json};
+use mongodb::bson::DateTime;
 use reqwest::StatusCode;
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::{event, Level};
 
-use crate::{cqrs::{CommandHandler, CreateCheckoutSessionCommand}, dtos::ApiError, state::AppState};
+use crate::{auth::{has_scope, Claims}, domain::IdempotencyKey, cqrs::{CancelPaymentCommand, CancelSubscriptionCommand, CommandHandler, CreateCheckoutSessionCommand, CreatePromotionCommand, CreateSetupCheckoutSessionCommand, CreateSubscriptionCommand, CustomerIdentity, DetachPaymentMethodCommand, GetCheckoutSessionQuery, GetOrderPaymentQuery, GetPaymentSagaQuery, HandlePaymentProcessorWebhookCommand, HandlerError, ListPaymentMethodsQuery, PauseSubscriptionCommand, QueryHandler, ReconcilePaymentsCommand, RefundPaymentCommand, ResumeSubscriptionCommand, SubmitDisputeEvidenceCommand}, dtos::ApiError, state::AppState};
+
+pub static IDEMPOTENCY_KEY_HEADER_NAME: &str = "Idempotency-Key";
+
+fn handler_error_response(e: HandlerError) -> (StatusCode, Json<Value>) {
+    let status = match e {
+        HandlerError::BadRequest(_) => StatusCode::BAD_REQUEST,
+        HandlerError::Forbidden(_) => StatusCode::FORBIDDEN,
+        HandlerError::NotFound(_) => StatusCode::NOT_FOUND,
+        HandlerError::Conflict(_) => StatusCode::CONFLICT,
+        HandlerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (status, Json(json!(ApiError{error: e.to_string()})))
+}
 
 pub async fn index() -> &'static str {
     "Hello, World!"
 }
 
-pub async fn create_checkout_session(State(state): State<Arc<AppState>>, Json(create_checkout_session_command): Json<CreateCheckoutSessionCommand>) -> (StatusCode, Json<Value>) {
-    match state.create_checkout_session_command_handler.handle(&create_checkout_session_command).await {
+// Unavailable while the RabbitMQ connection is down, so the instance is taken out of rotation until it reconnects
+pub async fn health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>) {
+    if state.message_broker.is_connected() {
+        (StatusCode::OK, Json(json!({"status": "ok", "rabbitmq": "connected"})))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(json!({"status": "unavailable", "rabbitmq": "disconnected"})))
+    }
+}
+
+pub async fn create_checkout_session(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, headers: HeaderMap, Json(mut create_checkout_session_command): Json<CreateCheckoutSessionCommand>) -> (StatusCode, Json<Value>) {
+    create_checkout_session_command.customer = Some(CustomerIdentity {
+        subject: claims.sub.clone(),
+        email: claims.email.clone(),
+        email_verified: claims.email_verified,
+        name: claims.name.clone(),
+    });
+
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY_HEADER_NAME) {
+        Some(header_value) => {
+            match header_value.to_str() {
+                Ok(key) if !key.is_empty() => String::from(key),
+                _ => return (StatusCode::BAD_REQUEST, Json(json!(ApiError{error: format!("{} header is not valid", IDEMPOTENCY_KEY_HEADER_NAME)})))
+            }
+        },
+        None => return handle_create_checkout_session(&state, &create_checkout_session_command).await
+    };
+
+    let request_hash = format!("{:x}", Sha256::digest(serde_json::to_vec(&create_checkout_session_command).unwrap()));
+    let mut idempotency_key = IdempotencyKey {
+        subject: claims.sub,
+        key: idempotency_key,
+        request_hash,
+        response_status: None,
+        response_body: None,
+        expires_at: DateTime::from_millis(DateTime::now().timestamp_millis() + (state.idempotency_key_ttl_seconds * 1000) as i64),
+    };
+
+    match state.idempotency_key_repository.create(&idempotency_key).await {
+        Ok(true) => {
+            let (status, response) = handle_create_checkout_session(&state, &create_checkout_session_command).await;
+
+            // Server errors are not remembered so the client can retry with the same key
+            let saved = if status.is_server_error() {
+                state.idempotency_key_repository.delete(&idempotency_key.subject, &idempotency_key.key).await
+            } else {
+                idempotency_key.response_status = Some(status.as_u16());
+                idempotency_key.response_body = Some(response.0.to_string());
+                state.idempotency_key_repository.update(&idempotency_key).await
+            };
+
+            if let Err(e) = saved {
+                event!(Level::WARN, "Failed to save response for {} {}: {}", IDEMPOTENCY_KEY_HEADER_NAME, idempotency_key.key, e);
+            }
+
+            (status, response)
+        },
+        Ok(false) => {
+            match state.idempotency_key_repository.read(&idempotency_key.subject, &idempotency_key.key).await {
+                Ok(Some(stored_idempotency_key)) if stored_idempotency_key.request_hash != idempotency_key.request_hash => {
+                    (StatusCode::CONFLICT, Json(json!(ApiError{error: format!("{} {} was already used with a different request", IDEMPOTENCY_KEY_HEADER_NAME, idempotency_key.key)})))
+                },
+                Ok(Some(IdempotencyKey { response_status: Some(response_status), response_body: Some(response_body), .. })) => {
+                    event!(Level::INFO, "Replaying response for {} {}", IDEMPOTENCY_KEY_HEADER_NAME, idempotency_key.key);
+                    (StatusCode::from_u16(response_status).unwrap_or(StatusCode::OK), Json(serde_json::from_str(&response_body).unwrap_or(Value::Null)))
+                },
+                Ok(_) => {
+                    (StatusCode::CONFLICT, Json(json!(ApiError{error: format!("A request with {} {} is still in progress", IDEMPOTENCY_KEY_HEADER_NAME, idempotency_key.key)})))
+                },
+                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+            }
+        },
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    }
+}
+
+async fn handle_create_checkout_session(state: &Arc<AppState>, create_checkout_session_command: &CreateCheckoutSessionCommand) -> (StatusCode, Json<Value>) {
+    match state.create_checkout_session_command_handler.handle(create_checkout_session_command).await {
+        Ok(response) => (StatusCode::CREATED, Json(json!(response))),
+        Err(e) => handler_error_response(e)
+    }
+}
+
+pub async fn get_checkout_session(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Path(session_id): Path<String>) -> (StatusCode, Json<Value>) {
+    let get_checkout_session_query = GetCheckoutSessionQuery {
+        session_id,
+        is_admin: has_scope(&claims, &state.auth0_admin_scope),
+        customer: CustomerIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            name: claims.name,
+        },
+    };
+
+    match state.get_checkout_session_query_handler.handle(Some(get_checkout_session_query)).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => handler_error_response(e)
+    }
+}
+
+// The Idempotency-Key header is required and identifies the refund, so a retried request never refunds twice
+pub async fn refund_payment(State(state): State<Arc<AppState>>, Path(id): Path<String>, headers: HeaderMap, Json(mut refund_payment_command): Json<RefundPaymentCommand>) -> (StatusCode, Json<Value>) {
+    refund_payment_command.payment_id = id;
+    refund_payment_command.request_id = match headers.get(IDEMPOTENCY_KEY_HEADER_NAME).map(|header_value| header_value.to_str()) {
+        Some(Ok(key)) if !key.is_empty() => String::from(key),
+        _ => return (StatusCode::BAD_REQUEST, Json(json!(ApiError{error: format!("{} header is required", IDEMPOTENCY_KEY_HEADER_NAME)})))
+    };
+
+    match state.refund_payment_command_handler.handle(&refund_payment_command).await {
+        Ok(response) => (StatusCode::CREATED, Json(json!(response))),
+        Err(e) => handler_error_response(e)
+    }
+}
+
+pub async fn cancel_payment(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Path(id): Path<String>) -> (StatusCode, Json<Value>) {
+    let cancel_payment_command = CancelPaymentCommand {
+        payment_id: id,
+        is_admin: has_scope(&claims, &state.auth0_admin_scope),
+        customer: CustomerIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            name: claims.name,
+        },
+    };
+
+    match state.cancel_payment_command_handler.handle(&cancel_payment_command).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => handler_error_response(e)
+    }
+}
+
+pub async fn reconcile_payments(State(state): State<Arc<AppState>>, Json(reconcile_payments_command): Json<ReconcilePaymentsCommand>) -> (StatusCode, Json<Value>) {
+    match state.reconcile_payments_command_handler.handle(&reconcile_payments_command).await {
         Ok(response) => (StatusCode::CREATED, Json(json!(response))),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
     }
-}
\ No newline at end of file
+}
+
+pub async fn create_promotion(State(state): State<Arc<AppState>>, Json(create_promotion_command): Json<CreatePromotionCommand>) -> (StatusCode, Json<Value>) {
+    match state.create_promotion_command_handler.handle(&create_promotion_command).await {
+        Ok(response) => (StatusCode::CREATED, Json(json!(response))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    }
+}
+
+// Authenticated by the payment processor's signature rather than a JWT, the raw body is required to verify it
+pub async fn handle_payment_processor_webhook(State(state): State<Arc<AppState>>, Path(payment_processor): Path<String>, headers: HeaderMap, payload: String) -> (StatusCode, Json<Value>) {
+    // Header names are already lowercase, each processor picks out the ones carrying its signature
+    let headers: HashMap<String, String> = headers.iter()
+        .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.to_string(), String::from(value))))
+        .collect();
+
+    match state.handle_payment_processor_webhook_command_handler.handle(&HandlePaymentProcessorWebhookCommand { payment_processor, payload, headers }).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!(ApiError{error: e})))
+    }
+}
+
+pub async fn get_order_payment(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Path(order_id): Path<String>) -> (StatusCode, Json<Value>) {
+    let get_order_payment_query = GetOrderPaymentQuery {
+        order_id,
+        is_admin: has_scope(&claims, &state.auth0_admin_scope),
+        customer: CustomerIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            name: claims.name,
+        },
+    };
+
+    match state.get_order_payment_query_handler.handle(Some(get_order_payment_query)).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => handler_error_response(e)
+    }
+}
+
+pub async fn submit_dispute_evidence(State(state): State<Arc<AppState>>, Path(dispute_id): Path<String>, Json(mut submit_dispute_evidence_command): Json<SubmitDisputeEvidenceCommand>) -> (StatusCode, Json<Value>) {
+    submit_dispute_evidence_command.dispute_id = dispute_id;
+
+    match state.submit_dispute_evidence_command_handler.handle(&submit_dispute_evidence_command).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    }
+}
+
+pub async fn get_payment_saga(State(state): State<Arc<AppState>>, Path(order_id): Path<String>) -> (StatusCode, Json<Value>) {
+    match state.get_payment_saga_query_handler.handle(Some(GetPaymentSagaQuery { order_id })).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    }
+}
+
+pub async fn create_subscription(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Json(mut create_subscription_command): Json<CreateSubscriptionCommand>) -> (StatusCode, Json<Value>) {
+    create_subscription_command.customer = Some(CustomerIdentity {
+        subject: claims.sub,
+        email: claims.email,
+        email_verified: claims.email_verified,
+        name: claims.name,
+    });
+
+    match state.create_subscription_command_handler.handle(&create_subscription_command).await {
+        Ok(response) => (StatusCode::CREATED, Json(json!(response))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    }
+}
+
+pub async fn cancel_subscription(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Path(id): Path<String>, Json(mut cancel_subscription_command): Json<CancelSubscriptionCommand>) -> (StatusCode, Json<Value>) {
+    cancel_subscription_command.subscription_id = id;
+    cancel_subscription_command.is_admin = has_scope(&claims, &state.auth0_admin_scope);
+    cancel_subscription_command.customer = CustomerIdentity {
+        subject: claims.sub,
+        email: claims.email,
+        email_verified: claims.email_verified,
+        name: claims.name,
+    };
+
+    match state.cancel_subscription_command_handler.handle(&cancel_subscription_command).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => handler_error_response(e)
+    }
+}
+
+pub async fn pause_subscription(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Path(id): Path<String>) -> (StatusCode, Json<Value>) {
+    let pause_subscription_command = PauseSubscriptionCommand {
+        subscription_id: id,
+        is_admin: has_scope(&claims, &state.auth0_admin_scope),
+        customer: CustomerIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            name: claims.name,
+        },
+    };
+
+    match state.pause_subscription_command_handler.handle(&pause_subscription_command).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => handler_error_response(e)
+    }
+}
+
+pub async fn resume_subscription(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Path(id): Path<String>) -> (StatusCode, Json<Value>) {
+    let resume_subscription_command = ResumeSubscriptionCommand {
+        subscription_id: id,
+        is_admin: has_scope(&claims, &state.auth0_admin_scope),
+        customer: CustomerIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            name: claims.name,
+        },
+    };
+
+    match state.resume_subscription_command_handler.handle(&resume_subscription_command).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => handler_error_response(e)
+    }
+}
+
+pub async fn list_payment_methods(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>) -> (StatusCode, Json<Value>) {
+    match state.list_payment_methods_query_handler.handle(Some(ListPaymentMethodsQuery { subject: claims.sub })).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    }
+}
+
+pub async fn detach_payment_method(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Path(id): Path<String>) -> (StatusCode, Json<Value>) {
+    match state.detach_payment_method_command_handler.handle(&DetachPaymentMethodCommand { subject: claims.sub, payment_method_id: id }).await {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    }
+}
+
+pub async fn create_setup_checkout_session(State(state): State<Arc<AppState>>, Extension(claims): Extension<Claims>, Json(mut create_setup_checkout_session_command): Json<CreateSetupCheckoutSessionCommand>) -> (StatusCode, Json<Value>) {
+    create_setup_checkout_session_command.customer = Some(CustomerIdentity {
+        subject: claims.sub,
+        email: claims.email,
+        email_verified: claims.email_verified,
+        name: claims.name,
+    });
+
+    match state.create_setup_checkout_session_command_handler.handle(&create_setup_checkout_session_command).await {
+        Ok(response) => (StatusCode::CREATED, Json(json!(response))),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    }
+}
@@ -1,11 +1,32 @@
 use std::sync::Arc;
 
-use crate::cqrs::{CreateCheckoutSessionCommandHandler, CreateProductPricingCommandHandler};
+use crate::{cqrs::{AdvancePaymentSagaCommandHandler, CancelPaymentCommandHandler, CancelSubscriptionCommandHandler, CreateCheckoutSessionCommandHandler, CreateProductPricingCommandHandler, CreatePromotionCommandHandler, CreateSetupCheckoutSessionCommandHandler, CreateSubscriptionCommandHandler, DetachPaymentMethodCommandHandler, GetCheckoutSessionQueryHandler, GetOrderPaymentQueryHandler, GetPaymentSagaQueryHandler, HandlePaymentProcessorWebhookCommandHandler, ListPaymentMethodsQueryHandler, PauseSubscriptionCommandHandler, ReconcilePaymentsCommandHandler, RefundPaymentCommandHandler, ResumeSubscriptionCommandHandler, SubmitDisputeEvidenceCommandHandler}, events::MessageBroker, repositories::IdempotencyKeyRepository};
 
 #[derive(Clone)]
 pub struct AppState {
     pub create_checkout_session_command_handler: Arc<CreateCheckoutSessionCommandHandler>,
     pub create_product_pricing_command_handler: Arc<CreateProductPricingCommandHandler>,
+    pub get_checkout_session_query_handler: Arc<GetCheckoutSessionQueryHandler>,
+    pub get_order_payment_query_handler: Arc<GetOrderPaymentQueryHandler>,
+    pub refund_payment_command_handler: Arc<RefundPaymentCommandHandler>,
+    pub cancel_payment_command_handler: Arc<CancelPaymentCommandHandler>,
+    pub reconcile_payments_command_handler: Arc<ReconcilePaymentsCommandHandler>,
+    pub handle_payment_processor_webhook_command_handler: Arc<HandlePaymentProcessorWebhookCommandHandler>,
+    pub create_subscription_command_handler: Arc<CreateSubscriptionCommandHandler>,
+    pub cancel_subscription_command_handler: Arc<CancelSubscriptionCommandHandler>,
+    pub pause_subscription_command_handler: Arc<PauseSubscriptionCommandHandler>,
+    pub resume_subscription_command_handler: Arc<ResumeSubscriptionCommandHandler>,
+    pub list_payment_methods_query_handler: Arc<ListPaymentMethodsQueryHandler>,
+    pub detach_payment_method_command_handler: Arc<DetachPaymentMethodCommandHandler>,
+    pub create_setup_checkout_session_command_handler: Arc<CreateSetupCheckoutSessionCommandHandler>,
+    pub create_promotion_command_handler: Arc<CreatePromotionCommandHandler>,
+    pub advance_payment_saga_command_handler: Arc<AdvancePaymentSagaCommandHandler>,
+    pub get_payment_saga_query_handler: Arc<GetPaymentSagaQueryHandler>,
+    pub submit_dispute_evidence_command_handler: Arc<SubmitDisputeEvidenceCommandHandler>,
+    pub message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    pub idempotency_key_repository: Arc<dyn IdempotencyKeyRepository + Send + Sync>,
+    pub idempotency_key_ttl_seconds: u64,
     pub auth0_domain: String,
     pub auth0_audience: String,
+    pub auth0_admin_scope: String,
 }
\ No newline at end of file
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use tracing::{event, Level};
+
+use crate::{domain::Payment, paymentprocessors::STRIPE_PAYMENT_PROCESSOR_NAME};
+
+pub static NO_TAX_CALCULATOR_NAME: &str = "none";
+pub static STRIPE_TAX_CALCULATOR_NAME: &str = "stripe";
+pub static LOCAL_TAX_CALCULATOR_NAME: &str = "local";
+
+#[async_trait]
+pub trait TaxCalculator {
+    // Called before the checkout session is created, either sets each line item's tax or marks the payment for the payment
+    // processor to work the tax out during checkout
+    async fn calculate_tax(&self, payment: &mut Payment) -> Result<(), String>;
+}
+
+// Line items are charged tax-free
+pub struct NoTaxCalculator {}
+
+impl NoTaxCalculator {
+    pub fn new() -> Self {
+        NoTaxCalculator {}
+    }
+}
+
+#[async_trait]
+impl TaxCalculator for NoTaxCalculator {
+    async fn calculate_tax(&self, _payment: &mut Payment) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Stripe Tax works the tax out from the customer's address and the products' tax codes once it is collected at checkout,
+// so it can only be used for payments going through Stripe
+pub struct StripeTaxCalculator {}
+
+impl StripeTaxCalculator {
+    pub fn new() -> Self {
+        StripeTaxCalculator {}
+    }
+}
+
+#[async_trait]
+impl TaxCalculator for StripeTaxCalculator {
+    async fn calculate_tax(&self, payment: &mut Payment) -> Result<(), String> {
+        if payment.payment_processor != STRIPE_PAYMENT_PROCESSOR_NAME {
+            event!(Level::WARN, "Stripe Tax cannot calculate tax for Payment {} with payment processor {}", payment.id, payment.payment_processor);
+            return Err(format!("Stripe Tax cannot calculate tax for Payment {} with payment processor {}", payment.id, payment.payment_processor));
+        }
+
+        payment.automatic_tax = true;
+        for line_item in payment.line_items.iter_mut() {
+            line_item.tax_amount = 0;
+        }
+
+        Ok(())
+    }
+}
+
+// Applies a fixed rate per tax code, with a default rate for products without a code or with one not in the table
+pub struct LocalTaxCalculator {
+    rates_by_tax_code: HashMap<String, f32>,
+    default_rate: f32,
+}
+
+impl LocalTaxCalculator {
+    pub fn new(rates_by_tax_code: HashMap<String, f32>, default_rate: f32) -> Self {
+        LocalTaxCalculator {
+            rates_by_tax_code,
+            default_rate,
+        }
+    }
+}
+
+#[async_trait]
+impl TaxCalculator for LocalTaxCalculator {
+    async fn calculate_tax(&self, payment: &mut Payment) -> Result<(), String> {
+        // Tax is due on what is actually paid, so a discount lowers each line's taxable amount by its share
+        let subtotal = payment.subtotal();
+        let taxable_share = if subtotal > 0 { (subtotal - payment.discount_total()).max(0) as f64 / subtotal as f64 } else { 0.0 };
+
+        payment.automatic_tax = false;
+        for line_item in payment.line_items.iter_mut() {
+            let rate = self.rates_by_tax_code.get(&line_item.tax_code).copied().unwrap_or(self.default_rate);
+            let taxable_amount = (line_item.price * line_item.quantity as i64) as f64 * taxable_share;
+            line_item.tax_amount = (taxable_amount * rate as f64).round() as i64;
+        }
+
+        Ok(())
+    }
+}